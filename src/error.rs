@@ -0,0 +1,119 @@
+use std::{fmt::Display, time::Duration};
+
+use crate::ops::Span;
+
+/// The kinds of error MathJIT reports, so library users can tell them apart, such as a typing mistake from a
+/// failed evaluation. Functions still return `anyhow::Error`, from which these are recovered with
+/// `downcast_ref::<MathJitError>()`, even through added context. `Display` gives the message followed by the
+/// input with the offending part underlined, when there is a span.
+#[derive(Debug, Clone)]
+pub enum MathJitError {
+    /// Input which can't be split into tokens, such as `2 $ 3` or `1..2`.
+    Tokenize { message: String, span: Span },
+    /// Tokens which don't form an expression, such as `2 +` or an unclosed bracket.
+    Parse { message: String, span: Option<Span> },
+    /// Input nested deeper than the parser allows, see `Parser::set_max_depth`.
+    TooDeeplyNested { span: Option<Span> },
+    /// A call to a function which isn't defined.
+    UnknownFunction {
+        name: String,
+        message: String,
+        span: Span,
+    },
+    /// A call to a built-in function turned off with `EvalOptions::disabled`.
+    Disabled { name: String, span: Span },
+    /// A call with the wrong number of arguments.
+    Arity {
+        name: String,
+        provided: usize,
+        message: String,
+        span: Span,
+    },
+    /// A variable which isn't a parameter of the function using it.
+    UnknownVariable {
+        name: char,
+        message: String,
+        span: Span,
+    },
+    /// Evaluation failed, such as the square root of a negative number with `--strict`, or ran into a limit
+    /// such as `--max-iterations`.
+    Runtime { message: String, span: Option<Span> },
+    /// Something the back-end can't evaluate, such as a list in the JIT.
+    Unsupported { message: String, span: Option<Span> },
+    /// Evaluation took longer than `EvalOptions::timeout`.
+    TimedOut { timeout: Duration },
+    /// Evaluation was cancelled through `EvalOptions::interrupt`.
+    Interrupted,
+    /// LLVM failed to build, link or run compiled code.
+    Backend { message: String },
+}
+
+impl MathJitError {
+    pub(crate) fn runtime(message: impl Into<String>) -> anyhow::Error {
+        MathJitError::Runtime {
+            message: message.into(),
+            span: None,
+        }
+        .into()
+    }
+
+    pub(crate) fn unsupported(message: impl Into<String>) -> anyhow::Error {
+        MathJitError::Unsupported {
+            message: message.into(),
+            span: None,
+        }
+        .into()
+    }
+
+    pub(crate) fn backend(message: impl Into<String>) -> anyhow::Error {
+        MathJitError::Backend {
+            message: message.into(),
+        }
+        .into()
+    }
+
+    /// The part of the input the error is about, if it is about a particular part.
+    pub fn span(&self) -> Option<&Span> {
+        match self {
+            MathJitError::Tokenize { span, .. }
+            | MathJitError::UnknownFunction { span, .. }
+            | MathJitError::Disabled { span, .. }
+            | MathJitError::Arity { span, .. }
+            | MathJitError::UnknownVariable { span, .. } => Some(span),
+            MathJitError::Parse { span, .. }
+            | MathJitError::TooDeeplyNested { span }
+            | MathJitError::Runtime { span, .. }
+            | MathJitError::Unsupported { span, .. } => span.as_ref(),
+            MathJitError::TimedOut { .. }
+            | MathJitError::Interrupted
+            | MathJitError::Backend { .. } => None,
+        }
+    }
+}
+
+impl Display for MathJitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MathJitError::Tokenize { message, .. }
+            | MathJitError::Parse { message, .. }
+            | MathJitError::UnknownFunction { message, .. }
+            | MathJitError::Arity { message, .. }
+            | MathJitError::UnknownVariable { message, .. }
+            | MathJitError::Runtime { message, .. }
+            | MathJitError::Unsupported { message, .. }
+            | MathJitError::Backend { message } => write!(f, "{message}")?,
+            MathJitError::TooDeeplyNested { .. } => write!(f, "expression too deeply nested")?,
+            MathJitError::Disabled { name, .. } => write!(f, "function '{name}' is disabled")?,
+            MathJitError::TimedOut { timeout } => {
+                write!(f, "evaluation timed out after {} ms", timeout.as_millis())?;
+            }
+            MathJitError::Interrupted => write!(f, "evaluation interrupted")?,
+        }
+        match self.span() {
+            Some(span) => write!(f, "{}", span.error_message()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for MathJitError {}
@@ -1,109 +1,455 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
 use crate::{
-    ops::MathOp,
+    error::MathJitError,
+    ops::{MathOp, Span},
     parser::{Function, ParseOutput},
     timings::Timings,
 };
 
 use super::{
-    intrinsic::{self},
-    Eval, Response,
+    interrupted,
+    intrinsic::{self, BuiltinFunction},
+    missing_function, results_differ, timed_out, unbound_variable, wrong_arg_count, Eval,
+    EvalOptions, Response, Value,
 };
 
+// Limits how deeply evaluation may recurse, which in practice is only reached by recursive functions
+const MAX_EVAL_DEPTH: usize = 2000;
+
+// Reading the clock for every operation would be slow, so the timeout and interrupt are only checked this often
+const TIMEOUT_CHECK_INTERVAL: u32 = 1024;
+
 pub struct AstInterpreter {
     pub functions: Vec<Function>,
+    // Values of constants, which were evaluated when they were defined
+    pub constants: HashMap<char, f64>,
+    pub options: EvalOptions,
+    intrinsics: HashMap<&'static str, Box<dyn BuiltinFunction>>,
+    depth: Cell<usize>,
+    // Number of user function calls currently being evaluated, used to indent the verbose trace
+    call_depth: Cell<usize>,
+    // When the expression being evaluated runs out of time, and how many operations until that is checked
+    deadline: Cell<Option<(Instant, Duration)>>,
+    steps: Cell<u32>,
+}
+
+fn is_zero(ops: &MathOp) -> bool {
+    matches!(ops, MathOp::Num(x) if *x == 0.0)
+}
+
+// What an expression is evaluated in: the function whose body it is, that function's arguments, and whether
+// it's a lambda. Lambdas only see their own argument, so constants are hidden from them
+#[derive(Clone, Copy)]
+struct Frame<'a> {
+    func: &'a Function,
+    args: &'a [f64],
+    lambda: bool,
+}
+
+impl<'a> Frame<'a> {
+    fn call(func: &'a Function, args: &'a [f64]) -> Self {
+        Self {
+            func,
+            args,
+            lambda: false,
+        }
+    }
+}
+
+struct DepthGuard<'a>(&'a Cell<usize>);
+
+impl Drop for DepthGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
 }
 
 impl AstInterpreter {
-    pub fn eval_func(&self, ops: &MathOp, func: &Function, current_args: &[f64]) -> Option<f64> {
-        Some(match ops {
-            MathOp::Add { lhs, rhs } => {
-                self.eval_func(lhs, func, current_args)?
-                    + self.eval_func(rhs, func, current_args)?
-            }
-            MathOp::Sub { lhs, rhs } => {
-                self.eval_func(lhs, func, current_args)?
-                    - self.eval_func(rhs, func, current_args)?
-            }
-            MathOp::Mul { lhs, rhs } => {
-                self.eval_func(lhs, func, current_args)?
-                    * self.eval_func(rhs, func, current_args)?
-            }
-            MathOp::Div { lhs, rhs } => {
-                self.eval_func(lhs, func, current_args)?
-                    / self.eval_func(rhs, func, current_args)?
-            }
-            MathOp::Exp { lhs, rhs } => self
-                .eval_func(lhs, func, current_args)?
-                .powf(self.eval_func(rhs, func, current_args)?),
+    /// Makes `func` callable as `name`, alongside (or in place of) the standard intrinsics.
+    #[must_use]
+    pub fn with_intrinsic(mut self, name: &'static str, func: Box<dyn BuiltinFunction>) -> Self {
+        self.intrinsics.insert(name, func);
+        self
+    }
+
+    /// Defines `functions` without parsing them, replacing any existing definitions of the same name.
+    #[must_use]
+    pub fn with_functions(mut self, functions: Vec<Function>) -> Self {
+        self.functions
+            .retain(|x| !functions.iter().any(|func| func.name == x.name));
+        self.functions.extend(functions);
+        self
+    }
+
+    /// Gives each variable its value, as if it had been defined as a constant, such as `c = 3`.
+    #[must_use]
+    pub fn with_constants(mut self, constants: impl IntoIterator<Item = (char, f64)>) -> Self {
+        self.constants.extend(constants);
+        self
+    }
+
+    fn enter(&self, func: &Function) -> Result<DepthGuard<'_>> {
+        if self.depth.get() >= MAX_EVAL_DEPTH {
+            return Err(MathJitError::runtime(format!(
+                "maximum recursion depth exceeded while evaluating {}",
+                if func.name.is_empty() {
+                    "expression"
+                } else {
+                    &func.name
+                }
+            )));
+        }
+        if self.steps.get() > 0 {
+            self.steps.set(self.steps.get() - 1);
+        } else {
+            self.steps.set(TIMEOUT_CHECK_INTERVAL);
+            if self.options.is_interrupted() {
+                return Err(interrupted());
+            }
+            if let Some((deadline, timeout)) = self.deadline.get() {
+                if Instant::now() > deadline {
+                    return Err(timed_out(timeout));
+                }
+            }
+        }
+        self.depth.set(self.depth.get() + 1);
+        Ok(DepthGuard(&self.depth))
+    }
+
+    pub fn eval_func(&self, ops: &MathOp, func: &Function, current_args: &[f64]) -> Result<f64> {
+        self.eval_in(ops, Frame::call(func, current_args))
+    }
+
+    /// Evaluates the body of `lambda`, which unlike a function can't use constants.
+    pub fn eval_lambda(&self, lambda: &Function, args: &[f64]) -> Result<f64> {
+        self.eval_in(
+            &lambda.body,
+            Frame {
+                func: lambda,
+                args,
+                lambda: true,
+            },
+        )
+    }
+
+    fn eval_in(&self, ops: &MathOp, frame: Frame) -> Result<f64> {
+        let value = self.eval_op(ops, frame)?;
+        if self.options.decimals_only {
+            self.check_whole(ops, value)?;
+        }
+        Ok(value)
+    }
+
+    fn check_whole(&self, ops: &MathOp, value: f64) -> Result<()> {
+        if !results_differ(value, value.round(), self.options.epsilon) {
+            return Ok(());
+        }
+        Err(match ops {
+            MathOp::Num(_) => {
+                MathJitError::runtime(format!("{value} isn't a whole number (--decimals-only)"))
+            }
+            _ => MathJitError::runtime(format!(
+                "{ops} is {value}, which isn't a whole number (--decimals-only)"
+            )),
+        })
+    }
+
+    fn eval_op(&self, ops: &MathOp, frame: Frame) -> Result<f64> {
+        let Frame {
+            func,
+            args: current_args,
+            ..
+        } = frame;
+        let _depth = self.enter(func)?;
+        Ok(match ops {
+            MathOp::Add { lhs, rhs } => self.eval_in(lhs, frame)? + self.eval_in(rhs, frame)?,
+            MathOp::Sub { lhs, rhs } => self.eval_in(lhs, frame)? - self.eval_in(rhs, frame)?,
+            MathOp::Mul { lhs, rhs } if self.options.fast => {
+                if is_zero(rhs) {
+                    return Ok(0.0);
+                }
+                let lhs = self.eval_in(lhs, frame)?;
+                if lhs == 0.0 {
+                    return Ok(0.0);
+                }
+                lhs * self.eval_in(rhs, frame)?
+            }
+            MathOp::Mul { lhs, rhs } => self.eval_in(lhs, frame)? * self.eval_in(rhs, frame)?,
+            MathOp::Div { lhs, rhs } => self.eval_in(lhs, frame)? / self.eval_in(rhs, frame)?,
+            MathOp::Exp { lhs, rhs } => self.eval_in(lhs, frame)?.powf(self.eval_in(rhs, frame)?),
+            MathOp::Cmp { op, lhs, rhs } => {
+                if op.apply(self.eval_in(lhs, frame)?, self.eval_in(rhs, frame)?) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            MathOp::Piecewise(cases) => {
+                for (cond, value) in cases {
+                    let matched = match cond {
+                        Some(cond) => self.eval_in(cond, frame)? != 0.0,
+                        None => true,
+                    };
+                    if matched {
+                        return self.eval_in(value, frame);
+                    }
+                }
+                f64::NAN
+            }
             MathOp::Num(x) => *x,
-            MathOp::Neg(x) => -self.eval_func(x, func, current_args)?,
-            MathOp::Call { name, args } => {
-                let Some(func) = self.functions.iter().find(|x| x.name == *name) else {
-                    if let Some(ifunc) = intrinsic::standard_intrinsics().get(&name[..]) {
-                        return Some(
-                            ifunc.eval_interpreter(
-                                self,
-                                args.iter()
-                                    .map(|x| self.eval_func(x, func, current_args))
-                                    .collect::<Option<Vec<_>>>()?,
+            MathOp::Neg(x) => -self.eval_in(x, frame)?,
+            MathOp::Call { name, args, span } => {
+                let Some(callee) = self.functions.iter().find(|x| x.name == *name) else {
+                    if let Some(ifunc) = self.intrinsics.get(&name[..]) {
+                        let (lambda, args) = match args.split_first() {
+                            Some((MathOp::Lambda { arg, body }, rest)) => (
+                                Some(Function {
+                                    name: "lambda".to_string(),
+                                    args: vec![*arg],
+                                    body: *body.clone(),
+                                }),
+                                rest,
                             ),
-                        );
+                            _ => (None, &args[..]),
+                        };
+                        let args = args
+                            .iter()
+                            .map(|x| self.eval_in(x, frame))
+                            .collect::<Result<Vec<_>>>()?;
+                        ifunc.check_args(&self.options, &args)?;
+                        let value = match &lambda {
+                            Some(lambda) => {
+                                ifunc.eval_interpreter_lambda(self, lambda, args.clone())?
+                            }
+                            None => ifunc.eval_interpreter(self, args.clone())?,
+                        };
+                        self.trace_call(name, &args, &value);
+                        return Ok(value);
                     }
-                    panic!("Could not find function")
+                    return Err(missing_function(&self.options, name, &func.name, span));
                 };
 
-                self.eval_func(
-                    &func.body,
-                    func,
-                    &args
-                        .iter()
-                        .map(|x| self.eval_func(x, func, current_args))
-                        .collect::<Option<Vec<_>>>()?,
-                )?
+                let args = self.call_args(callee, args, span, frame)?;
+                let value = self.enter_call(|| self.eval_func(&callee.body, callee, &args))?;
+                self.trace_call(name, &args, &value);
+                value
+            }
+            MathOp::List(_) => {
+                return Err(MathJitError::runtime(
+                    "list used where a single number was expected",
+                ))
             }
-            MathOp::Arg(n) => {
+            MathOp::Tuple(_) => {
+                return Err(MathJitError::runtime(
+                    "tuple used where a single number was expected",
+                ))
+            }
+            MathOp::Lambda { .. } => {
+                return Err(MathJitError::runtime(
+                    "lambda used where a single number was expected",
+                ))
+            }
+            MathOp::Arg(n, span) => {
                 if let Some((index, _)) = func.args.iter().enumerate().find(|x| x.1 == n) {
                     *current_args.get(index).expect("Could not find argument")
+                } else if let Some(value) = self.constants.get(n).filter(|_| !frame.lambda) {
+                    *value
                 } else {
-                    panic!("Argument specified in function body was not passed in function call")
+                    return Err(unbound_variable(*n, &func.name, span));
                 }
             }
         })
     }
+
+    fn enter_call<T>(&self, call: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.call_depth.set(self.call_depth.get() + 1);
+        let result = call();
+        self.call_depth.set(self.call_depth.get() - 1);
+        result
+    }
+
+    // With verbose output, each call is printed once it returns, indented by how deeply it is nested
+    fn trace_call(&self, name: &str, args: &[f64], value: &dyn std::fmt::Display) {
+        if !self.options.verbose {
+            return;
+        }
+        let args = args.iter().map(ToString::to_string).collect::<Vec<_>>();
+        println!(
+            "{}{name}({}) = {value}",
+            "  ".repeat(self.call_depth.get()),
+            args.join(", ")
+        );
+    }
+
+    fn call_args(
+        &self,
+        callee: &Function,
+        args: &[MathOp],
+        span: &Span,
+        frame: Frame,
+    ) -> Result<Vec<f64>> {
+        if callee.args.len() != args.len() {
+            return Err(wrong_arg_count(
+                &callee.name,
+                "",
+                args.len(),
+                callee.args.len(),
+                span,
+            ));
+        }
+        args.iter().map(|x| self.eval_in(x, frame)).collect()
+    }
+
+    // Evaluates an expression which may produce a list, element-wise operations broadcast scalars
+    pub fn eval_value(&self, ops: &MathOp, func: &Function, current_args: &[f64]) -> Result<Value> {
+        let value = self.eval_value_op(ops, func, current_args)?;
+        if self.options.decimals_only {
+            match &value {
+                Value::Scalar(x) => self.check_whole(ops, *x)?,
+                Value::List(xs) | Value::Tuple(xs) => {
+                    for x in xs {
+                        self.check_whole(ops, *x)?;
+                    }
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    fn eval_value_op(&self, ops: &MathOp, func: &Function, current_args: &[f64]) -> Result<Value> {
+        let _depth = self.enter(func)?;
+        let binary = |lhs: &MathOp, rhs: &MathOp, op: fn(f64, f64) -> f64| {
+            self.eval_value(lhs, func, current_args)?
+                .zip_with(self.eval_value(rhs, func, current_args)?, op)
+        };
+
+        match ops {
+            MathOp::Add { lhs, rhs } => binary(lhs, rhs, |x, y| x + y),
+            MathOp::Sub { lhs, rhs } => binary(lhs, rhs, |x, y| x - y),
+            // Without lists, `eval_func` can skip the other operand. With them, `0 * [1, 2]` still has to be a list
+            MathOp::Mul { .. }
+                if self.options.fast && !ops.contains_list() && !ops.contains_tuple() =>
+            {
+                Ok(Value::Scalar(self.eval_func(ops, func, current_args)?))
+            }
+            MathOp::Mul { lhs, rhs } => binary(lhs, rhs, |x, y| x * y),
+            MathOp::Div { lhs, rhs } => binary(lhs, rhs, |x, y| x / y),
+            MathOp::Exp { lhs, rhs } => binary(lhs, rhs, f64::powf),
+            MathOp::Neg(x) => Ok(self.eval_value(x, func, current_args)?.map(|x| -x)),
+            MathOp::List(items) => Ok(Value::List(
+                items
+                    .iter()
+                    .map(|x| self.eval_func(x, func, current_args))
+                    .collect::<Result<Vec<_>>>()?,
+            )),
+            MathOp::Tuple(items) => Ok(Value::Tuple(
+                items
+                    .iter()
+                    .map(|x| self.eval_func(x, func, current_args))
+                    .collect::<Result<Vec<_>>>()?,
+            )),
+            MathOp::Call { name, args, .. }
+                if !self.functions.iter().any(|x| x.name == *name)
+                    && self
+                        .intrinsics
+                        .get(&name[..])
+                        .is_some_and(|x| x.returns_tuple()) =>
+            {
+                let ifunc = &self.intrinsics[&name[..]];
+                let args = args
+                    .iter()
+                    .map(|x| self.eval_func(x, func, current_args))
+                    .collect::<Result<Vec<_>>>()?;
+                ifunc.check_args(&self.options, &args)?;
+                let value = Value::Tuple(ifunc.eval_interpreter_tuple(self, args.clone())?);
+                self.trace_call(name, &args, &value);
+                Ok(value)
+            }
+            MathOp::Call { name, args, span } if self.functions.iter().any(|x| x.name == *name) => {
+                let callee = self.functions.iter().find(|x| x.name == *name).unwrap();
+                let args = self.call_args(callee, args, span, Frame::call(func, current_args))?;
+                let value = self.enter_call(|| self.eval_value(&callee.body, callee, &args))?;
+                self.trace_call(name, &args, &value);
+                Ok(value)
+            }
+            _ => Ok(Value::Scalar(self.eval_func(ops, func, current_args)?)),
+        }
+    }
 }
 
 impl Eval for AstInterpreter {
-    fn new(verbose: bool) -> Self {
-        let _ = verbose;
-
-        Self { functions: vec![] }
+    fn new(options: EvalOptions) -> Self {
+        Self {
+            functions: vec![],
+            constants: HashMap::new(),
+            intrinsics: intrinsic::enabled_intrinsics(&options),
+            options,
+            depth: Cell::new(0),
+            call_depth: Cell::new(0),
+            deadline: Cell::new(None),
+            steps: Cell::new(0),
+        }
     }
 
-    fn eval(&mut self, ops: ParseOutput) -> Option<(super::Response, Timings)> {
+    fn eval(&mut self, ops: ParseOutput) -> Result<(super::Response, Timings)> {
         let timings = Timings::start();
+        self.deadline
+            .set(self.options.timeout.map(|x| (Instant::now() + x, x)));
+        let top_level = |ops: &MathOp| Function {
+            name: String::new(),
+            args: vec![],
+            body: ops.clone(),
+        };
         match ops {
-            ParseOutput::Body(ops) => Some((
-                Response::Value(self.eval_func(
-                    &ops,
-                    &Function {
-                        name: String::new(),
-                        args: vec![],
-                        body: ops.clone(),
-                    },
-                    &[],
-                )?),
+            ParseOutput::Body(ops) => Ok((
+                Response::Value(self.eval_value(&ops, &top_level(&ops), &[])?),
                 timings,
             )),
+            // The value may refer to the constant's previous value, such as `c = c + 1`
+            ParseOutput::Constant { name, value } => {
+                let value = self.eval_func(&value, &top_level(&value), &[])?;
+                self.constants.insert(name, value);
+                Ok((Response::Value(Value::Scalar(value)), timings))
+            }
             ParseOutput::Functions(funcs) => {
+                let mut redefined = vec![];
                 for func in funcs {
                     if let Some(item) = self.functions.iter_mut().find(|x| x.name == func.name) {
+                        redefined.push(func.signature());
                         *item = func;
                     } else {
                         self.functions.push(func);
                     }
                 }
-                Some((Response::Ok, timings))
+                Ok((Response::Ok { redefined }, timings))
             }
         }
     }
+
+    fn stats(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("Functions defined", self.functions.len().to_string()),
+            ("Constants defined", self.constants.len().to_string()),
+        ]
+    }
+
+    fn functions(&self) -> Vec<&Function> {
+        self.functions.iter().collect()
+    }
+
+    fn reset(&mut self) {
+        self.functions.clear();
+        self.constants.clear();
+    }
+
+    fn produced_by(&self) -> Vec<(&'static str, Option<Value>)> {
+        vec![("Interpreter", None)]
+    }
 }
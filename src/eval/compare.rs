@@ -0,0 +1,96 @@
+use anyhow::Result;
+
+use crate::{
+    parser::{Function, ParseOutput},
+    timings::Timings,
+};
+
+use super::{
+    ast_interpret::AstInterpreter, llvm::Jit, results_differ, Eval, EvalOptions, Response, Value,
+};
+
+/// Evaluates everything with both the interpreter and the JIT, warning when their results differ by
+/// more than `EvalOptions::epsilon`. The interpreter's result is the one returned.
+pub struct Compare {
+    interpreter: AstInterpreter,
+    jit: Jit,
+    // The back-ends which produced the last value, with the JIT's result when it differs
+    produced_by: Vec<(&'static str, Option<Value>)>,
+}
+
+impl Compare {
+    /// Gives each variable its value in both back-ends, see `AstInterpreter::with_constants`.
+    #[must_use]
+    pub fn with_constants(self, constants: impl IntoIterator<Item = (char, f64)> + Clone) -> Self {
+        Self {
+            interpreter: self.interpreter.with_constants(constants.clone()),
+            jit: self.jit.with_constants(constants),
+            ..self
+        }
+    }
+
+    fn report(&self, interpreted: &Value, compiled: &Value) {
+        let (Value::Scalar(interpreted), Value::Scalar(compiled)) = (interpreted, compiled) else {
+            return;
+        };
+        if !results_differ(*interpreted, *compiled, self.interpreter.options.epsilon) {
+            return;
+        }
+        let absolute = (interpreted - compiled).abs();
+        let relative = absolute / interpreted.abs().max(compiled.abs());
+        eprintln!(
+            "Warning: the interpreter gave {interpreted} but the JIT gave {compiled}, a difference of {absolute:e} ({relative:e} relative)"
+        );
+    }
+}
+
+impl Eval for Compare {
+    fn new(options: EvalOptions) -> Self {
+        Self {
+            interpreter: AstInterpreter::new(options.clone()),
+            jit: Jit::new(options),
+            produced_by: vec![],
+        }
+    }
+
+    fn eval(&mut self, ops: ParseOutput) -> Result<(Response, Timings)> {
+        let (response, interpreter_timings) = self.interpreter.eval(ops.clone())?;
+        self.produced_by = self.interpreter.produced_by();
+        let mut timings = Timings::start();
+        timings.append(interpreter_timings, "Interpreter");
+        // The JIT lacks some features, such as lists, which shouldn't stop the interpreter's result being shown
+        match self.jit.eval(ops) {
+            Ok((jit_response, jit_timings)) => {
+                timings.append(jit_timings, "JIT");
+                if let (Response::Value(interpreted), Response::Value(compiled)) =
+                    (&response, &jit_response)
+                {
+                    self.report(interpreted, compiled);
+                    let epsilon = self.interpreter.options.epsilon;
+                    let differs = matches!((interpreted, compiled), (Value::Scalar(x), Value::Scalar(y)) if results_differ(*x, *y, epsilon));
+                    self.produced_by
+                        .push(("JIT", differs.then(|| compiled.clone())));
+                }
+            }
+            Err(e) => eprintln!("Warning: the JIT could not evaluate this: {e}"),
+        }
+        Ok((response, timings))
+    }
+
+    fn stats(&self) -> Vec<(&'static str, String)> {
+        self.jit.stats()
+    }
+
+    fn functions(&self) -> Vec<&Function> {
+        self.interpreter.functions()
+    }
+
+    fn reset(&mut self) {
+        self.interpreter.reset();
+        self.jit.reset();
+    }
+
+    fn produced_by(&self) -> Vec<(&'static str, Option<Value>)> {
+        self.produced_by.clone()
+    }
+}
@@ -0,0 +1,71 @@
+use std::f64::consts::PI;
+
+use anyhow::Result;
+use inkwell::values::FloatValue;
+
+use crate::{
+    eval::{ast_interpret::AstInterpreter, llvm::FunctionGen},
+    ops::MathOp,
+};
+
+use super::{BuiltinFunction, BuiltinProto};
+
+// Both back-ends multiply by the same constant, rather than the interpreter using `f64::to_degrees`, so their
+// results agree to the last bit
+const DEGREES_PER_RADIAN: f64 = 180.0 / PI;
+const RADIANS_PER_DEGREE: f64 = PI / 180.0;
+
+fn scale<'b>(fg: &FunctionGen<'b, '_>, args: &[MathOp], factor: f64) -> FloatValue<'b> {
+    let value = fg.cg.build_block(&args[0], fg);
+    let factor = fg.cg.context.f64_type().const_float(factor);
+    fg.cg
+        .builder
+        .build_float_mul(value, factor, "scale")
+        .expect("Failed to multiply")
+}
+
+#[derive(Default)]
+pub(super) struct Degrees;
+impl BuiltinFunction for Degrees {
+    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        Ok(args[0] * DEGREES_PER_RADIAN)
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
+        scale(fg, args, DEGREES_PER_RADIAN)
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["radians"],
+            description: "angle in radians converted to degrees",
+        }
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Radians;
+impl BuiltinFunction for Radians {
+    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        Ok(args[0] * RADIANS_PER_DEGREE)
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
+        scale(fg, args, RADIANS_PER_DEGREE)
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["degrees"],
+            description: "angle in degrees converted to radians",
+        }
+    }
+}
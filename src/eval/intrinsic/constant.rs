@@ -0,0 +1,78 @@
+use anyhow::Result;
+use inkwell::values::FloatValue;
+
+use crate::{
+    eval::{ast_interpret::AstInterpreter, llvm::FunctionGen},
+    ops::MathOp,
+};
+
+use super::{BuiltinFunction, BuiltinProto};
+
+#[derive(Default)]
+pub(super) struct E;
+impl BuiltinFunction for E {
+    fn eval_interpreter(&self, _: &AstInterpreter, _args: Vec<f64>) -> Result<f64> {
+        Ok(std::f64::consts::E)
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, _args: &[MathOp]) -> FloatValue<'b> {
+        fg.cg.context.f64_type().const_float(std::f64::consts::E)
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &[],
+            description: "Euler's number",
+        }
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Inf;
+impl BuiltinFunction for Inf {
+    fn eval_interpreter(&self, _: &AstInterpreter, _args: Vec<f64>) -> Result<f64> {
+        Ok(f64::INFINITY)
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, _args: &[MathOp]) -> FloatValue<'b> {
+        fg.cg.context.f64_type().const_float(f64::INFINITY)
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &[],
+            description: "positive infinity",
+        }
+    }
+}
+
+#[derive(Default)]
+pub(super) struct NaN;
+impl BuiltinFunction for NaN {
+    fn eval_interpreter(&self, _: &AstInterpreter, _args: Vec<f64>) -> Result<f64> {
+        Ok(f64::NAN)
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, _args: &[MathOp]) -> FloatValue<'b> {
+        fg.cg.context.f64_type().const_float(f64::NAN)
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &[],
+            description: "not a number, which compares unequal to everything including itself",
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use anyhow::Result;
+use inkwell::values::FloatValue;
+
+use crate::{
+    eval::{ast_interpret::AstInterpreter, llvm::FunctionGen},
+    ops::MathOp,
+};
+
+use super::{BuiltinFunction, BuiltinProto};
+
+#[derive(Default)]
+pub(super) struct CopySign;
+impl BuiltinFunction for CopySign {
+    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        Ok(args[0].copysign(args[1]))
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
+        fg.cg
+            .call_llvm_intrinsic(fg, "llvm.copysign.f64", &args[..2])
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["magnitude", "sign"],
+            description: "magnitude of the first argument with the sign of the second",
+        }
+    }
+}
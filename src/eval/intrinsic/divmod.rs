@@ -0,0 +1,56 @@
+use anyhow::Result;
+use inkwell::values::FloatValue;
+
+use crate::{
+    error::MathJitError,
+    eval::{ast_interpret::AstInterpreter, llvm::FunctionGen},
+    ops::MathOp,
+};
+
+use super::{BuiltinFunction, BuiltinProto};
+
+// Floored division, as in Python, so the remainder takes the sign of the divisor and `divmod(-7, 3)` is
+// `(-3, 2)` rather than the `(-2, -1)` of truncating division
+#[derive(Default)]
+pub(super) struct DivMod;
+impl BuiltinFunction for DivMod {
+    fn eval_interpreter(&self, _: &AstInterpreter, _args: Vec<f64>) -> Result<f64> {
+        Err(MathJitError::runtime(
+            "divmod gives a tuple, which can't be used where a single number was expected",
+        ))
+    }
+
+    fn gen_jit<'b>(&self, _fg: &FunctionGen<'b, '_>, _args: &[MathOp]) -> FloatValue<'b> {
+        unreachable!("the JIT refuses calls to intrinsics which give a tuple before compiling")
+    }
+
+    fn returns_tuple(&self) -> bool {
+        true
+    }
+
+    fn eval_interpreter_tuple(&self, _: &AstInterpreter, args: Vec<f64>) -> Result<Vec<f64>> {
+        let (a, b) = (args[0], args[1]);
+        let mut remainder = a % b;
+        if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+            remainder += b;
+        }
+        // Dividing by zero gives an infinite quotient and a NaN remainder, like `a / 0`
+        let quotient = if b == 0.0 {
+            (a / b).floor()
+        } else {
+            ((a - remainder) / b).round()
+        };
+        Ok(vec![quotient, remainder])
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["a", "b"],
+            description: "the tuple (floor(a/b), a mod b), where the remainder has the sign of b",
+        }
+    }
+}
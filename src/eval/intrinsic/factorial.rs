@@ -0,0 +1,89 @@
+use anyhow::Result;
+use inkwell::{values::FloatValue, FloatPredicate};
+
+use crate::{
+    error::MathJitError,
+    eval::{ast_interpret::AstInterpreter, llvm::FunctionGen},
+    ops::MathOp,
+};
+
+use super::{BuiltinFunction, BuiltinProto};
+
+// 171! is the first factorial too large for an f64
+const LARGEST_FACTORIAL: u32 = 170;
+
+#[derive(Default)]
+pub(super) struct Factorial;
+impl BuiltinFunction for Factorial {
+    // Only whole numbers have a factorial here, anything else is NaN as in the JIT
+    fn eval_interpreter(&self, ast: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        let n = args[0];
+        if n < 0.0 || n.fract() != 0.0 {
+            return Ok(f64::NAN);
+        }
+        let mut product = 1.0f64;
+        let mut i = 2.0;
+        while i <= n {
+            product *= i;
+            if product.is_infinite() {
+                // Any larger factorial is infinite too, so there's no need to carry on to `n`
+                if ast.options.strict || ast.options.decimals_only {
+                    return Err(MathJitError::runtime(format!(
+                        "factorial of {n} overflows, the largest which can be held is fact({LARGEST_FACTORIAL})"
+                    )));
+                }
+                return Ok(f64::INFINITY);
+            }
+            i += 1.0;
+        }
+        Ok(product)
+    }
+
+    // Compiled code can't report an error, so overflow is infinity. libm's tgamma(n + 1) is n! for whole
+    // numbers, and anything else is made NaN to match the interpreter
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
+        let f64_type = fg.cg.context.f64_type();
+        let tgamma = fg.cg.module.get_function("tgamma").unwrap_or_else(|| {
+            fg.cg
+                .module
+                .add_function("tgamma", f64_type.fn_type(&[f64_type.into()], false), None)
+        });
+        let n = fg.cg.build_block(&args[0], fg);
+        let builder = &fg.cg.builder;
+        let n_plus_one = builder
+            .build_float_add(n, f64_type.const_float(1.0), "fact n+1")
+            .expect("Failed to add floats");
+        let gamma = builder
+            .build_call(tgamma, &[n_plus_one.into()], "tgamma")
+            .expect("Failed to call")
+            .try_as_basic_value()
+            .left()
+            .expect("Could not find left value")
+            .into_float_value();
+        let floor = fg.cg.call_llvm_intrinsic_values("llvm.floor.f64", &[n]);
+        let whole = builder
+            .build_float_compare(FloatPredicate::OEQ, n, floor, "fact whole")
+            .expect("Failed to compare floats");
+        let non_negative = builder
+            .build_float_compare(FloatPredicate::OGE, n, f64_type.const_zero(), "fact sign")
+            .expect("Failed to compare floats");
+        let valid = builder
+            .build_and(whole, non_negative, "fact valid")
+            .expect("Failed to and");
+        builder
+            .build_select(valid, gamma, f64_type.const_float(f64::NAN), "fact")
+            .expect("Failed to select factorial")
+            .into_float_value()
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["n"],
+            description: "n! for a whole number n, infinite past fact(170) unless --strict",
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::Result;
+use inkwell::values::FloatValue;
+
+use crate::{
+    eval::{ast_interpret::AstInterpreter, llvm::FunctionGen},
+    ops::MathOp,
+};
+
+use super::{BuiltinFunction, BuiltinProto};
+
+#[derive(Default)]
+pub(super) struct Hypot;
+impl BuiltinFunction for Hypot {
+    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        Ok(args[0].hypot(args[1]))
+    }
+
+    // LLVM has no hypot intrinsic, so libm's is called, which avoids overflowing on the squares
+    // the same way the interpreter does
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
+        let f64_type = fg.cg.context.f64_type();
+        let hypot = fg.cg.module.get_function("hypot").unwrap_or_else(|| {
+            fg.cg.module.add_function(
+                "hypot",
+                f64_type.fn_type(&[f64_type.into(); 2], false),
+                None,
+            )
+        });
+        let args = args[..2]
+            .iter()
+            .map(|x| fg.cg.build_block(x, fg).into())
+            .collect::<Vec<_>>();
+        fg.cg
+            .builder
+            .build_call(hypot, &args, "hypot")
+            .expect("Failed to call")
+            .try_as_basic_value()
+            .left()
+            .expect("Could not find left value")
+            .into_float_value()
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["x", "y"],
+            description: "length of the hypotenuse, sqrt(x^2 + y^2)",
+        }
+    }
+}
@@ -1,22 +1,112 @@
 use std::collections::HashMap;
 
-use inkwell::values::FloatValue;
+use anyhow::Result;
+use inkwell::values::{FloatValue, FunctionValue};
 
-use crate::ops::MathOp;
+use crate::{error::MathJitError, ops::MathOp, parser::Function};
 
-use super::{ast_interpret::AstInterpreter, llvm::FunctionGen};
+use super::{ast_interpret::AstInterpreter, llvm::FunctionGen, EvalOptions};
 
 pub struct BuiltinProto {
-    pub arg_count: u8,
+    // Names of the arguments, shown in errors and by `--list-intrinsics`
+    pub args: &'static [&'static str],
+    // One line summary, shown by `--list-intrinsics`
+    pub description: &'static str,
+}
+
+impl BuiltinProto {
+    pub fn arg_count(&self) -> usize {
+        self.args.len()
+    }
+
+    /// The argument names in brackets, such as `(start, stop, step)`.
+    pub fn parameters(&self) -> String {
+        format!("({})", self.args.join(", "))
+    }
 }
 
 pub trait BuiltinFunction {
-    fn eval_interpreter(&self, ast: &AstInterpreter, args: Vec<f64>) -> f64;
+    fn eval_interpreter(&self, ast: &AstInterpreter, args: Vec<f64>) -> Result<f64>;
     fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b>;
     fn replicate(&self) -> Box<dyn BuiltinFunction>;
     fn proto(&self) -> BuiltinProto;
+
+    // Called by the interpreter with the evaluated arguments before the intrinsic is run, so
+    // arguments which can't be handled are reported rather than panicking or hanging
+    fn check_args(&self, _options: &EvalOptions, _args: &[f64]) -> Result<()> {
+        Ok(())
+    }
+
+    // Called by the JIT before compiling a call, as compiled code can't report errors. Arguments which can be
+    // worked out ahead of time are given, the others are `None`. A lambda argument is left out
+    fn check_jit_args(&self, _options: &EvalOptions, _args: &[Option<f64>]) -> Result<()> {
+        Ok(())
+    }
+
+    // Whether `check_jit_args` is overridden. Working arguments out ahead of time can be costly, so the JIT only
+    // does it for intrinsics which check them
+    fn has_jit_arg_check(&self) -> bool {
+        false
+    }
+
+    // Whether a lambda may be passed ahead of the usual arguments, such as `sum(x -> x^2, 1, 5)`. After a lambda
+    // the last of the usual arguments may be left out, and the intrinsic picks a default for it
+    fn accepts_lambda(&self) -> bool {
+        false
+    }
+
+    // Whether the result is a tuple rather than a single number, such as `divmod(7, 3)`. Such intrinsics are
+    // evaluated with `eval_interpreter_tuple`, and aren't supported by the JIT
+    fn returns_tuple(&self) -> bool {
+        false
+    }
+
+    fn eval_interpreter_tuple(&self, _ast: &AstInterpreter, _args: Vec<f64>) -> Result<Vec<f64>> {
+        Err(MathJitError::runtime(
+            "this function does not return a tuple",
+        ))
+    }
+
+    fn eval_interpreter_lambda(
+        &self,
+        _ast: &AstInterpreter,
+        _lambda: &Function,
+        _args: Vec<f64>,
+    ) -> Result<f64> {
+        Err(MathJitError::runtime(
+            "this function does not accept a lambda",
+        ))
+    }
+
+    // `lambda` has already been compiled into a function of its own
+    fn gen_jit_lambda<'b>(
+        &self,
+        _fg: &FunctionGen<'b, '_>,
+        _lambda: FunctionValue<'b>,
+        _args: &[MathOp],
+    ) -> FloatValue<'b> {
+        unreachable!("the JIT only passes a lambda to intrinsics which accept one")
+    }
+
+    // The user function a call with `args` runs itself, out of the session's `functions` in the order they were
+    // defined, such as the last one for `sum` without a lambda. The JIT makes sure it is built before the call is
+    // compiled, and reports an error given here instead of compiling the call
+    fn jit_callee<'f>(
+        &self,
+        _functions: &[&'f Function],
+        _args: &[MathOp],
+    ) -> Result<Option<&'f Function>> {
+        Ok(None)
+    }
 }
 
+mod angle;
+mod constant;
+mod copysign;
+mod divmod;
+mod factorial;
+mod hypot;
+mod rounding;
 mod sqrt;
 mod sum;
 mod trig;
@@ -24,9 +114,52 @@ pub fn standard_intrinsics() -> HashMap<&'static str, Box<dyn BuiltinFunction>>
     let mut funcs = HashMap::<&'static str, Box<dyn BuiltinFunction>>::new();
     funcs.insert("sqrt", Box::new(sqrt::Sqrt));
     funcs.insert("pi", Box::new(trig::Pi));
+    funcs.insert("e", Box::new(constant::E));
+    funcs.insert("inf", Box::new(constant::Inf));
+    funcs.insert("nan", Box::new(constant::NaN));
     funcs.insert("sin", Box::new(trig::Sin));
     funcs.insert("cos", Box::new(trig::Cos));
     funcs.insert("sum", Box::new(sum::Sum));
+    funcs.insert("copysign", Box::new(copysign::CopySign));
+    funcs.insert("hypot", Box::new(hypot::Hypot));
+    funcs.insert("floor", Box::new(rounding::Floor));
+    funcs.insert("ceil", Box::new(rounding::Ceil));
+    funcs.insert("degrees", Box::new(angle::Degrees));
+    funcs.insert("radians", Box::new(angle::Radians));
+    funcs.insert("fact", Box::new(factorial::Factorial));
+    funcs.insert("divmod", Box::new(divmod::DivMod));
+
+    funcs
+}
+
+// Every looping intrinsic checks its iteration count against `--max-iterations` here, in both back-ends
+pub(crate) fn check_iteration_cap(
+    max_iterations: Option<u64>,
+    call: &str,
+    iterations: f64,
+) -> Result<()> {
+    match max_iterations {
+        Some(max) if iterations > max as f64 => Err(MathJitError::runtime(format!(
+            "{call} would run {iterations} iterations, more than the --max-iterations limit of {max}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Functions of this program which compiled code calls by name, with their addresses. The JIT maps them into
+// every execution engine it creates
+pub(crate) fn runtime_functions() -> Vec<(&'static str, usize)> {
+    vec![(
+        sum::CHECKED_ITERATIONS,
+        sum::checked_iterations as *const () as usize,
+    )]
+}
 
+// The standard intrinsics, less those turned off with `EvalOptions::disabled`
+pub(crate) fn enabled_intrinsics(
+    options: &EvalOptions,
+) -> HashMap<&'static str, Box<dyn BuiltinFunction>> {
+    let mut funcs = standard_intrinsics();
+    funcs.retain(|name, _| !options.disabled.iter().any(|x| x == name));
     funcs
 }
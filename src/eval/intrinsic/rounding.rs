@@ -0,0 +1,55 @@
+use anyhow::Result;
+use inkwell::values::FloatValue;
+
+use crate::{
+    eval::{ast_interpret::AstInterpreter, llvm::FunctionGen},
+    ops::MathOp,
+};
+
+use super::{BuiltinFunction, BuiltinProto};
+
+#[derive(Default)]
+pub(super) struct Floor;
+impl BuiltinFunction for Floor {
+    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        Ok(args[0].floor())
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
+        fg.cg.call_llvm_intrinsic(fg, "llvm.floor.f64", &args[..1])
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["x"],
+            description: "largest integer not greater than the argument, also written ⌊x⌋",
+        }
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Ceil;
+impl BuiltinFunction for Ceil {
+    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        Ok(args[0].ceil())
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
+        fg.cg.call_llvm_intrinsic(fg, "llvm.ceil.f64", &args[..1])
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["x"],
+            description: "smallest integer not less than the argument, also written ⌈x⌉",
+        }
+    }
+}
@@ -1,7 +1,9 @@
+use anyhow::Result;
 use inkwell::values::FloatValue;
 
 use crate::{
-    eval::{ast_interpret::AstInterpreter, llvm::FunctionGen},
+    error::MathJitError,
+    eval::{ast_interpret::AstInterpreter, llvm::FunctionGen, EvalOptions},
     ops::MathOp,
 };
 
@@ -10,19 +12,32 @@ use super::{BuiltinFunction, BuiltinProto};
 #[derive(Default)]
 pub(super) struct Sqrt;
 impl BuiltinFunction for Sqrt {
-    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> f64 {
-        args[0].sqrt()
+    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        Ok(args[0].sqrt())
     }
 
     fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
         fg.cg.call_llvm_intrinsic(fg, "llvm.sqrt.f64", &args[..1])
     }
 
+    fn check_args(&self, options: &EvalOptions, args: &[f64]) -> Result<()> {
+        if options.strict && args[0] < 0.0 {
+            return Err(MathJitError::runtime(format!(
+                "square root of negative number {}, which has no real result",
+                args[0]
+            )));
+        }
+        Ok(())
+    }
+
     fn replicate(&self) -> Box<dyn BuiltinFunction> {
         Box::new(Self)
     }
 
     fn proto(&self) -> BuiltinProto {
-        BuiltinProto { arg_count: 1 }
+        BuiltinProto {
+            args: &["x"],
+            description: "square root",
+        }
     }
 }
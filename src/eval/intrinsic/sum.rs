@@ -1,48 +1,83 @@
-use inkwell::values::FloatValue;
+use anyhow::Result;
+use inkwell::values::{FloatValue, FunctionValue};
 
 use crate::{
-    eval::{ast_interpret::AstInterpreter, llvm::FunctionGen},
+    error::MathJitError,
+    eval::{
+        ast_interpret::AstInterpreter,
+        llvm::{raise_runtime_error, FunctionGen},
+        EvalOptions,
+    },
     ops::MathOp,
+    parser::Function,
 };
 
-use super::{BuiltinFunction, BuiltinProto};
+use super::{check_iteration_cap, BuiltinFunction, BuiltinProto};
 
-#[derive(Default)]
-pub(super) struct Sum;
-impl BuiltinFunction for Sum {
-    fn eval_interpreter(&self, ast: &AstInterpreter, args: Vec<f64>) -> f64 {
-        let (start, stop, step) = (args[0], args[1], args[2]);
-        let Some(func) = ast.functions.last() else {
-            panic!("could not find last function for sum function");
-        };
+// Beyond this the interpreter would appear to hang, taking minutes or more to finish
+const MAX_INTERPRETER_ITERATIONS: f64 = 1e8;
 
-        assert!(
-            func.args.len() == 1,
-            "last function takes incorrect arguments"
-        );
+// Both back-ends run a fixed number of iterations, evaluating at `start + n * step` rather than
+// accumulating the step, so they agree on the final iteration
+fn iteration_count(start: f64, stop: f64, step: f64, exclusive: bool) -> f64 {
+    let span = (stop - start) / step;
+    if exclusive {
+        span.ceil()
+    } else {
+        span.floor() + 1.0
+    }
+}
 
-        let mut sum = 0.0;
-        let mut i = start;
-        loop {
-            sum += ast.eval_func(&func.body, func, &[i]).unwrap();
-            i += step;
-            if i > stop {
-                break;
-            }
+// The function summed when no lambda is given, which has to take a single argument
+fn summed_function(last: Option<&Function>) -> Result<&Function> {
+    let Some(func) = last else {
+        return Err(MathJitError::runtime(
+            "sum without a lambda sums the last defined function, but none is defined",
+        ));
+    };
+    if func.args.len() != 1 {
+        return Err(MathJitError::runtime(format!(
+            "sum without a lambda sums the last defined function, but {} doesn't take a single argument",
+            func.signature()
+        )));
+    }
+    Ok(func)
+}
+
+pub(super) const CHECKED_ITERATIONS: &str = "mathjit_sum_checked_iterations";
+
+// Called by compiled code to check a range which wasn't known before compiling, returning the iterations to run.
+// A range over the limit runs none, and its error is reported once the expression returns
+pub(super) extern "C" fn checked_iterations(
+    start: f64,
+    stop: f64,
+    step: f64,
+    iterations: f64,
+    max_iterations: f64,
+) -> f64 {
+    let checked = check_iteration_cap(
+        Some(max_iterations as u64),
+        &format!("sum from {start} to {stop} by {step}"),
+        iterations,
+    );
+    match checked {
+        Ok(()) => iterations,
+        Err(e) => {
+            raise_runtime_error(e);
+            0.0
         }
-        sum
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Sum;
+impl BuiltinFunction for Sum {
+    fn eval_interpreter(&self, ast: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        let func = summed_function(ast.functions.last())?;
+        self.sum_interpreter(ast, &args, |n| ast.eval_func(&func.body, func, &[n]))
     }
 
     fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
-        assert!(
-            args.len() == 3,
-            "too many arguments passed into Sum function"
-        );
-        let (start, stop, step) = (
-            fg.cg.build_block(args.first().as_ref().unwrap(), fg),
-            fg.cg.build_block(args.get(1).as_ref().unwrap(), fg),
-            fg.cg.build_block(args.get(2).as_ref().unwrap(), fg),
-        );
         let Some(func) = fg
             .cg
             .functions
@@ -51,106 +86,300 @@ impl BuiltinFunction for Sum {
             .last()
             .and_then(|x| fg.cg.module.get_function(&x.name))
         else {
-            panic!("could not find last function for sum function");
+            unreachable!("the summed function is checked by `jit_callee` before compiling");
         };
+        self.sum_jit(fg, func, args)
+    }
 
-        assert!(
-            func.count_params() == 1,
-            "last function {} has an incorrect number of arguments {}",
-            func.get_name().to_string_lossy(),
-            func.count_params()
-        );
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["start", "stop", "step"],
+            description: "sum of the last defined function, or a lambda, from min to max by step",
+        }
+    }
 
-        let counter = fg
+    fn check_args(&self, options: &EvalOptions, args: &[f64]) -> Result<()> {
+        let (start, stop, step) = (args[0], args[1], args.get(2).copied().unwrap_or(1.0));
+        let iterations = iteration_count(start, stop, step, options.sum_exclusive);
+        check_iteration_cap(
+            options.max_iterations,
+            &format!("sum from {start} to {stop} by {step}"),
+            iterations,
+        )?;
+        if iterations > MAX_INTERPRETER_ITERATIONS && !options.allow_huge_loops {
+            return Err(MathJitError::runtime(format!(
+                "sum from {start} to {stop} by {step} would run {iterations} iterations, pass --allow-huge-loops to run it anyway"
+            )));
+        }
+        Ok(())
+    }
+
+    // Only a range which doesn't depend on parameters can be checked before it is compiled, the compiled code
+    // checks the others each time it runs
+    fn check_jit_args(&self, options: &EvalOptions, args: &[Option<f64>]) -> Result<()> {
+        let step = args.get(2).copied().unwrap_or(Some(1.0));
+        if let (Some(start), Some(stop), Some(step)) = (args[0], args[1], step) {
+            let iterations = iteration_count(start, stop, step, options.sum_exclusive);
+            check_iteration_cap(
+                options.max_iterations,
+                &format!("sum from {start} to {stop} by {step}"),
+                iterations,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn has_jit_arg_check(&self) -> bool {
+        true
+    }
+
+    fn jit_callee<'f>(
+        &self,
+        functions: &[&'f Function],
+        args: &[MathOp],
+    ) -> Result<Option<&'f Function>> {
+        if let Some(MathOp::Lambda { .. }) = args.first() {
+            return Ok(None);
+        }
+        summed_function(functions.last().copied()).map(Some)
+    }
+
+    fn accepts_lambda(&self) -> bool {
+        true
+    }
+
+    fn eval_interpreter_lambda(
+        &self,
+        ast: &AstInterpreter,
+        lambda: &Function,
+        args: Vec<f64>,
+    ) -> Result<f64> {
+        self.sum_interpreter(ast, &args, |n| ast.eval_lambda(lambda, &[n]))
+    }
+
+    fn gen_jit_lambda<'b>(
+        &self,
+        fg: &FunctionGen<'b, '_>,
+        lambda: FunctionValue<'b>,
+        args: &[MathOp],
+    ) -> FloatValue<'b> {
+        self.sum_jit(fg, lambda, args)
+    }
+}
+
+impl Sum {
+    // Sums `term` over the range given by `args`
+    fn sum_interpreter(
+        &self,
+        ast: &AstInterpreter,
+        args: &[f64],
+        term: impl Fn(f64) -> Result<f64>,
+    ) -> Result<f64> {
+        // The step may be left out after a lambda
+        let (start, stop, step) = (args[0], args[1], args.get(2).copied().unwrap_or(1.0));
+        let mut sum = 0.0;
+        let mut compensation = 0.0;
+        let iterations = iteration_count(start, stop, step, ast.options.sum_exclusive);
+        let mut n = 0.0;
+        while n < iterations {
+            let value = term(start + n * step)?;
+            if ast.options.kahan {
+                let y = value - compensation;
+                let t = sum + y;
+                compensation = (t - sum) - y;
+                sum = t;
+            } else {
+                sum += value;
+            }
+            n += 1.0;
+        }
+        Ok(sum)
+    }
+
+    fn sum_jit<'b>(
+        &self,
+        fg: &FunctionGen<'b, '_>,
+        func: FunctionValue<'b>,
+        args: &[MathOp],
+    ) -> FloatValue<'b> {
+        // The JIT has already checked there are two or three arguments
+        let f64_type = fg.cg.context.f64_type();
+        let (start, stop, step) = (
+            fg.cg.build_block(&args[0], fg),
+            fg.cg.build_block(&args[1], fg),
+            args.get(2)
+                .map_or(f64_type.const_float(1.0), |x| fg.cg.build_block(x, fg)),
+        );
+        let span = fg
             .cg
             .builder
-            .build_alloca(fg.cg.context.f64_type(), "counter")
+            .build_float_div::<FloatValue>(
+                fg.cg
+                    .builder
+                    .build_float_sub::<FloatValue>(stop, start, "sum span")
+                    .unwrap(),
+                step,
+                "sum steps",
+            )
             .unwrap();
-        let sum = fg
+        let iterations = if fg.cg.options.sum_exclusive {
+            fg.cg.call_llvm_intrinsic_values("llvm.ceil.f64", &[span])
+        } else {
+            fg.cg
+                .builder
+                .build_float_add::<FloatValue>(
+                    fg.cg.call_llvm_intrinsic_values("llvm.floor.f64", &[span]),
+                    f64_type.const_float(1.0),
+                    "sum iterations",
+                )
+                .unwrap()
+        };
+        let iterations = match fg.cg.options.max_iterations {
+            Some(max) => fg.cg.call_runtime_function(
+                CHECKED_ITERATIONS,
+                &[
+                    start,
+                    stop,
+                    step,
+                    iterations,
+                    f64_type.const_float(max as f64),
+                ],
+            ),
+            None => iterations,
+        };
+
+        let counter = fg.cg.builder.build_alloca(f64_type, "counter").unwrap();
+        let sum = fg.cg.builder.build_alloca(f64_type, "sum").unwrap();
+        let compensation = fg
             .cg
             .builder
-            .build_alloca(fg.cg.context.f64_type(), "sum")
+            .build_alloca(f64_type, "compensation")
             .unwrap();
 
-        fg.cg.builder.build_store(counter, start).unwrap();
         fg.cg
             .builder
-            .build_store(sum, fg.cg.context.f64_type().const_zero())
+            .build_store(counter, f64_type.const_zero())
+            .unwrap();
+        fg.cg
+            .builder
+            .build_store(sum, f64_type.const_zero())
+            .unwrap();
+        fg.cg
+            .builder
+            .build_store(compensation, f64_type.const_zero())
             .unwrap();
 
+        let check_blk = fg.cg.context.append_basic_block(fg.llvm_func, "check");
         let loop_blk = fg.cg.context.append_basic_block(fg.llvm_func, "loop");
-        fg.cg.builder.build_unconditional_branch(loop_blk).unwrap();
-        fg.cg.builder.position_at_end(loop_blk);
+        let loop_exit_blk = fg.cg.context.append_basic_block(fg.llvm_func, "exit");
+        fg.cg.builder.build_unconditional_branch(check_blk).unwrap();
 
-        let fn_call = fg
+        fg.cg.builder.position_at_end(check_blk);
+        let n = fg
+            .cg
+            .builder
+            .build_load(f64_type, counter, "load counter")
+            .unwrap()
+            .into_float_value();
+        let cmp = fg
+            .cg
+            .builder
+            .build_float_compare(inkwell::FloatPredicate::OLT, n, iterations, "check")
+            .unwrap();
+        fg.cg
+            .builder
+            .build_conditional_branch(cmp, loop_blk, loop_exit_blk)
+            .unwrap();
+
+        fg.cg.builder.position_at_end(loop_blk);
+        let x = fg
             .cg
             .builder
-            .build_call(
-                func,
-                &[fg.cg
+            .build_float_add::<FloatValue>(
+                start,
+                fg.cg
                     .builder
-                    .build_load(fg.cg.context.f64_type(), counter, "load counter")
-                    .unwrap()
-                    .into_float_value()
-                    .into()],
-                "func call",
+                    .build_float_mul::<FloatValue>(n, step, "sum offset")
+                    .unwrap(),
+                "sum x",
             )
+            .unwrap();
+
+        let fn_call = fg
+            .cg
+            .builder
+            .build_call(func, &[x.into()], "func call")
             .expect("Failed to call");
 
         let ret = fn_call
             .try_as_basic_value()
             .left()
             .expect("Could not find left value");
-        let new_sum = fg
+        let old_sum = fg
             .cg
             .builder
-            .build_float_add::<FloatValue>(
-                ret.into_float_value(),
-                fg.cg
-                    .builder
-                    .build_load(fg.cg.context.f64_type(), sum, "load sum")
-                    .unwrap()
-                    .into_float_value(),
-                "add sum",
-            )
-            .unwrap();
+            .build_load(f64_type, sum, "load sum")
+            .unwrap()
+            .into_float_value();
+        let new_sum = if fg.cg.options.kahan {
+            let y = fg
+                .cg
+                .builder
+                .build_float_sub::<FloatValue>(
+                    ret.into_float_value(),
+                    fg.cg
+                        .builder
+                        .build_load(f64_type, compensation, "load compensation")
+                        .unwrap()
+                        .into_float_value(),
+                    "compensate",
+                )
+                .unwrap();
+            let t = fg
+                .cg
+                .builder
+                .build_float_add::<FloatValue>(old_sum, y, "add sum")
+                .unwrap();
+            let rounded = fg
+                .cg
+                .builder
+                .build_float_sub::<FloatValue>(t, old_sum, "rounded sum")
+                .unwrap();
+            let new_compensation = fg
+                .cg
+                .builder
+                .build_float_sub::<FloatValue>(rounded, y, "new compensation")
+                .unwrap();
+            fg.cg
+                .builder
+                .build_store(compensation, new_compensation)
+                .unwrap();
+            t
+        } else {
+            fg.cg
+                .builder
+                .build_float_add::<FloatValue>(ret.into_float_value(), old_sum, "add sum")
+                .unwrap()
+        };
 
         fg.cg.builder.build_store(sum, new_sum).unwrap();
-
         let new_counter = fg
             .cg
             .builder
-            .build_float_add::<FloatValue>(
-                fg.cg
-                    .builder
-                    .build_load(fg.cg.context.f64_type(), counter, "load counter")
-                    .unwrap()
-                    .into_float_value(),
-                step,
-                "add counter",
-            )
+            .build_float_add::<FloatValue>(n, f64_type.const_float(1.0), "add counter")
             .unwrap();
-
         fg.cg.builder.build_store(counter, new_counter).unwrap();
-        let cmp = fg
-            .cg
-            .builder
-            .build_float_compare(inkwell::FloatPredicate::OLE, new_counter, stop, "check")
-            .unwrap();
-        let loop_exit_blk = fg.cg.context.append_basic_block(fg.llvm_func, "exit");
+        fg.cg.builder.build_unconditional_branch(check_blk).unwrap();
+
+        fg.cg.builder.position_at_end(loop_exit_blk);
         fg.cg
             .builder
-            .build_conditional_branch(cmp, loop_blk, loop_exit_blk)
-            .unwrap();
-        fg.cg.builder.position_at_end(loop_exit_blk);
-        new_sum
-    }
-
-    fn replicate(&self) -> Box<dyn BuiltinFunction> {
-        Box::new(Self)
-    }
-
-    fn proto(&self) -> BuiltinProto {
-        BuiltinProto { arg_count: 3 }
+            .build_load(f64_type, sum, "load sum")
+            .unwrap()
+            .into_float_value()
     }
 }
@@ -1,3 +1,4 @@
+use anyhow::Result;
 use inkwell::values::FloatValue;
 
 use crate::{
@@ -10,8 +11,8 @@ use super::{BuiltinFunction, BuiltinProto};
 #[derive(Default)]
 pub(super) struct Pi;
 impl BuiltinFunction for Pi {
-    fn eval_interpreter(&self, _: &AstInterpreter, _args: Vec<f64>) -> f64 {
-        std::f64::consts::PI
+    fn eval_interpreter(&self, _: &AstInterpreter, _args: Vec<f64>) -> Result<f64> {
+        Ok(std::f64::consts::PI)
     }
 
     fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, _args: &[MathOp]) -> FloatValue<'b> {
@@ -23,15 +24,44 @@ impl BuiltinFunction for Pi {
     }
 
     fn proto(&self) -> BuiltinProto {
-        BuiltinProto { arg_count: 0 }
+        BuiltinProto {
+            args: &[],
+            description: "ratio of a circle's circumference to its diameter",
+        }
+    }
+}
+
+// 2*pi split into a sum of doubles, so multiples of it can be subtracted without losing precision
+const TAU_PARTS: [f64; 3] = [
+    std::f64::consts::TAU,
+    2.449_293_598_294_706_4e-16,
+    -5.989_539_619_436_679e-33,
+];
+
+// Cody-Waite style reduction into [-pi, pi], each step is fused so the product is never rounded
+fn reduce_angle(x: f64) -> f64 {
+    // Arguments already in range are left alone, and beyond 2^52 the quotient can't be represented
+    // exactly, so reduction would be meaningless
+    if !x.is_finite() || x.abs() <= std::f64::consts::PI || x.abs() > 2f64.powi(52) {
+        return x;
+    }
+    let k = (x / TAU_PARTS[0]).round();
+    TAU_PARTS.iter().fold(x, |r, part| (-k).mul_add(*part, r))
+}
+
+fn angle_arg(ast: &AstInterpreter, x: f64) -> f64 {
+    if ast.options.reduce_angles {
+        reduce_angle(x)
+    } else {
+        x
     }
 }
 
 #[derive(Default)]
 pub(super) struct Sin;
 impl BuiltinFunction for Sin {
-    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> f64 {
-        args[0].sin()
+    fn eval_interpreter(&self, ast: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        Ok(angle_arg(ast, args[0]).sin())
     }
 
     fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
@@ -43,15 +73,18 @@ impl BuiltinFunction for Sin {
     }
 
     fn proto(&self) -> BuiltinProto {
-        BuiltinProto { arg_count: 1 }
+        BuiltinProto {
+            args: &["x"],
+            description: "sine, in radians",
+        }
     }
 }
 
 #[derive(Default)]
 pub(super) struct Cos;
 impl BuiltinFunction for Cos {
-    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> f64 {
-        args[0].cos()
+    fn eval_interpreter(&self, ast: &AstInterpreter, args: Vec<f64>) -> Result<f64> {
+        Ok(angle_arg(ast, args[0]).cos())
     }
 
     fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
@@ -63,6 +96,26 @@ impl BuiltinFunction for Cos {
     }
 
     fn proto(&self) -> BuiltinProto {
-        BuiltinProto { arg_count: 1 }
+        BuiltinProto {
+            args: &["x"],
+            description: "cosine, in radians",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reduce_angle;
+
+    #[test]
+    fn large_arguments_reduce_accurately() {
+        // sin(1e15) and sin(2^50), from an arbitrary precision library
+        for (x, reference) in [
+            (1e15, 0.858_272_793_170_235_8),
+            (2f64.powi(50), 0.496_396_515_208_940_8),
+        ] {
+            let error = (reduce_angle(x).sin() - reference).abs();
+            assert!(error < 1e-15, "sin({x}) is off by {error:e}");
+        }
     }
 }
@@ -1,5 +1,11 @@
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
 
+use anyhow::Result;
 use inkwell::{
     attributes::Attribute,
     builder::Builder,
@@ -7,42 +13,158 @@ use inkwell::{
     execution_engine::ExecutionEngine,
     intrinsics::Intrinsic,
     memory_buffer::MemoryBuffer,
-    module::Module,
+    module::{Linkage, Module},
     passes::PassBuilderOptions,
-    targets::{CodeModel, InitializationConfig, RelocMode, Target, TargetMachine},
+    targets::{
+        CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+    },
     values::{FloatValue, FunctionValue},
-    OptimizationLevel,
+    FloatPredicate, OptimizationLevel,
 };
 
 use crate::{
-    ops::MathOp,
+    error::MathJitError,
+    ops::{CmpOp, MathOp, Span},
     parser::{Function, ParseOutput},
     timings::Timings,
 };
 
 use super::{
+    interrupted,
     intrinsic::{self, BuiltinFunction},
-    Eval, Response,
+    missing_function, results_differ, timed_out, unbound_variable, wrong_arg_count, Eval,
+    EvalOptions, Response, Value,
 };
 
 pub struct Jit {
-    pub verbose: bool,
+    pub options: EvalOptions,
     pub compile_ms: f64,
     pub run_ms: f64,
+    // User functions compiled over the session, counting every recompilation
+    pub functions_compiled: usize,
+    // Cached function modules thrown away because a definition they depend on changed
+    pub cache_invalidations: usize,
+    // Expressions the optimization passes were run on, which skipping the passes leaves at zero
+    pub optimized: usize,
     context: Context,
     functions: Vec<Function>,
-    cached_module: Option<Vec<u8>>,
+    // Values of constants, which are built into the compiled code as literals
+    constants: HashMap<char, f64>,
+    // Bitcode of each compiled function's own module. Expressions link these together, so redefining a
+    // function only means rebuilding its own module
+    compiled: HashMap<String, Vec<u8>>,
+    intrinsics: HashMap<&'static str, Box<dyn BuiltinFunction>>,
 }
 
 type EvalFunc = unsafe extern "C" fn() -> f64;
 
+// Code generation recurses once per level of nesting, so deeper trees are refused rather than overflowing
+// the stack. The parser's own limit is lower, this catches trees built by a library user or a raised limit
+const MAX_CODEGEN_DEPTH: usize = 2000;
+
+// How often a compiled expression running on its own thread is checked for an interrupt
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+thread_local! {
+    // An error raised by compiled code as it runs, such as a sum over the --max-iterations limit. Compiled code
+    // can't unwind, so it carries on and the error is reported once it returns. Only the first is kept
+    static RUNTIME_ERROR: RefCell<Option<anyhow::Error>> = const { RefCell::new(None) };
+}
+
+// Records an error for the compiled code running on this thread, for the host functions it calls
+pub(crate) fn raise_runtime_error(error: anyhow::Error) {
+    RUNTIME_ERROR.with_borrow_mut(|x| {
+        x.get_or_insert(error);
+    });
+}
+
+// Runs `func` on this thread, returning the error it raised rather than its result if there was one
+fn run_compiled(func: EvalFunc) -> Result<f64> {
+    let val = unsafe { func() };
+    match RUNTIME_ERROR.take() {
+        Some(e) => Err(e),
+        None => Ok(val),
+    }
+}
+
+// Points the declarations of `intrinsic::runtime_functions` in the engine's module at this program's functions
+fn map_runtime_functions(module: &Module, execution_engine: &ExecutionEngine) {
+    for (name, address) in intrinsic::runtime_functions() {
+        if let Some(func) = module.get_function(name) {
+            execution_engine.add_global_mapping(&func, address);
+        }
+    }
+}
+
+// Compiled code can't be interrupted, so with a timeout or an interrupt flag it runs on a thread of its own,
+// which is abandoned if it doesn't finish in time or is interrupted
+fn call_compiled(func: EvalFunc, options: &EvalOptions) -> Result<f64> {
+    if options.timeout.is_none() && options.interrupt.is_none() {
+        return run_compiled(func);
+    }
+    let deadline = options.timeout.map(|x| Instant::now() + x);
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(run_compiled(func));
+    });
+    loop {
+        let wait = deadline.map_or(INTERRUPT_POLL_INTERVAL, |x| {
+            x.saturating_duration_since(Instant::now())
+                .min(INTERRUPT_POLL_INTERVAL)
+        });
+        match rx.recv_timeout(wait) {
+            Ok(result) => return result,
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(MathJitError::backend(
+                    "compiled code stopped without returning a result",
+                ))
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        if options.is_interrupted() {
+            return Err(interrupted());
+        }
+        if deadline.is_some_and(|x| Instant::now() >= x) {
+            return Err(timed_out(options.timeout.unwrap()));
+        }
+    }
+}
+
+// An abandoned thread is still running code from the module, so it is leaked rather than freed
+fn abandon(
+    codegen: CodeGen<'_>,
+    execution_engine: ExecutionEngine<'_>,
+    error: anyhow::Error,
+) -> anyhow::Error {
+    std::mem::forget((codegen, execution_engine));
+    error
+}
+
+const DEFAULT_PASSES: &[&str] = &[
+    "instcombine",
+    "lcssa",
+    "jump-threading",
+    "loop-reduce",
+    "loop-rotate",
+    "loop-simplify",
+    "loop-unroll",
+    "sroa",
+    "sccp",
+    "sink",
+    "reassociate",
+    "gvn",
+    "simplifycfg",
+    "mem2reg",
+];
+
 pub struct CodeGen<'a> {
     pub context: &'a Context,
     pub module: Module<'a>,
     pub builder: Builder<'a>,
-    execution_engine: ExecutionEngine<'a>,
-    intrinsics: HashMap<&'static str, Box<dyn BuiltinFunction>>,
+    intrinsics: &'a HashMap<&'static str, Box<dyn BuiltinFunction>>,
     pub functions: &'a [Function],
+    constants: &'a HashMap<char, f64>,
+    pub options: &'a EvalOptions,
 }
 
 pub struct FunctionGen<'a, 'b> {
@@ -57,7 +179,9 @@ enum FunctionKind<'a> {
 }
 
 impl<'a> CodeGen<'a> {
-    fn compile(&self, ops: &Function, _verbose: bool) {
+    // Functions are declared before any body is built, so they can call each other regardless of
+    // the order they were defined in
+    fn declare(&self, ops: &Function) -> FunctionValue<'a> {
         let f64_type = self.context.f64_type();
         let fn_type = f64_type.fn_type(&vec![f64_type.into(); ops.args.len()][..], false);
         let function = self.module.add_function(&ops.name, fn_type, None);
@@ -89,11 +213,23 @@ impl<'a> CodeGen<'a> {
         function.add_attribute(inkwell::attributes::AttributeLoc::Function, nofree);
         function.add_attribute(inkwell::attributes::AttributeLoc::Function, nocallback);
         function.add_attribute(inkwell::attributes::AttributeLoc::Function, nounwind);
-        function.add_attribute(inkwell::attributes::AttributeLoc::Function, speculatable);
-        function.add_attribute(inkwell::attributes::AttributeLoc::Function, willreturn);
-        function.add_attribute(inkwell::attributes::AttributeLoc::Function, alwaysinline);
+        // A recursive function might never return, such as `f(x) = { f(x) if x > 0, 0 otherwise }`, so LLVM
+        // mustn't assume it does, nor hoist calls to it out of the branch guarding them
+        if !self.may_recurse(ops) {
+            // With --max-iterations a sum may raise an error as it runs, which a call hoisted out of an untaken
+            // branch would raise too
+            if self.options.max_iterations.is_none() {
+                function.add_attribute(inkwell::attributes::AttributeLoc::Function, speculatable);
+            }
+            function.add_attribute(inkwell::attributes::AttributeLoc::Function, willreturn);
+            function.add_attribute(inkwell::attributes::AttributeLoc::Function, alwaysinline);
+        }
         function.add_attribute(inkwell::attributes::AttributeLoc::Function, hot);
         function.add_attribute(inkwell::attributes::AttributeLoc::Function, inlinehint);
+        function
+    }
+
+    fn compile(&self, ops: &Function, function: FunctionValue<'a>, _verbose: bool) {
         let basic_block = self.context.append_basic_block(function, "entry");
         self.builder.position_at_end(basic_block);
 
@@ -108,19 +244,82 @@ impl<'a> CodeGen<'a> {
             .expect("Failed to build return");
     }
 
+    // Whether calling `func` can lead to a function which is already being called, directly such as `f`
+    // calling itself, or through other functions
+    fn may_recurse(&self, func: &Function) -> bool {
+        let mut path = vec![func.name.clone()];
+        self.reaches_cycle(&func.body, &mut path, &mut HashSet::new())
+    }
+
+    // `path` is the chain of calls being followed, and `done` the functions already found not to recurse
+    fn reaches_cycle(
+        &self,
+        body: &MathOp,
+        path: &mut Vec<String>,
+        done: &mut HashSet<String>,
+    ) -> bool {
+        body.called_functions().into_iter().any(|(name, _)| {
+            let Some(callee) = self.functions.iter().find(|x| x.name == name) else {
+                return false;
+            };
+            if path.iter().any(|x| x == name) {
+                return true;
+            }
+            if done.contains(name) {
+                return false;
+            }
+            path.push(name.to_string());
+            let found = self.reaches_cycle(&callee.body, path, done);
+            path.pop();
+            if !found {
+                done.insert(name.to_string());
+            }
+            found
+        })
+    }
+
+    // Lambdas become functions of their own, which LLVM can then inline into the caller. They are internal,
+    // so the lambdas of different functions don't clash when their modules are linked
+    fn compile_lambda(&self, arg: char, body: &MathOp) -> FunctionValue<'a> {
+        let lambda = Function {
+            name: "_lambda".to_string(),
+            args: vec![arg],
+            body: body.clone(),
+        };
+        let resume = self.builder.get_insert_block().unwrap();
+        let function = self.declare(&lambda);
+        function.set_linkage(Linkage::Internal);
+        self.compile(&lambda, function, self.options.verbose);
+        self.builder.position_at_end(resume);
+        function
+    }
+
+    // With `explain_ir`, instructions are named after the source they compute, such as `add:(x + 1)`, which
+    // shows up in the IR printed in verbose mode
+    fn value_name(&self, kind: &str, generic: &str, ops: &MathOp) -> String {
+        if self.options.explain_ir {
+            format!("{kind}:{ops}")
+        } else {
+            generic.to_string()
+        }
+    }
+
     pub fn build_block(&self, ops: &MathOp, gen: &FunctionGen<'a, '_>) -> FloatValue<'a> {
         match ops {
             MathOp::Num(x) => self.context.f64_type().const_float(*x),
             MathOp::Neg(x) => self
                 .builder
-                .build_float_neg(self.build_block(x, gen), "float neg")
+                .build_float_neg(
+                    self.build_block(x, gen),
+                    &self.value_name("neg", "float neg", ops),
+                )
                 .expect("Failed to negate float"),
             MathOp::Add { lhs, rhs } => self
                 .builder
                 .build_float_add(
                     self.build_block(lhs, gen),
                     self.build_block(rhs, gen),
-                    "float add",
+                    &self.value_name("add", "float add", ops),
                 )
                 .expect("Failed to add floats"),
             MathOp::Sub { lhs, rhs } => self
@@ -128,7 +327,7 @@ impl<'a> CodeGen<'a> {
                 .build_float_sub(
                     self.build_block(lhs, gen),
                     self.build_block(rhs, gen),
-                    "float sub",
+                    &self.value_name("sub", "float sub", ops),
                 )
                 .expect("Failed to sub floats"),
             MathOp::Mul { lhs, rhs } => self
@@ -136,7 +335,7 @@ impl<'a> CodeGen<'a> {
                 .build_float_mul(
                     self.build_block(lhs, gen),
                     self.build_block(rhs, gen),
-                    "float mul",
+                    &self.value_name("mul", "float mul", ops),
                 )
                 .expect("Failed to mul floats"),
             MathOp::Div { lhs, rhs } => self
@@ -144,7 +343,7 @@ impl<'a> CodeGen<'a> {
                 .build_float_div(
                     self.build_block(lhs, gen),
                     self.build_block(rhs, gen),
-                    "float div",
+                    &self.value_name("div", "float div", ops),
                 )
                 .expect("Failed to div floats"),
             MathOp::Exp { lhs, rhs } => {
@@ -152,8 +351,14 @@ impl<'a> CodeGen<'a> {
                 let rhs = *rhs.clone();
                 self.call_llvm_intrinsic(gen, "llvm.pow.f64", &[lhs, rhs])
             }
-            MathOp::Call { name, args } => match self.get_function(name) {
-                FunctionKind::Intrinsic(func) => func.gen_jit(gen, args),
+            MathOp::Call { name, args, .. } => match self.get_function(name) {
+                FunctionKind::Intrinsic(func) => match args.split_first() {
+                    Some((MathOp::Lambda { arg, body }, rest)) => {
+                        let lambda = self.compile_lambda(*arg, body);
+                        func.gen_jit_lambda(gen, lambda, rest)
+                    }
+                    _ => func.gen_jit(gen, args),
+                },
                 FunctionKind::Normal(cfunc) => {
                     let fn_args = args
                         .iter()
@@ -161,7 +366,11 @@ impl<'a> CodeGen<'a> {
                         .collect::<Vec<_>>();
                     let fn_call = self
                         .builder
-                        .build_call(cfunc, &fn_args[..], "func call")
+                        .build_call(
+                            cfunc,
+                            &fn_args[..],
+                            &self.value_name("call", "func call", ops),
+                        )
                         .expect("Failed to call");
                     let ret = fn_call
                         .try_as_basic_value()
@@ -171,7 +380,40 @@ impl<'a> CodeGen<'a> {
                     ret
                 }
             },
-            MathOp::Arg(n) => {
+            MathOp::Cmp { op, lhs, rhs } => {
+                let predicate = match op {
+                    CmpOp::Lt => FloatPredicate::OLT,
+                    CmpOp::Le => FloatPredicate::OLE,
+                    CmpOp::Gt => FloatPredicate::OGT,
+                    CmpOp::Ge => FloatPredicate::OGE,
+                    CmpOp::Eq => FloatPredicate::OEQ,
+                    CmpOp::Ne => FloatPredicate::UNE,
+                };
+                let cmp = self
+                    .builder
+                    .build_float_compare(
+                        predicate,
+                        self.build_block(lhs, gen),
+                        self.build_block(rhs, gen),
+                        &self.value_name("cmp", "float cmp", ops),
+                    )
+                    .expect("Failed to compare floats");
+                self.builder
+                    .build_select(
+                        cmp,
+                        self.context.f64_type().const_float(1.0),
+                        self.context.f64_type().const_zero(),
+                        &self.value_name("select", "cmp select", ops),
+                    )
+                    .expect("Failed to select comparison result")
+                    .into_float_value()
+            }
+            MathOp::Piecewise(cases) => self.build_piecewise(cases, gen),
+            // These are all refused by `Jit::check_supported` before anything is compiled
+            MathOp::List(_) => unreachable!("lists are not supported in JIT mode"),
+            MathOp::Tuple(_) => unreachable!("tuples are not supported in JIT mode"),
+            MathOp::Lambda { .. } => unreachable!("lambda used where a single number was expected"),
+            MathOp::Arg(n, _) => {
                 if let Some((index, _)) = gen.func.args.iter().enumerate().find(|x| x.1 == n) {
                     let arg = gen
                         .llvm_func
@@ -180,16 +422,91 @@ impl<'a> CodeGen<'a> {
                         .into_float_value();
                     return arg;
                 }
-                panic!("could not find argument")
+                if let Some(value) = self.constants.get(n) {
+                    return self.context.f64_type().const_float(*value);
+                }
+                unreachable!("unbound variable '{n}' wasn't caught before compiling")
             }
         }
     }
-    fn get_assembly(&self) -> String {
-        let triple = TargetMachine::get_default_triple();
-        let cpu = TargetMachine::get_host_cpu_name().to_string();
-        let features = TargetMachine::get_host_cpu_features().to_string();
+    fn build_piecewise(
+        &self,
+        cases: &[(Option<MathOp>, MathOp)],
+        gen: &FunctionGen<'a, '_>,
+    ) -> FloatValue<'a> {
+        let f64_type = self.context.f64_type();
+        let merge_blk = self
+            .context
+            .append_basic_block(gen.llvm_func, "piecewise merge");
+        let mut incoming = vec![];
+        let mut has_fallthrough = false;
+        for (cond, value) in cases {
+            let Some(cond) = cond else {
+                let value = self.build_block(value, gen);
+                incoming.push((value, self.builder.get_insert_block().unwrap()));
+                has_fallthrough = true;
+                break;
+            };
 
-        let target = Target::from_triple(&triple).unwrap();
+            let cmp = self
+                .builder
+                .build_float_compare(
+                    FloatPredicate::UNE,
+                    self.build_block(cond, gen),
+                    f64_type.const_zero(),
+                    "case check",
+                )
+                .expect("Failed to compare floats");
+            let case_blk = self.context.append_basic_block(gen.llvm_func, "case");
+            let next_blk = self.context.append_basic_block(gen.llvm_func, "next case");
+            self.builder
+                .build_conditional_branch(cmp, case_blk, next_blk)
+                .unwrap();
+
+            self.builder.position_at_end(case_blk);
+            let value = self.build_block(value, gen);
+            incoming.push((value, self.builder.get_insert_block().unwrap()));
+            self.builder.build_unconditional_branch(merge_blk).unwrap();
+            self.builder.position_at_end(next_blk);
+        }
+
+        // No case matched, so the result is undefined
+        if !has_fallthrough {
+            incoming.push((
+                f64_type.const_float(f64::NAN),
+                self.builder.get_insert_block().unwrap(),
+            ));
+        }
+        self.builder.build_unconditional_branch(merge_blk).unwrap();
+
+        self.builder.position_at_end(merge_blk);
+        let phi = self.builder.build_phi(f64_type, "piecewise").unwrap();
+        for (value, blk) in &incoming {
+            phi.add_incoming(&[(value, *blk)]);
+        }
+        phi.as_basic_value().into_float_value()
+    }
+
+    // The assembly is for the host, unless another target triple was chosen, in which case a generic CPU
+    // for that target is assumed. Execution always happens on the host either way
+    fn get_assembly(&self) -> Result<String> {
+        let (triple, cpu, features) = match &self.options.target {
+            Some(target) => (
+                TargetTriple::create(target),
+                "generic".to_string(),
+                String::new(),
+            ),
+            None => (
+                TargetMachine::get_default_triple(),
+                TargetMachine::get_host_cpu_name().to_string(),
+                TargetMachine::get_host_cpu_features().to_string(),
+            ),
+        };
+
+        let name = triple.as_str().to_string_lossy();
+        let target = Target::from_triple(&triple).map_err(|e| {
+            MathJitError::backend(format!("unsupported target triple '{name}': {e}"))
+        })?;
         let machine = target
             .create_target_machine(
                 &triple,
@@ -199,12 +516,40 @@ impl<'a> CodeGen<'a> {
                 RelocMode::Default,
                 CodeModel::JITDefault,
             )
-            .unwrap();
+            .ok_or_else(|| {
+                MathJitError::backend(format!(
+                    "LLVM could not create a target machine for '{name}'"
+                ))
+            })?;
         let mem_buf = machine
-            .write_to_memory_buffer(&self.module, inkwell::targets::FileType::Assembly)
+            .write_to_memory_buffer(&self.module, FileType::Assembly)
             .expect("Failed to get memory buffer");
         let asm = String::from_utf8_lossy(mem_buf.as_slice());
-        asm.to_string()
+        Ok(asm.to_string())
+    }
+
+    // Machine code size of each function defined in the module, read from the symbol table of an object
+    // file built by `machine`. Object formats which don't record symbol sizes, such as Mach-O, report none
+    fn code_sizes(&self, machine: &TargetMachine) -> Result<Vec<(String, u64)>> {
+        let mem_buf = machine
+            .write_to_memory_buffer(&self.module, FileType::Object)
+            .map_err(|e| {
+                MathJitError::backend(format!("LLVM could not emit an object file: {e}"))
+            })?;
+        let object = mem_buf
+            .create_object_file()
+            .map_err(|()| MathJitError::backend("LLVM could not read the emitted object file"))?;
+        let mut sizes = object
+            .get_symbols()
+            .filter_map(|symbol| {
+                let name = symbol.get_name()?.to_string_lossy().to_string();
+                self.module.get_function(&name)?;
+                Some((name, symbol.get_size()))
+            })
+            .filter(|(_, size)| *size > 0)
+            .collect::<Vec<_>>();
+        sizes.sort();
+        Ok(sizes)
     }
 
     fn get_function(&self, name: &str) -> FunctionKind<'a> {
@@ -213,7 +558,7 @@ impl<'a> CodeGen<'a> {
         } else if let Some(func) = self.intrinsics.get(name) {
             return FunctionKind::Intrinsic(func.replicate());
         }
-        panic!("could not find function {name}")
+        unreachable!("call to {name}, which isn't defined, wasn't caught before compiling")
     }
 
     pub fn call_llvm_intrinsic(
@@ -221,6 +566,36 @@ impl<'a> CodeGen<'a> {
         gen: &FunctionGen<'a, '_>,
         name: &str,
         args: &[MathOp],
+    ) -> FloatValue<'a> {
+        let args = args
+            .iter()
+            .map(|x| self.build_block(x, gen))
+            .collect::<Vec<_>>();
+        self.call_llvm_intrinsic_values(name, &args)
+    }
+
+    // Calls `name`, one of `intrinsic::runtime_functions`, which takes and returns numbers
+    pub fn call_runtime_function(&self, name: &str, args: &[FloatValue<'a>]) -> FloatValue<'a> {
+        let f64_type = self.context.f64_type();
+        let function = self.module.get_function(name).unwrap_or_else(|| {
+            let fn_type = f64_type.fn_type(&vec![f64_type.into(); args.len()], false);
+            self.module
+                .add_function(name, fn_type, Some(Linkage::External))
+        });
+        let call_args = args.iter().map(|x| (*x).into()).collect::<Vec<_>>();
+        self.builder
+            .build_call(function, &call_args, "call")
+            .expect("Failed to call")
+            .try_as_basic_value()
+            .left()
+            .expect("Could not find left value")
+            .into_float_value()
+    }
+
+    pub fn call_llvm_intrinsic_values(
+        &self,
+        name: &str,
+        args: &[FloatValue<'a>],
     ) -> FloatValue<'a> {
         let pow_intrinsic =
             Intrinsic::find(name).unwrap_or_else(|| panic!("Failed to find {name} intrinsic"));
@@ -230,10 +605,7 @@ impl<'a> CodeGen<'a> {
                 &vec![self.context.f64_type().into(); args.len()],
             )
             .unwrap_or_else(|| panic!("Failed to get {name} declaration"));
-        let call_args = args
-            .iter()
-            .map(|x| self.build_block(x, gen).into())
-            .collect::<Vec<_>>();
+        let call_args = args.iter().map(|x| (*x).into()).collect::<Vec<_>>();
         let call = self
             .builder
             .build_call(pow_fn, &call_args, "call")
@@ -247,62 +619,404 @@ impl<'a> CodeGen<'a> {
     }
 }
 
+// The bodies of what is being evaluated, each with the function it belongs to, or none for an expression
+fn bodies(ops: &ParseOutput) -> Vec<(&MathOp, Option<&Function>)> {
+    match ops {
+        ParseOutput::Body(ops) | ParseOutput::Constant { value: ops, .. } => vec![(ops, None)],
+        ParseOutput::Functions(funcs) => funcs.iter().map(|x| (&x.body, Some(x))).collect(),
+    }
+}
+
 impl Jit {
-    fn compile_function(&self, codegen: &CodeGen, func: &Function, timings: &mut Timings) {
-        codegen.compile(func, self.verbose);
+    /// Makes `func` callable as `name`, alongside (or in place of) the standard intrinsics.
+    #[must_use]
+    pub fn with_intrinsic(mut self, name: &'static str, func: Box<dyn BuiltinFunction>) -> Self {
+        self.intrinsics.insert(name, func);
+        self
+    }
+
+    /// Defines `functions` without parsing them, and compiles them straight away rather than when an
+    /// expression first calls them. Any custom intrinsics they call must be registered beforehand.
+    pub fn with_functions(mut self, functions: Vec<Function>) -> Result<Self> {
+        self.eval(ParseOutput::Functions(functions))?;
+        // Evaluating any expression builds every definition which hasn't been compiled yet
+        self.eval(ParseOutput::Body(MathOp::Num(0.0)))?;
+        Ok(self)
+    }
+
+    /// Gives each variable its value, as if it had been defined as a constant, such as `c = 3`.
+    #[must_use]
+    pub fn with_constants(mut self, constants: impl IntoIterator<Item = (char, f64)>) -> Self {
+        self.constants.extend(constants);
+        // Compiled functions hold constants as literals, so they have to be rebuilt
+        self.cache_invalidations += self.compiled.len();
+        self.compiled.clear();
+        self
+    }
+
+    fn compile_function<'a>(
+        &self,
+        codegen: &CodeGen<'a>,
+        func: &Function,
+        function: FunctionValue<'a>,
+        timings: &mut Timings,
+    ) {
+        codegen.compile(func, function, self.options.verbose);
         timings.lap(&format!("Codegen({})", func.name));
     }
 
-    fn create_codegen(&self, cached_module: &Option<Vec<u8>>) -> CodeGen {
-        let module = if let Some(cached_module) = cached_module.as_ref() {
-            Module::parse_bitcode_from_buffer(
-                &MemoryBuffer::create_from_memory_range(cached_module, "Cached module"),
-                &self.context,
-            )
-            .unwrap()
-        } else {
-            self.context.create_module("jit")
-        };
+    // Finds the first call, following calls to other defined functions, which can't be compiled: one to a
+    // function which has not been defined yet, or one with the wrong number of arguments, such as from a
+    // caller of a function since redefined with other parameters. Such functions are left uncompiled until
+    // the call is fixed
+    fn unbuildable_call(&self, func: &Function) -> Option<anyhow::Error> {
+        let mut visited = vec![&func.name[..]];
+        let mut pending = vec![func];
+        while let Some(caller) = pending.pop() {
+            let caller_name = if caller.name == "_repl" {
+                ""
+            } else {
+                &caller.name
+            };
+            if let Some(e) = self.arity_mismatch(&caller.body, caller_name) {
+                return Some(e);
+            }
+            // A function defined since may shadow an intrinsic the body was checked against
+            if let Err(e) = self.check_intrinsic_args(&caller.body) {
+                return Some(e);
+            }
+            let implied = match self.implied_callees(&caller.body) {
+                Ok(x) => x,
+                Err(e) => return Some(e),
+            };
+            for callee in implied {
+                if !visited.contains(&&callee.name[..]) {
+                    visited.push(&callee.name);
+                    pending.push(callee);
+                }
+            }
+            for (name, span) in caller.body.called_functions() {
+                if self.intrinsics.contains_key(name) || visited.contains(&name) {
+                    continue;
+                }
+                let Some(callee) = self.functions.iter().find(|x| x.name == name) else {
+                    return Some(missing_function(&self.options, name, caller_name, span));
+                };
+                visited.push(name);
+                pending.push(callee);
+            }
+        }
+        None
+    }
 
+    // Finds a call in `body` to a defined function with the wrong number of arguments, which would be
+    // compiled against the wrong signature. `caller` names the function `body` belongs to
+    fn arity_mismatch(&self, body: &MathOp, caller: &str) -> Option<anyhow::Error> {
+        if let MathOp::Call { name, args, span } = body {
+            let callee = self
+                .functions
+                .iter()
+                .find(|x| x.name == *name && x.name != "_repl");
+            if let Some(callee) = callee.filter(|x| x.args.len() != args.len()) {
+                return Some(wrong_arg_count(
+                    name,
+                    caller,
+                    args.len(),
+                    callee.args.len(),
+                    span,
+                ));
+            }
+        }
+        body.children()
+            .into_iter()
+            .find_map(|x| self.arity_mismatch(x, caller))
+    }
+
+    // Runs the expression in `unoptimized`, a copy of the module from before the optimization passes, and
+    // warns if the result doesn't match the optimized one
+    fn check_optimization(&self, unoptimized: &MemoryBuffer, optimized: f64) -> Result<()> {
+        let module =
+            Module::parse_bitcode_from_buffer(unoptimized, &self.context).map_err(|e| {
+                MathJitError::backend(format!("could not reload the unoptimized module: {e}"))
+            })?;
         let execution_engine = module
-            .create_jit_execution_engine(inkwell::OptimizationLevel::Aggressive)
-            .expect("Failed to create execution engine");
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|e| {
+                MathJitError::backend(format!(
+                    "could not create an execution engine for the unoptimized module: {e}"
+                ))
+            })?;
+        map_runtime_functions(&module, &execution_engine);
+        let func = unsafe {
+            execution_engine
+                .get_function::<EvalFunc>("_repl")
+                .unwrap()
+                .as_raw()
+        };
+        let val = match call_compiled(func, &self.options) {
+            Ok(val) => val,
+            Err(e) => {
+                std::mem::forget((module, execution_engine));
+                return Err(e);
+            }
+        };
+        if results_differ(val, optimized, self.options.epsilon) {
+            eprintln!("Warning: optimization changed the result from {val} to {optimized}");
+        }
+        Ok(())
+    }
 
+    // Builds `func` into a module of its own, returning its bitcode. The functions it calls are only declared,
+    // their definitions come from their own modules when everything is linked together
+    fn compile_function_module(&self, func: &Function, timings: &mut Timings) -> Vec<u8> {
+        let codegen = self.create_codegen(&func.name);
+        for other in self
+            .functions
+            .iter()
+            .filter(|x| x.name != "_repl" && x.name != func.name)
+        {
+            codegen.declare(other);
+        }
+        let function = codegen.declare(func);
+        self.compile_function(&codegen, func, function, timings);
+        codegen.module.write_bitcode_to_memory().as_slice().to_vec()
+    }
+
+    // Rejects anything which can't be compiled before any code is generated. `func` is the function `body`
+    // belongs to, or none for an expression
+    fn check_supported(&self, body: &MathOp, func: Option<&Function>) -> Result<()> {
+        let depth = body.depth();
+        if depth > MAX_CODEGEN_DEPTH {
+            return Err(MathJitError::unsupported(format!(
+                "expression is nested {depth} levels deep, which is too deep to compile (the limit is {MAX_CODEGEN_DEPTH})"
+            )));
+        }
+        if body.contains_list() {
+            return Err(MathJitError::unsupported(
+                "lists are not supported in JIT mode",
+            ));
+        }
+        if body.contains_tuple() {
+            return Err(MathJitError::unsupported(
+                "tuples are not supported in JIT mode",
+            ));
+        }
+        let tuple_call = body
+            .called_functions()
+            .into_iter()
+            .find(|(name, _)| self.intrinsic(name).is_some_and(|x| x.returns_tuple()));
+        if let Some((name, span)) = tuple_call {
+            return Err(MathJitError::Unsupported {
+                message: format!(
+                    "'{name}' gives a tuple, and tuples are not supported in JIT mode"
+                ),
+                span: Some(span.clone()),
+            }
+            .into());
+        }
+        self.check_intrinsic_args(body)?;
+        self.check_intrinsic_calls(body)?;
+        let constants = self.constants.keys().copied().collect::<Vec<_>>();
+        let (params, caller) = func.map_or((&[][..], ""), |x| (&x.args[..], &x.name[..]));
+        if let Some((name, span)) = body.unbound_variable(&[params, &constants].concat()) {
+            return Err(unbound_variable(name, caller, span));
+        }
+        // Definitions are left alone, as the functions they call may be redefined before they are compiled
+        if func.is_none() {
+            if let Some(e) = self.arity_mismatch(body, "") {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Lets each intrinsic called by `ops` which checks its arguments do so before anything is compiled. Only
+    // arguments which fold to a number are known, nothing is run to work out the others, which the compiled code
+    // has to check itself
+    fn check_intrinsic_calls(&self, ops: &MathOp) -> Result<()> {
+        if let MathOp::Call { name, args, span } = ops {
+            if let Some(ifunc) = self.intrinsic(name).filter(|x| x.has_jit_arg_check()) {
+                let args = match args.split_first() {
+                    Some((MathOp::Lambda { .. }, rest)) => rest,
+                    _ => &args[..],
+                };
+                let known = args
+                    .iter()
+                    .map(|x| match x.clone().fold_constants() {
+                        MathOp::Num(value) => Some(value),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                ifunc
+                    .check_jit_args(&self.options, &known)
+                    .map_err(|e| MathJitError::Runtime {
+                        message: e.to_string(),
+                        span: Some(span.clone()),
+                    })?;
+            }
+        }
+        ops.children()
+            .into_iter()
+            .try_for_each(|x| self.check_intrinsic_calls(x))
+    }
+
+    // The intrinsic a call to `name` reaches, unless a user function of the same name shadows it
+    fn intrinsic(&self, name: &str) -> Option<&dyn BuiltinFunction> {
+        if self.functions.iter().any(|x| x.name == name) {
+            return None;
+        }
+        self.intrinsics.get(name).map(AsRef::as_ref)
+    }
+
+    // Codegen relies on each intrinsic getting the arguments its prototype names, and on a lambda only being
+    // passed first to an intrinsic which accepts one. The parser only checks the standard intrinsics, and can't
+    // tell when one is shadowed by a user function
+    fn check_intrinsic_args(&self, ops: &MathOp) -> Result<()> {
+        let stray_lambda = |span: Option<&Span>| MathJitError::Runtime {
+            message: "lambda used where a single number was expected".to_string(),
+            span: span.cloned(),
+        };
+        let MathOp::Call { name, args, span } = ops else {
+            if let MathOp::Lambda { .. } = ops {
+                return Err(stray_lambda(None).into());
+            }
+            return ops
+                .children()
+                .into_iter()
+                .try_for_each(|x| self.check_intrinsic_args(x));
+        };
+        let ifunc = self.intrinsic(name);
+        let (lambda, rest) = match args.split_first() {
+            Some((MathOp::Lambda { body, .. }, rest))
+                if ifunc.is_some_and(|x| x.accepts_lambda()) =>
+            {
+                (Some(body), rest)
+            }
+            _ => (None, &args[..]),
+        };
+        if let Some(ifunc) = ifunc {
+            // After a lambda the last argument may be left out
+            let expected = ifunc.proto().arg_count();
+            if rest.len() != expected && !(lambda.is_some() && rest.len() + 1 == expected) {
+                return Err(wrong_arg_count(name, "", rest.len(), expected, span));
+            }
+        }
+        if let Some(body) = lambda {
+            self.check_intrinsic_args(body)?;
+        }
+        for arg in rest {
+            if let MathOp::Lambda { .. } = arg {
+                return Err(stray_lambda(Some(span)).into());
+            }
+            self.check_intrinsic_args(arg)?;
+        }
+        Ok(())
+    }
+
+    // Functions which the intrinsics called in `body` call themselves, such as the last defined function for
+    // `sum` without a lambda
+    fn implied_callees(&self, body: &MathOp) -> Result<Vec<&Function>> {
+        let defined = self
+            .functions
+            .iter()
+            .filter(|x| x.name != "_repl")
+            .collect::<Vec<_>>();
+        let mut callees = vec![];
+        let mut pending = vec![body];
+        while let Some(op) = pending.pop() {
+            if let MathOp::Call { name, args, span } = op {
+                if let Some(ifunc) = self.intrinsic(name) {
+                    let callee =
+                        ifunc
+                            .jit_callee(&defined, args)
+                            .map_err(|e| MathJitError::Runtime {
+                                message: e.to_string(),
+                                span: Some(span.clone()),
+                            })?;
+                    callees.extend(callee);
+                }
+            }
+            pending.extend(op.children());
+        }
+        Ok(callees)
+    }
+
+    // Functions which call `name`, and so were built against its old parameter list
+    fn callers(&self, name: &str) -> Vec<String> {
+        self.functions
+            .iter()
+            .filter(|x| x.body.called_functions().iter().any(|(x, _)| *x == name))
+            .map(|x| x.name.clone())
+            .collect()
+    }
+
+    fn create_codegen(&self, name: &str) -> CodeGen {
         let codegen = CodeGen {
             context: &self.context,
-            module,
+            module: self.context.create_module(name),
             builder: self.context.create_builder(),
-            execution_engine,
-            intrinsics: intrinsic::standard_intrinsics(),
+            intrinsics: &self.intrinsics,
             functions: &self.functions,
+            constants: &self.constants,
+            options: &self.options,
         };
         codegen
     }
 }
 
 impl Eval for Jit {
-    fn new(verbose: bool) -> Self {
+    fn new(options: EvalOptions) -> Self {
         let config = InitializationConfig {
             asm_printer: true,
             ..Default::default()
         };
 
         Target::initialize_native(&config).expect("failed to initialize target");
+        if options.target.is_some() {
+            Target::initialize_all(&config);
+        }
         let context = Context::create();
         Self {
-            verbose,
+            intrinsics: intrinsic::enabled_intrinsics(&options),
+            options,
             compile_ms: 0f64,
             run_ms: 0f64,
+            functions_compiled: 0,
+            cache_invalidations: 0,
+            optimized: 0,
             context,
             functions: Vec::new(),
-            cached_module: None,
+            constants: HashMap::new(),
+            compiled: HashMap::new(),
         }
     }
 
-    fn eval(&mut self, ops: ParseOutput) -> Option<(Response, Timings)> {
+    fn eval(&mut self, ops: ParseOutput) -> Result<(Response, Timings)> {
+        // The value is compiled and run like any other expression, then kept as a number
+        if let ParseOutput::Constant { name, value } = ops {
+            let (response, timings) = self.eval(ParseOutput::Body(value))?;
+            let Response::Value(Value::Scalar(value)) = response else {
+                unreachable!("the JIT only produces numbers");
+            };
+            // Compiled functions hold the old value as a literal, so they have to be rebuilt
+            if self
+                .constants
+                .insert(name, value)
+                .is_some_and(|x| x != value)
+            {
+                self.cache_invalidations += self.compiled.len();
+                self.compiled.clear();
+            }
+            return Ok((response, timings));
+        }
+
+        for (body, func) in bodies(&ops) {
+            self.check_supported(body, func)?;
+        }
+
         self.functions.retain(|x| x.name != "_repl");
         let (functions, exec_last) = match ops {
-            ParseOutput::Body(ops) => (
+            ParseOutput::Body(ops) | ParseOutput::Constant { value: ops, .. } => (
                 vec![Function {
                     name: "_repl".to_string(),
                     args: vec![],
@@ -313,28 +1027,89 @@ impl Eval for Jit {
             ParseOutput::Functions(funcs) => (funcs, false),
         };
 
-        let mut changed_functions = vec![];
-
+        let mut redefined = vec![];
         for func in functions {
-            if let Some(item) = self.functions.iter_mut().find(|x| x.name == func.name) {
-                *item = func;
-                changed_functions.push(item.name.clone());
-            } else {
+            let Some(index) = self.functions.iter().position(|x| x.name == func.name) else {
                 self.functions.push(func);
+                continue;
+            };
+            // Only the redefined function is rebuilt, unless its parameters changed, in which case its callers
+            // declared it with the wrong type and have to be rebuilt too. A definition which only swaps the
+            // operands of `+` or `*`, such as `f(x)=1+x` after `f(x)=x+1`, keeps its compiled module
+            let old = &self.functions[index];
+            let mut stale = vec![];
+            if old.args != func.args || !old.body.equivalent(&func.body) {
+                stale.push(func.name.clone());
+            }
+            if old.args.len() != func.args.len() {
+                stale.extend(self.callers(&func.name));
             }
+            for name in stale {
+                if self.compiled.remove(&name).is_some() {
+                    self.cache_invalidations += 1;
+                }
+            }
+            redefined.push(func.signature());
+            self.functions[index] = func;
         }
 
-        let mut timings = Timings::start();
-        let codegen = self.create_codegen(&self.cached_module);
-        timings.lap("CreateCodegen");
+        if !exec_last {
+            // Definitions are compiled once an expression needs them, so `f(x)=x^2 & f(3)` builds a single module
+            return Ok((Response::Ok { redefined }, Timings::start()));
+        }
 
-        self.functions
+        let repl = self.functions.last().unwrap();
+        if let Some(e) = self.unbuildable_call(repl) {
+            self.functions.retain(|x| x.name != "_repl");
+            return Err(e);
+        }
+
+        let mut timings = Timings::start();
+        let built = self
+            .functions
             .iter()
-            .filter(|x| {
-                changed_functions.contains(&x.name)
-                    || codegen.module.get_function(&x.name).is_none()
+            .filter(|x| x.name != "_repl" && !self.compiled.contains_key(&x.name))
+            .filter(|x| self.unbuildable_call(x).is_none())
+            .map(|x| {
+                (
+                    x.name.clone(),
+                    self.compile_function_module(x, &mut timings),
+                )
             })
-            .for_each(|x| self.compile_function(&codegen, x, &mut timings));
+            .collect::<Vec<_>>();
+
+        let codegen = self.create_codegen("jit");
+        for bitcode in self.compiled.values().chain(built.iter().map(|(_, x)| x)) {
+            let module = Module::parse_bitcode_from_buffer(
+                &MemoryBuffer::create_from_memory_range(bitcode, "Function module"),
+                &self.context,
+            )
+            .map_err(|e| {
+                MathJitError::backend(format!("could not load a compiled function: {e}"))
+            })?;
+            codegen.module.link_in_module(module).map_err(|e| {
+                MathJitError::backend(format!("could not link a compiled function: {e}"))
+            })?;
+        }
+        timings.lap("Link");
+
+        // The expression is rebuilt every time, and never cached
+        let function = codegen.declare(repl);
+        self.compile_function(&codegen, repl, function, &mut timings);
+
+        // Optimizing or running IR which fails verification could crash, so it is reported instead
+        if let Err(e) = codegen.module.verify() {
+            if self.options.verbose {
+                codegen.module.print_to_stderr();
+            }
+            drop(codegen);
+            // Only the expression is dropped. The definitions built just now aren't cached, so they are built
+            // again next time rather than reusing code which may be the broken part
+            self.functions.retain(|x| x.name != "_repl");
+            return Err(MathJitError::backend(format!(
+                "generated code failed LLVM verification, this is a bug in MathJIT: {e}"
+            )));
+        }
 
         let triple = TargetMachine::get_default_triple();
         let cpu = TargetMachine::get_host_cpu_name().to_string();
@@ -351,22 +1126,10 @@ impl Eval for Jit {
                 CodeModel::JITDefault,
             )
             .unwrap();
-        let passes: &[&str] = &[
-            "instcombine",
-            "lcssa",
-            "jump-threading",
-            "loop-reduce",
-            "loop-rotate",
-            "loop-simplify",
-            "loop-unroll",
-            "sroa",
-            "sccp",
-            "sink",
-            "reassociate",
-            "gvn",
-            "simplifycfg",
-            "mem2reg",
-        ];
+        let passes = match &self.options.passes {
+            Some(passes) => passes.join(","),
+            None => DEFAULT_PASSES.join(","),
+        };
         let pass_cfg = PassBuilderOptions::create();
         pass_cfg.set_loop_interleaving(true);
         pass_cfg.set_loop_slp_vectorization(true);
@@ -374,45 +1137,101 @@ impl Eval for Jit {
         pass_cfg.set_loop_vectorization(true);
         pass_cfg.set_merge_functions(true);
 
-        codegen
-            .module
-            .run_passes(&passes.join(","), &machine, pass_cfg)
-            .unwrap();
+        // The module is copied before optimizing, so it can be run again unoptimized afterwards
+        let unoptimized = (self.options.check_opt && !passes.is_empty())
+            .then(|| codegen.module.write_bitcode_to_memory());
+        if !passes.is_empty() {
+            codegen
+                .module
+                .run_passes(&passes, &machine, pass_cfg)
+                .map_err(|e| {
+                    MathJitError::backend(format!("LLVM rejected the pass list '{passes}': {e}"))
+                })?;
+        }
 
-        if self.verbose {
+        if self.options.verbose {
             println!("--- LLVM IR ---");
             codegen.module.print_to_stderr();
-            println!("--- Assembly ---\n{}", codegen.get_assembly());
+            println!("--- Assembly ---\n{}", codegen.get_assembly()?);
+            println!("--- Code size ---");
+            for (name, size) in codegen.code_sizes(&machine)? {
+                println!("{name}: {size} bytes");
+            }
         }
 
-        if exec_last {
-            let last = &self.functions.last().unwrap().name;
-            let func = unsafe {
-                codegen
-                    .execution_engine
-                    .get_function::<EvalFunc>(last)
-                    .unwrap()
-                    .as_raw()
-            };
-            timings.lap("LLVMCompile");
-            let val = unsafe { func() };
-            timings.lap("Exec");
-            return Some((Response::Value(val), timings));
+        let execution_engine = codegen
+            .module
+            .create_jit_execution_engine(OptimizationLevel::Aggressive)
+            .map_err(|e| {
+                MathJitError::backend(format!("could not create an execution engine: {e}"))
+            })?;
+        map_runtime_functions(&codegen.module, &execution_engine);
+        let func = unsafe {
+            execution_engine
+                .get_function::<EvalFunc>("_repl")
+                .unwrap()
+                .as_raw()
+        };
+        timings.lap("LLVMCompile");
+        let compile_ms = timings.total();
+        for _ in 0..self.options.warmup {
+            if let Err(e) = call_compiled(func, &self.options) {
+                return Err(abandon(codegen, execution_engine, e));
+            }
         }
-
-        let cached = codegen.module.write_bitcode_to_memory().as_slice().to_vec();
+        timings.skip();
+        let val = match call_compiled(func, &self.options) {
+            Ok(val) => val,
+            Err(e) => return Err(abandon(codegen, execution_engine, e)),
+        };
+        timings.lap("Exec");
+        drop(execution_engine);
         drop(codegen);
-
-        if changed_functions.is_empty() {
-            self.cached_module = Some(cached);
-        } else {
-            // We skip caching the module so LLVM can rebuild the entire IR with the new version of the func
-            // Ideally, LLVM would provide a: module.remove_function(...)
-            // Perhaps we could map changed functions with a seperate name, and call the new name? (LLVM might provide this through Function.name())
-            // ^ but that might increase compile times for proper evaluations due to unneeded IR, though not caching also increases comp times
-            self.cached_module = None;
+        self.functions_compiled += built.len();
+        self.optimized += usize::from(!passes.is_empty());
+        self.compiled.extend(built);
+        self.compile_ms += compile_ms;
+        self.run_ms += timings.total() - compile_ms;
+        if let Some(unoptimized) = unoptimized {
+            self.check_optimization(&unoptimized, val)?;
         }
+        Ok((Response::Value(Value::Scalar(val)), timings))
+    }
+
+    fn stats(&self) -> Vec<(&'static str, String)> {
+        vec![
+            (
+                "Functions defined",
+                self.functions
+                    .iter()
+                    .filter(|x| x.name != "_repl")
+                    .count()
+                    .to_string(),
+            ),
+            ("Functions compiled", self.functions_compiled.to_string()),
+            ("Cache invalidations", self.cache_invalidations.to_string()),
+            ("Expressions optimized", self.optimized.to_string()),
+            ("Compile time (MS)", format!("{:.4}", self.compile_ms)),
+            ("Run time (MS)", format!("{:.4}", self.run_ms)),
+        ]
+    }
+
+    fn functions(&self) -> Vec<&Function> {
+        self.functions
+            .iter()
+            .filter(|x| x.name != "_repl")
+            .collect()
+    }
+
+    // Session statistics are kept, only the definitions, constants and the modules compiled from them are
+    // dropped
+    fn reset(&mut self) {
+        self.functions.clear();
+        self.constants.clear();
+        self.compiled.clear();
+    }
 
-        Some((Response::Ok, timings))
+    fn produced_by(&self) -> Vec<(&'static str, Option<Value>)> {
+        vec![("JIT", None)]
     }
 }
@@ -1,15 +1,332 @@
-use crate::{parser::ParseOutput, timings::Timings};
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    error::MathJitError,
+    ops::Span,
+    parser::{Function, ParseOutput},
+    timings::Timings,
+};
 
 pub mod ast_interpret;
+pub mod compare;
 pub mod intrinsic;
 pub mod llvm;
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    List(Vec<f64>),
+    Tuple(Vec<f64>),
+}
+
+impl Value {
+    pub fn map(self, op: impl Fn(f64) -> f64) -> Value {
+        match self {
+            Value::Scalar(x) => Value::Scalar(op(x)),
+            Value::List(xs) => Value::List(xs.into_iter().map(op).collect()),
+            Value::Tuple(xs) => Value::Tuple(xs.into_iter().map(op).collect()),
+        }
+    }
+
+    // Element-wise combination, where a scalar operand is broadcast across the other list
+    pub fn zip_with(self, other: Value, op: impl Fn(f64, f64) -> f64) -> Result<Value> {
+        Ok(match (self, other) {
+            (Value::Tuple(_), _) | (_, Value::Tuple(_)) => {
+                return Err(MathJitError::runtime("tuples can't be used in arithmetic"))
+            }
+            (Value::Scalar(lhs), Value::Scalar(rhs)) => Value::Scalar(op(lhs, rhs)),
+            (Value::List(lhs), Value::Scalar(rhs)) => {
+                Value::List(lhs.into_iter().map(|x| op(x, rhs)).collect())
+            }
+            (Value::Scalar(lhs), Value::List(rhs)) => {
+                Value::List(rhs.into_iter().map(|x| op(lhs, x)).collect())
+            }
+            (Value::List(lhs), Value::List(rhs)) => {
+                if lhs.len() != rhs.len() {
+                    return Err(MathJitError::runtime(format!(
+                        "shape mismatch, list of length {} combined with list of length {}",
+                        lhs.len(),
+                        rhs.len()
+                    )));
+                }
+                Value::List(lhs.into_iter().zip(rhs).map(|(x, y)| op(x, y)).collect())
+            }
+        })
+    }
+}
+
+impl Value {
+    /// Whether two results differ by more than `epsilon` (relatively) in any element, or aren't the same
+    /// shape at all.
+    pub fn differs(&self, other: &Value, epsilon: f64) -> bool {
+        match (self, other) {
+            (Value::Scalar(lhs), Value::Scalar(rhs)) => results_differ(*lhs, *rhs, epsilon),
+            (Value::List(lhs), Value::List(rhs)) | (Value::Tuple(lhs), Value::Tuple(rhs)) => {
+                lhs.len() != rhs.len()
+                    || lhs
+                        .iter()
+                        .zip(rhs)
+                        .any(|(x, y)| results_differ(*x, *y, epsilon))
+            }
+            _ => true,
+        }
+    }
+
+    /// Formats the value as `Display` does, but with each number written by `show`
+    pub fn display_with(&self, show: impl Fn(f64) -> String) -> String {
+        match self {
+            Value::Scalar(x) => show(*x),
+            Value::List(xs) => format!(
+                "[{}]",
+                xs.iter().map(|x| show(*x)).collect::<Vec<_>>().join(", ")
+            ),
+            // A single value keeps its trailing comma, so it can't be mistaken for a scalar
+            Value::Tuple(xs) if xs.len() == 1 => format!("({},)", show(xs[0])),
+            Value::Tuple(xs) => format!(
+                "({})",
+                xs.iter().map(|x| show(*x)).collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.display_with(|x| x.to_string()))
+    }
+}
+
 pub enum Response {
-    Value(f64),
-    Ok,
+    Value(Value),
+    // Functions were defined, `redefined` holds the signature of each which replaced an earlier definition
+    Ok { redefined: Vec<String> },
+}
+
+/// The `epsilon` used unless one is given, such as with `--epsilon`.
+pub const DEFAULT_EPSILON: f64 = 1e-9;
+
+#[derive(Debug, Clone)]
+pub struct EvalOptions {
+    pub verbose: bool,
+    // Unmeasured executions of a JIT-compiled expression before the timed run
+    pub warmup: usize,
+    // Reduce `sin`/`cos` arguments with an extended-precision 2*pi in the interpreter
+    pub reduce_angles: bool,
+    // Use compensated (Kahan) summation in `sum`, trading speed for precision
+    pub kahan: bool,
+    // Exclude `stop` from the range iterated by `sum`
+    pub sum_exclusive: bool,
+    // Replaces the JIT's default optimization pipeline, an empty list skips optimization entirely
+    pub passes: Option<Vec<String>>,
+    // Lift the interpreter's limit on how many iterations a single `sum` may run
+    pub allow_huge_loops: bool,
+    // Multiplying by zero in the interpreter gives zero without evaluating the other operand, so `0 * nan` is 0
+    // and an error in the other operand goes unreported
+    pub fast: bool,
+    // The interpreter reports every number and intermediate result which isn't a whole number, to within `epsilon`
+    pub decimals_only: bool,
+    // Report the square root of a negative number as an error in the interpreter, rather than returning NaN
+    pub strict: bool,
+    // Target triple for the assembly printed in verbose mode, the host's when unset
+    pub target: Option<String>,
+    // Abandon evaluating an expression which runs for longer than this
+    pub timeout: Option<Duration>,
+    // Name each instruction of the JIT's IR after the source it computes, rather than generically
+    pub explain_ir: bool,
+    // Also run JIT-compiled expressions without the optimization passes, warning if the results differ
+    pub check_opt: bool,
+    // Relative difference beyond which two results of the same expression are reported as disagreeing
+    pub epsilon: f64,
+    // Set from elsewhere, such as a Ctrl-C handler, to cancel the evaluation in progress. It isn't cleared
+    // by the evaluator, that is left to whoever sets it
+    pub interrupt: Option<Arc<AtomicBool>>,
+    // Standard intrinsics which can't be called, such as for sandboxing. Calling one is reported as disabled
+    pub disabled: Vec<String>,
+    // Most iterations any looping intrinsic, such as `sum`, may run in either back-end
+    pub max_iterations: Option<u64>,
+}
+
+// Written out so that `epsilon` gets a usable default, rather than zero
+impl Default for EvalOptions {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            warmup: 0,
+            reduce_angles: false,
+            kahan: false,
+            sum_exclusive: false,
+            passes: None,
+            allow_huge_loops: false,
+            fast: false,
+            decimals_only: false,
+            strict: false,
+            target: None,
+            timeout: None,
+            explain_ir: false,
+            check_opt: false,
+            epsilon: DEFAULT_EPSILON,
+            interrupt: None,
+            disabled: vec![],
+            max_iterations: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// Which back-end evaluates an expression.
+pub enum Mode {
+    Interpret,
+    Jit,
+    // Both back-ends, reporting when their results disagree
+    Compare,
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Mode::Interpret => "Interpreter",
+                Mode::Jit => "JIT",
+                Mode::Compare => "Compare",
+            }
+        )
+    }
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jit" | "j" | "JIT" => Ok(Mode::Jit),
+            "interpret" | "i" | "interpreter" | "Interpreter" => Ok(Mode::Interpret),
+            "compare" | "c" | "Compare" => Ok(Mode::Compare),
+            _ => Err(anyhow!(
+                "invalid selection, wanted 'jit', 'interpret' or 'compare'"
+            )),
+        }
+    }
 }
 
 pub trait Eval {
-    fn new(verbose: bool) -> Self;
-    fn eval(&mut self, ops: ParseOutput) -> Option<(Response, Timings)>;
+    fn new(options: EvalOptions) -> Self;
+    fn eval(&mut self, ops: ParseOutput) -> Result<(Response, Timings)>;
+    fn stats(&self) -> Vec<(&'static str, String)>;
+    // Every function currently defined, for showing to the user
+    fn functions(&self) -> Vec<&Function>;
+    // Forgets every defined function and constant, keeping the options and registered intrinsics
+    fn reset(&mut self);
+    // The back-ends which produced the last value, for labelling it. One whose own result differs from the
+    // value returned, such as the JIT's in compare mode, comes with that result
+    fn produced_by(&self) -> Vec<(&'static str, Option<Value>)>;
+}
+
+// A top-level expression has no parameters, so any variable in it is unknown
+pub(crate) fn unbound_variable(name: char, caller: &str, span: &Span) -> anyhow::Error {
+    let message = if caller.is_empty() {
+        format!("unknown variable '{name}'")
+    } else {
+        format!("'{name}' is not a parameter of {caller}")
+    };
+    MathJitError::UnknownVariable {
+        name,
+        message,
+        span: span.clone(),
+    }
+    .into()
+}
+
+// `caller` is empty when the call is made directly from an evaluated expression, or isn't known
+pub(crate) fn wrong_arg_count(
+    name: &str,
+    caller: &str,
+    provided: usize,
+    expected: usize,
+    span: &Span,
+) -> anyhow::Error {
+    let call = if caller.is_empty() {
+        format!("'{name}' call")
+    } else {
+        format!("'{name}' call in {caller}")
+    };
+    MathJitError::Arity {
+        name: name.to_string(),
+        provided,
+        message: format!(
+            "incorrect argument count for {call}, {provided} provided, {expected} expected"
+        ),
+        span: span.clone(),
+    }
+    .into()
+}
+
+// NaN agrees with NaN, and an infinity only with itself
+pub(crate) fn results_differ(lhs: f64, rhs: f64, epsilon: f64) -> bool {
+    if lhs == rhs || (lhs.is_nan() && rhs.is_nan()) {
+        return false;
+    }
+    if !lhs.is_finite() || !rhs.is_finite() {
+        return true;
+    }
+    (lhs - rhs).abs() > epsilon * lhs.abs().max(rhs.abs())
+}
+
+pub(crate) fn timed_out(timeout: Duration) -> anyhow::Error {
+    MathJitError::TimedOut { timeout }.into()
+}
+
+pub(crate) fn interrupted() -> anyhow::Error {
+    MathJitError::Interrupted.into()
+}
+
+impl EvalOptions {
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.interrupt
+            .as_ref()
+            .is_some_and(|x| x.load(Ordering::Relaxed))
+    }
+}
+
+// An intrinsic turned off with `EvalOptions::disabled` is reported as such, rather than as undefined
+pub(crate) fn missing_function(
+    options: &EvalOptions,
+    name: &str,
+    caller: &str,
+    span: &Span,
+) -> anyhow::Error {
+    if options.disabled.iter().any(|x| x == name) {
+        return MathJitError::Disabled {
+            name: name.to_string(),
+            span: span.clone(),
+        }
+        .into();
+    }
+    undefined_function(name, caller, span)
+}
+
+// `caller` is empty when the call is made directly from an evaluated expression
+pub(crate) fn undefined_function(name: &str, caller: &str, span: &Span) -> anyhow::Error {
+    let message = if caller.is_empty() {
+        format!("function {name} is not defined")
+    } else {
+        format!("function {name} referenced by {caller} is not defined")
+    };
+    MathJitError::UnknownFunction {
+        name: name.to_string(),
+        message,
+        span: span.clone(),
+    }
+    .into()
 }
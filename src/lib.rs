@@ -0,0 +1,190 @@
+pub mod error;
+pub mod eval;
+pub mod lint;
+pub mod ops;
+pub mod parser;
+#[cfg(test)]
+mod test_util;
+pub mod timings;
+pub mod tokenizer;
+mod util;
+pub mod validate;
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use error::MathJitError;
+use eval::{
+    ast_interpret::AstInterpreter, compare::Compare, llvm::Jit, Eval, EvalOptions, Mode, Response,
+    Value,
+};
+use parser::ParseOutput;
+use timings::Timings;
+
+/// Lazily evaluates each chained expression in order, so definitions carry forward to later ones.
+pub fn evaluate_chain<T: Eval>(
+    env: &mut T,
+    ops: Vec<ParseOutput>,
+) -> impl Iterator<Item = Result<(Response, Timings)>> + '_ {
+    ops.into_iter().map(move |op| env.eval(op))
+}
+
+/// Merges runs of consecutive function definitions, so each run is defined (and, with the JIT,
+/// compiled and linked together) in a single evaluation rather than one statement at a time.
+pub fn batch_definitions(ops: Vec<ParseOutput>) -> Vec<ParseOutput> {
+    let mut batched = Vec::with_capacity(ops.len());
+    for op in ops {
+        match op {
+            ParseOutput::Functions(funcs) => match batched.last_mut() {
+                Some(ParseOutput::Functions(batch)) => batch.extend(funcs),
+                _ => batched.push(ParseOutput::Functions(funcs)),
+            },
+            op => batched.push(op),
+        }
+    }
+    batched
+}
+
+/// Parses `math_expr` and yields the result of each of its chained expressions as it is evaluated.
+pub fn evaluate_all<'a, T: Eval>(
+    env: &'a mut T,
+    math_expr: &str,
+) -> Result<impl Iterator<Item = Result<Response>> + 'a> {
+    let ops = parser::Parser::new(math_expr)?.parse()?;
+    Ok(evaluate_chain(env, ops).map(|x| x.map(|(response, _)| response)))
+}
+
+/// Evaluates `expr` with `vars` giving the values of its variables, for using MathJIT as a formula engine
+/// whose host supplies new values on each call, such as `a*b + c` with `a`, `b` and `c` set. Each call
+/// starts from a fresh environment. Variable names are single letters, as in expressions, and can't be
+/// built-in constants such as `e`. The expression, or the last of a chain, must give a single number.
+pub fn evaluate_with(expr: &str, vars: &HashMap<String, f64>, mode: Mode) -> Result<f64> {
+    let intrinsics = eval::intrinsic::standard_intrinsics();
+    let mut constants = vec![];
+    for (name, value) in vars {
+        let mut chars = name.chars();
+        let (Some(chr @ ('a'..='z' | 'A'..='Z')), None) = (chars.next(), chars.next()) else {
+            return Err(MathJitError::Parse {
+                message: format!("variable name '{name}' isn't a single letter"),
+                span: None,
+            }
+            .into());
+        };
+        if intrinsics.contains_key(&name[..]) {
+            return Err(MathJitError::Parse {
+                message: format!(
+                    "'{name}' is a built-in constant, and can't be used as a variable"
+                ),
+                span: None,
+            }
+            .into());
+        }
+        constants.push((chr, *value));
+    }
+
+    let options = EvalOptions::default();
+    match mode {
+        Mode::Interpret => {
+            evaluate_scalar(AstInterpreter::new(options).with_constants(constants), expr)
+        }
+        Mode::Jit => evaluate_scalar(Jit::new(options).with_constants(constants), expr),
+        Mode::Compare => evaluate_scalar(Compare::new(options).with_constants(constants), expr),
+    }
+}
+
+fn evaluate_scalar<T: Eval>(mut env: T, expr: &str) -> Result<f64> {
+    let mut last = None;
+    for response in evaluate_all(&mut env, expr)? {
+        if let Response::Value(value) = response? {
+            last = Some(value);
+        }
+    }
+    match last {
+        Some(Value::Scalar(x)) => Ok(x),
+        Some(value) => Err(MathJitError::runtime(format!(
+            "expected a single number, but the result is {value}"
+        ))),
+        None => Err(MathJitError::runtime(
+            "expected a single number, but nothing was evaluated",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        batch_definitions,
+        error::MathJitError,
+        eval::{ast_interpret::AstInterpreter, Eval, EvalOptions, Mode, Response, Value},
+        evaluate_all, evaluate_with,
+        parser::{ParseOutput, Parser},
+    };
+
+    fn vars(vars: &[(&str, f64)]) -> HashMap<String, f64> {
+        vars.iter().map(|(x, y)| (x.to_string(), *y)).collect()
+    }
+
+    #[test]
+    fn evaluate_with_supplied_variables() {
+        let vars = vars(&[("a", 2.0), ("b", 3.0), ("c", 4.0)]);
+        for mode in [Mode::Interpret, Mode::Jit, Mode::Compare] {
+            assert_eq!(evaluate_with("a*b + c", &vars, mode).unwrap(), 10.0);
+        }
+    }
+
+    #[test]
+    fn evaluate_with_rejects_bad_variable_names() {
+        for name in ["ab", "1", "e"] {
+            let err = evaluate_with("1", &vars(&[(name, 1.0)]), Mode::Interpret).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(MathJitError::Parse { .. })),
+                "{name}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn evaluate_all_yields_each_chained_result() {
+        let mut env = AstInterpreter::new(EvalOptions::default());
+        let results = evaluate_all(&mut env, "a = 1 & b = 2 & f(x) = x * 10 & f(a + b)")
+            .unwrap()
+            .map(|x| match x.unwrap() {
+                Response::Value(value) => Some(value),
+                Response::Ok { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                Some(Value::Scalar(1.0)),
+                Some(Value::Scalar(2.0)),
+                None,
+                Some(Value::Scalar(30.0))
+            ]
+        );
+        // Definitions carry over to later calls
+        let mut results = evaluate_all(&mut env, "f(b)").unwrap();
+        assert!(matches!(
+            results.next(),
+            Some(Ok(Response::Value(Value::Scalar(20.0))))
+        ));
+    }
+
+    #[test]
+    fn consecutive_definitions_are_batched() {
+        let ops = Parser::new("f(x) = x & g(x) = 2x & f(1) & h(x) = 3x & c = 2 & k(x) = x")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let batched = batch_definitions(ops)
+            .iter()
+            .map(|x| match x {
+                ParseOutput::Functions(funcs) => funcs.len(),
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(batched, [2, 0, 1, 0, 1]);
+    }
+}
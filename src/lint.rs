@@ -0,0 +1,149 @@
+use crate::{
+    parser::{self, ParseOutput},
+    tokenizer::MathToken,
+};
+
+/// Looks for constructs whose grouping is easy to misread, such as `2^3^2` or `-2^2`, and returns a
+/// note for each, showing how the expression is actually grouped. Brackets aren't kept in the parsed
+/// expression, so the tokens are checked instead. Input which doesn't parse gives no notes.
+pub fn lint(source: &str) -> Vec<String> {
+    let Ok(tokens) = MathToken::try_new(source.to_string()) else {
+        return vec![];
+    };
+
+    let mut notes = vec![];
+    let mut start = 0;
+    while start < tokens.len() {
+        let end = tokens[start..]
+            .iter()
+            .position(|x| matches!(x, MathToken::Chain(_)))
+            .map_or(tokens.len(), |x| start + x);
+        let segment = &tokens[start..end];
+        let text_end = tokens.get(end).map_or(source.len(), MathToken::position);
+        let text = source[segment[0].position()..text_end].trim();
+
+        let chained_exp = segment
+            .iter()
+            .enumerate()
+            .any(|(i, x)| matches!(x, MathToken::Exp(_)) && next_operator_is_exp(segment, i + 1));
+        let negated_exp = segment.iter().enumerate().any(|(i, x)| {
+            let unary = i == 0 || !ends_operand(&segment[i - 1]);
+            let exponent_sign = i > 0 && matches!(segment[i - 1], MathToken::Exp(_));
+            matches!(x, MathToken::Sub(_))
+                && unary
+                && !exponent_sign
+                && next_operator_is_exp(segment, i + 1)
+        });
+
+        if chained_exp || negated_exp {
+            if let Some(grouping) = grouping(text) {
+                if chained_exp {
+                    notes.push(format!(
+                        "'^' groups from the left, so {text} is read as {grouping}"
+                    ));
+                }
+                if negated_exp {
+                    notes.push(format!(
+                        "'-' applies after '^', so {text} is read as {grouping}"
+                    ));
+                }
+            }
+        }
+        start = end + 1;
+    }
+    notes
+}
+
+// Every operation parenthesized, as `:explain` shows it
+fn grouping(text: &str) -> Option<String> {
+    let outputs = parser::Parser::new(text).and_then(|mut x| x.parse()).ok()?;
+    let grouped = outputs
+        .iter()
+        .flat_map(|output| match output {
+            ParseOutput::Body(body) => vec![body.to_string()],
+            ParseOutput::Functions(funcs) => funcs.iter().map(ToString::to_string).collect(),
+            ParseOutput::Constant { name, value } => vec![format!("{name} = {value}")],
+        })
+        .collect::<Vec<_>>();
+    Some(grouped.join(" & "))
+}
+
+fn ends_operand(token: &MathToken) -> bool {
+    matches!(
+        token,
+        MathToken::Num(..)
+            | MathToken::Id(..)
+            | MathToken::Percent(_)
+            | MathToken::Close(_)
+            | MathToken::ListClose(_)
+            | MathToken::CasesClose(_)
+            | MathToken::FloorClose(_)
+            | MathToken::CeilClose(_)
+    )
+}
+
+// Whether the operand starting at `from` is followed by `^` at the same bracket depth, with no other
+// operator in between. The operand may carry a sign of its own
+fn next_operator_is_exp(tokens: &[MathToken], from: usize) -> bool {
+    let mut depth = 0;
+    for (n, token) in tokens.iter().skip(from).enumerate() {
+        match token {
+            MathToken::Open(_)
+            | MathToken::ListOpen(_)
+            | MathToken::CasesOpen(_)
+            | MathToken::FloorOpen(_)
+            | MathToken::CeilOpen(_) => depth += 1,
+            MathToken::Close(_)
+            | MathToken::ListClose(_)
+            | MathToken::CasesClose(_)
+            | MathToken::FloorClose(_)
+            | MathToken::CeilClose(_) => {
+                if depth == 0 {
+                    return false;
+                }
+                depth -= 1;
+            }
+            _ if depth > 0 => {}
+            MathToken::Exp(_) => return true,
+            MathToken::Sub(_) | MathToken::Add(_) if n == 0 => {}
+            MathToken::Num(..) | MathToken::Id(..) | MathToken::Percent(_) => {}
+            _ => return false,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lint;
+
+    #[test]
+    fn chained_exponents_show_their_grouping() {
+        assert_eq!(
+            lint("2^3^2"),
+            ["'^' groups from the left, so 2^3^2 is read as ((2 ^ 3) ^ 2)"]
+        );
+        assert_eq!(
+            lint("1 + 2^(3^2) & 2^x^y"),
+            ["'^' groups from the left, so 2^x^y is read as ((2 ^ x) ^ y)"]
+        );
+    }
+
+    #[test]
+    fn negation_before_an_exponent() {
+        assert_eq!(
+            lint("-2^2"),
+            ["'-' applies after '^', so -2^2 is read as (-(2 ^ 2))"]
+        );
+        // A sign on the exponent itself, or a bracketed base, is what it looks like
+        assert!(lint("2^-2").is_empty());
+        assert!(lint("(-2)^2").is_empty());
+        assert!(lint("1 - 2^2").is_empty());
+    }
+
+    #[test]
+    fn input_which_doesnt_parse_gives_no_notes() {
+        assert!(lint("2^3^").is_empty());
+        assert!(lint("2 $ 3").is_empty());
+    }
+}
@@ -1,19 +1,22 @@
-mod eval;
-mod ops;
-mod parser;
-mod timings;
-mod tokenizer;
-mod util;
-
 use anyhow::anyhow;
-use eval::Eval;
-use parser::ParseOutput;
-use rustyline::DefaultEditor;
-use std::{fmt::Display, str::FromStr};
-use timings::Timings;
+use mathjit::eval::{self, Eval, EvalOptions, Mode, Value};
+use mathjit::ops::MathOp;
+use mathjit::parser::{self, Function, ParseOutput};
+use mathjit::timings::{self, TimeUnit, Timings};
+use mathjit::tokenizer::MathToken;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use std::{
+    io::{IsTerminal, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use crate::eval::{ast_interpret::AstInterpreter, llvm::Jit};
 use clap::Parser;
+use comfy_table::Table;
+use mathjit::eval::{ast_interpret::AstInterpreter, compare::Compare, llvm::Jit};
 
 #[derive(clap::Parser, Debug)]
 #[command(
@@ -23,54 +26,137 @@ use clap::Parser;
 )]
 struct Args {
     math_expr: Option<String>,
+    /// Evaluate each line of a file in turn
+    #[clap(short, long, conflicts_with = "math_expr")]
+    file: Option<String>,
+    /// Check every line of a file parses and only calls defined functions, without evaluating it
+    #[clap(long, conflicts_with_all = ["math_expr", "file"])]
+    validate: Option<String>,
     #[clap(short, long, default_value_t = Mode::Interpret)]
     mode: Mode,
     #[clap(short, long)]
     verbose: bool,
+    /// Print each result after the expression it came from, such as 2 + 2 = 4
+    #[clap(long, conflicts_with = "json_result")]
+    annotate: bool,
     #[clap(short, long)]
     timings: bool,
+    /// Unit for timings: ns, us, ms, s, or auto to pick one per row
+    #[clap(long, default_value_t = TimeUnit::Millis)]
+    time_unit: TimeUnit,
+    /// Run JIT-compiled expressions this many times before the timed run
+    #[clap(long, default_value_t = 0)]
+    warmup: usize,
+    /// Reduce large sin/cos arguments with extra precision in the interpreter
+    #[clap(long)]
+    reduce_angles: bool,
+    /// Use compensated (Kahan) summation in sum
+    #[clap(long)]
+    kahan: bool,
+    /// Exclude the stop value from the range iterated by sum
+    #[clap(long)]
+    sum_exclusive: bool,
+    /// Round printed results to this many significant digits
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=17))]
+    round_display: Option<u8>,
+    /// Print whole number results in this base, such as 16 for 0xFF
+    #[clap(long, value_parser = clap::value_parser!(u32).range(2..=16), conflicts_with = "json_result")]
+    base: Option<u32>,
+    /// Print results which are close to a fraction with a small denominator as one, such as 1/3
+    #[clap(long, conflicts_with_all = ["json_result", "base"])]
+    rational: bool,
+    /// Comma separated LLVM passes to run instead of the default optimization pipeline
+    #[clap(long, value_delimiter = ',')]
+    passes: Option<Vec<String>>,
+    /// Comma separated built-in functions which can't be called, such as sqrt,sin
+    #[clap(long, value_delimiter = ',')]
+    disable: Vec<String>,
+    /// Skip LLVM optimization passes entirely
+    #[clap(long, conflicts_with = "passes")]
+    no_passes: bool,
+    /// Run LLVM optimization passes in the interactive REPL too, which skips them by default for quicker
+    /// feedback. Files and single expressions are always optimized, as are REPL expressions given --passes
+    /// or --check-opt
+    #[clap(long)]
+    repl_passes: bool,
+    /// REPL prompt, where {mode} is replaced with the evaluation mode
+    #[clap(long, default_value = "> ")]
+    prompt: String,
+    /// Don't warn about function parameters which are never used
+    #[clap(long)]
+    allow_unused: bool,
+    /// Evaluate the expression with every back-end, showing their timings side by side and checking that their
+    /// results agree
+    #[clap(long, requires = "math_expr", conflicts_with_all = ["json_result", "validate"])]
+    benchmark_all: bool,
+    /// Print the built-in functions and exit
+    #[clap(long)]
+    list_intrinsics: bool,
+    /// Don't mention when a definition replaces an existing function
+    #[clap(short, long)]
+    quiet: bool,
+    /// Let the interpreter run a sum over more than 100 million terms
+    #[clap(long)]
+    allow_huge_loops: bool,
+    /// Have the interpreter skip the other side of a multiplication by zero, even if it would be NaN or an error
+    #[clap(long)]
+    fast: bool,
+    /// Most iterations a looping function such as sum may run, in either back-end
+    #[clap(long)]
+    max_iterations: Option<u64>,
+    /// Have the interpreter report an error for any number or result which isn't a whole number
+    #[clap(long)]
+    decimals_only: bool,
+    /// Make the square root of a negative number an error instead of NaN
+    #[clap(long)]
+    strict: bool,
+    /// Fold arithmetic on number literals before evaluating, so the JIT's IR holds the folded constants
+    #[clap(long)]
+    fold: bool,
+    /// Target triple for the assembly shown in verbose mode, such as aarch64-unknown-linux-gnu
+    #[clap(long)]
+    target: Option<String>,
+    /// Give up on an expression which takes longer than this many milliseconds to evaluate
+    #[clap(long)]
+    timeout: Option<u64>,
+    /// Name each instruction in the JIT's verbose IR after the part of the expression it computes
+    #[clap(long)]
+    explain_ir: bool,
+    /// Warn when the JIT's optimization passes change the result of an expression
+    #[clap(long)]
+    check_opt: bool,
+    /// Relative difference beyond which results are reported as disagreeing, in compare mode and with --check-opt,
+    /// or are shown as a fraction with --rational
+    #[clap(long, default_value_t = eval::DEFAULT_EPSILON)]
+    epsilon: f64,
+    /// Write the parsed expression to this path as a Graphviz DOT graph
+    #[clap(long)]
+    emit_dot: Option<String>,
+    /// Write results to this file instead of stdout, errors are still printed to stderr
+    #[clap(short, long)]
+    output: Option<String>,
+    /// Define the constants in this file, one `name = value` per line, before evaluating anything
+    #[clap(long)]
+    constants: Option<String>,
+    /// Point out expressions whose grouping may be surprising, such as 2^3^2 or -2^2
+    #[clap(long)]
+    lint: bool,
+    /// Print each result, or error, to stdout as a JSON object such as {"ok": true, "value": 42.0}
+    #[clap(long)]
+    json_result: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Mode {
-    Interpret,
-    Jit,
-}
-
-impl Display for Mode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Mode::Interpret => "Interpreter",
-                Mode::Jit => "JIT",
-            }
-        )
-    }
-}
-
-impl FromStr for Mode {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "jit" | "j" | "JIT" => Ok(Mode::Jit),
-            "interpret" | "i" | "interpreter" | "Interpreter" => Ok(Mode::Interpret),
-            _ => Err(anyhow!("invalid selection, wanted 'jit' or 'interpret'")),
-        }
-    }
-}
-
-fn into_ops(math_expr: &str, verbose: bool) -> Option<(Vec<ParseOutput>, Timings)> {
+// With --json-result, errors are written to `json_output` as records, see `report_error`
+fn into_ops(
+    math_expr: &str,
+    verbose: bool,
+    json_output: Option<&mut dyn Write>,
+) -> Option<(Vec<ParseOutput>, Timings)> {
     let mut timings = Timings::start();
     let mut parser = match parser::Parser::new(math_expr) {
         Ok(x) => x,
         Err(e) => {
-            eprintln!("Tokenizer error:");
-            for cause in e.chain() {
-                eprintln!("{cause}");
-            }
+            report_error("Tokenizer", &e, json_output);
             return None;
         }
     };
@@ -85,10 +171,7 @@ fn into_ops(math_expr: &str, verbose: bool) -> Option<(Vec<ParseOutput>, Timings
     let ops = match parser.parse() {
         Ok(x) => x,
         Err(e) => {
-            eprintln!("Parser error:");
-            for cause in e.chain() {
-                eprintln!("{cause}");
-            }
+            report_error("Parser", &e, json_output);
             return None;
         }
     };
@@ -102,15 +185,43 @@ fn into_ops(math_expr: &str, verbose: bool) -> Option<(Vec<ParseOutput>, Timings
     Some((ops, timings))
 }
 
+// Variable holding an expression to evaluate when none is given on the command line
+const EXPR_VAR: &str = "MATHJIT_EXPR";
+
+// The expression to run given the value of the variable, if it is set. Input piped to stdin takes priority
+// over the variable, so it is only used when stdin is a terminal
+fn env_expr(stdin_is_terminal: bool, value: Option<String>) -> Option<String> {
+    value.filter(|x| stdin_is_terminal && !x.trim().is_empty())
+}
+
 enum ReplMode {
     Single(String),
+    File(String),
     Loop,
 }
 
 fn main() {
     let args = Args::parse();
-    let repl_mode = if let Some(expr) = &args.math_expr {
+    if args.list_intrinsics {
+        list_intrinsics();
+        return;
+    }
+    if let Some(path) = &args.validate {
+        std::process::exit(validate_file(path));
+    }
+
+    if let (true, Some(expr)) = (args.benchmark_all, &args.math_expr) {
+        std::process::exit(benchmark_all(&args, expr));
+    }
+
+    let repl_mode = if let Some(path) = &args.file {
+        ReplMode::File(path.to_string())
+    } else if let Some(expr) = &args.math_expr {
         ReplMode::Single(expr.to_string())
+    } else if let Some(expr) =
+        env_expr(std::io::stdin().is_terminal(), std::env::var(EXPR_VAR).ok())
+    {
+        ReplMode::Single(expr)
     } else {
         ReplMode::Loop
     };
@@ -122,34 +233,283 @@ fn main() {
         Mode::Jit => {
             start_repl_loop::<Jit>(&args, &repl_mode);
         }
+        Mode::Compare => {
+            start_repl_loop::<Compare>(&args, &repl_mode);
+        }
     }
 }
 
+fn validate_file(path: &str) -> i32 {
+    let source = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to read '{path}': {e}");
+            return 1;
+        }
+    };
+
+    let errors = mathjit::validate::validate(&source);
+    for e in &errors {
+        eprintln!("Validation error:");
+        for cause in e.chain() {
+            eprintln!("{cause}");
+        }
+    }
+
+    if errors.is_empty() {
+        println!("{path} is valid");
+        0
+    } else {
+        eprintln!("{} error(s) found in {path}", errors.len());
+        1
+    }
+}
+
+// Runs the whole expression through each back-end in turn, each with a fresh environment. Returns the exit
+// code, which is 1 if a back-end failed or the results disagree
+fn benchmark_all(args: &Args, expr: &str) -> i32 {
+    let mut ops = vec![];
+    for segment in MathToken::chain_segments(expr) {
+        let Some((segment_ops, _)) = into_ops(segment.trim(), args.verbose, None) else {
+            return 1;
+        };
+        ops.extend(fold_constants(segment_ops, args));
+    }
+
+    let runs = [
+        (
+            "Interpreter",
+            benchmark::<AstInterpreter>(args, ops.clone()),
+        ),
+        ("JIT", benchmark::<Jit>(args, ops)),
+    ];
+    let mut finished = vec![];
+    for (name, run) in &runs {
+        match run {
+            Ok((value, timings)) => finished.push((*name, value, timings)),
+            Err(e) => report_error(name, e, None),
+        }
+    }
+
+    let columns = finished
+        .iter()
+        .map(|(name, _, timings)| (*name, *timings))
+        .collect::<Vec<_>>();
+    println!("{}", timings::side_by_side(&columns, args.time_unit));
+
+    let Some((_, first, _)) = finished.first() else {
+        return 1;
+    };
+    let agree = finished.iter().all(|(_, value, _)| match (first, value) {
+        (Some(first), Some(value)) => !first.differs(value, args.epsilon),
+        (first, value) => first.is_none() && value.is_none(),
+    });
+    if agree {
+        match first {
+            Some(value) => println!("All back-ends gave {value}"),
+            None => println!("No back-end gave a result"),
+        }
+    } else {
+        eprintln!("Warning: the back-ends disagree");
+        for (name, value, _) in &finished {
+            match value {
+                Some(value) => eprintln!("  {name}: {value}"),
+                None => eprintln!("  {name}: no result"),
+            }
+        }
+    }
+    i32::from(!agree || finished.len() < runs.len())
+}
+
+// The last result and every lap of evaluating `ops`, as a chain, in a fresh environment
+fn benchmark<T: Eval>(
+    args: &Args,
+    ops: Vec<ParseOutput>,
+) -> anyhow::Result<(Option<Value>, Timings)> {
+    let mut env = T::new(eval_options(args, false, None));
+    if let Some(path) = &args.constants {
+        load_constants(&mut env, path)?;
+    }
+    let mut timings = Timings::start();
+    let mut last = None;
+    for result in mathjit::evaluate_chain(&mut env, ops) {
+        let (response, eval_timings) = result?;
+        timings.append(eval_timings, "Eval");
+        if let eval::Response::Value(value) = response {
+            last = Some(value);
+        }
+    }
+    Ok((last, timings))
+}
+
+fn list_intrinsics() {
+    let mut intrinsics = eval::intrinsic::standard_intrinsics()
+        .into_iter()
+        .collect::<Vec<_>>();
+    intrinsics.sort_by_key(|(name, _)| *name);
+
+    let mut table = Table::new();
+    table.set_header(vec!["Function", "Arguments", "Description"]);
+    for (name, func) in intrinsics {
+        let proto = func.proto();
+        table.add_row(vec![
+            name.to_string(),
+            proto.parameters(),
+            proto.description.to_string(),
+        ]);
+    }
+    println!("{table}");
+}
+
+fn eval_options(args: &Args, skip_passes: bool, interrupt: Option<Arc<AtomicBool>>) -> EvalOptions {
+    EvalOptions {
+        verbose: args.verbose,
+        warmup: args.warmup,
+        reduce_angles: args.reduce_angles,
+        kahan: args.kahan,
+        sum_exclusive: args.sum_exclusive,
+        passes: if args.no_passes || skip_passes {
+            Some(vec![])
+        } else {
+            args.passes.clone()
+        },
+        allow_huge_loops: args.allow_huge_loops,
+        fast: args.fast,
+        decimals_only: args.decimals_only,
+        strict: args.strict,
+        target: args.target.clone(),
+        timeout: args.timeout.map(Duration::from_millis),
+        explain_ir: args.explain_ir,
+        check_opt: args.check_opt,
+        epsilon: args.epsilon,
+        interrupt,
+        disabled: args.disable.clone(),
+        max_iterations: args.max_iterations,
+    }
+}
+
+fn skip_repl_passes(args: &Args, repl_mode: &ReplMode) -> bool {
+    matches!(repl_mode, ReplMode::Loop)
+        && !args.repl_passes
+        && args.passes.is_none()
+        && !args.check_opt
+}
+
 fn start_repl_loop<T: Eval>(args: &Args, repl_mode: &ReplMode) {
     if let ReplMode::Loop = repl_mode {
         println!("MathJIT ({} mode)", args.mode);
     }
 
     let mut rl = DefaultEditor::new().unwrap();
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => match std::fs::File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Failed to create '{path}': {e}");
+                std::process::exit(1);
+            }
+        },
+        None => Box::new(std::io::stdout()),
+    };
+
+    // While the prompt is showing, rustyline reads Ctrl-C itself. During an evaluation it arrives as a signal,
+    // which cancels the evaluation rather than exiting
+    let interrupt = matches!(repl_mode, ReplMode::Loop).then(|| {
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let flag = interrupt.clone();
+        if let Err(e) = ctrlc::set_handler(move || flag.store(true, Ordering::Relaxed)) {
+            eprintln!("Warning: Ctrl-C will exit rather than cancel an evaluation: {e}");
+        }
+        interrupt
+    });
 
-    let mut repl = T::new(args.verbose);
+    let mut last_expr = None;
+    let mut repl = T::new(eval_options(
+        args,
+        skip_repl_passes(args, repl_mode),
+        interrupt.clone(),
+    ));
+    if let Some(path) = &args.constants {
+        if let Err(e) = load_constants(&mut repl, path) {
+            report_error("Constants", &e, json_output(args, &mut output));
+            std::process::exit(1);
+        }
+    }
     loop {
         let input = match repl_mode {
             ReplMode::Single(ref inp) => inp.to_string(),
+            ReplMode::File(ref path) => {
+                if !run_file(&mut repl, path, args, &mut output) {
+                    let _ = output.flush();
+                    std::process::exit(1);
+                }
+                break;
+            }
             ReplMode::Loop => {
-                let readline = rl.readline("> ");
-                match readline {
-                    Ok(line) => {
-                        let _ = rl.add_history_entry(line.clone());
-                        line.to_string()
+                let mut prompt = repl_prompt(args);
+                let mut input = String::new();
+                loop {
+                    match rl.readline(&prompt) {
+                        Ok(line) => {
+                            if !input.is_empty() {
+                                input.push(' ');
+                            }
+                            input.push_str(&line);
+                        }
+                        // Ctrl-C discards what has been typed so far, including earlier lines of an incomplete
+                        // expression
+                        Err(ReadlineError::Interrupted) => {
+                            input.clear();
+                            break;
+                        }
+                        _ => {
+                            print_stats(&repl);
+                            std::process::exit(0)
+                        }
+                    }
+                    if !is_incomplete(&input) {
+                        break;
                     }
-                    _ => std::process::exit(0),
+                    prompt = "... ".to_string();
                 }
+                if !input.trim().is_empty() {
+                    let _ = rl.add_history_entry(input.clone());
+                }
+                input
             }
         };
 
-        if let Some(val) = run_repl_expr::<T>(&mut repl, input.trim(), args.timings, args.verbose) {
-            println!("{val}");
+        let input = input.trim();
+        if input.is_empty() {
+            // An accidental blank line is not worth an error
+        } else if let Some(command) = input.strip_prefix(':') {
+            run_repl_command(&mut repl, command, last_expr.as_deref());
+        } else {
+            if let Some(interrupt) = &interrupt {
+                interrupt.store(false, Ordering::Relaxed);
+            }
+            // Each chained expression is parsed and evaluated on its own, so an error in one doesn't discard the
+            // results of the others. Ctrl-C cancels the rest of the chain too
+            let mut parsed = vec![];
+            for segment in MathToken::chain_segments(input) {
+                if let Some((val, source)) =
+                    run_repl_expr::<T>(&mut repl, segment.trim(), args, &mut output, &mut parsed)
+                {
+                    let produced_by = repl.produced_by();
+                    print_value(&mut output, val, source.as_deref(), &produced_by, args);
+                }
+                if interrupt
+                    .as_ref()
+                    .is_some_and(|x| x.load(Ordering::Relaxed))
+                {
+                    break;
+                }
+            }
+            // The graph covers the whole input, not just its last chained expression
+            if let Some(path) = &args.emit_dot {
+                emit_dot(&parsed, path);
+            }
+            last_expr = Some(input.to_string());
         }
 
         if let ReplMode::Single(_) = repl_mode {
@@ -158,29 +518,594 @@ fn start_repl_loop<T: Eval>(args: &Args, repl_mode: &ReplMode) {
     }
 }
 
+fn repl_prompt(args: &Args) -> String {
+    args.prompt.replace("{mode}", &args.mode.to_string())
+}
+
+// Input carries on to the next line while a bracket or block comment is left open
+fn is_incomplete(input: &str) -> bool {
+    let open_comment = input
+        .rfind("/*")
+        .is_some_and(|start| !input[start + 2..].contains("*/"));
+    let depth = input.chars().fold(0i32, |depth, c| match c {
+        '(' | '[' | '{' | '⌊' | '⌈' => depth + 1,
+        ')' | ']' | '}' | '⌋' | '⌉' => depth - 1,
+        _ => depth,
+    });
+    open_comment || depth > 0
+}
+
+fn run_repl_command<T: Eval>(env: &mut T, command: &str, last_expr: Option<&str>) {
+    match command.trim() {
+        "explain" => {
+            let Some(last_expr) = last_expr else {
+                eprintln!("No expression to explain");
+                return;
+            };
+            let Some((ops, _)) = into_ops(last_expr, false, None) else {
+                return;
+            };
+            for op in ops {
+                match op {
+                    ParseOutput::Body(body) => println!("{body}"),
+                    ParseOutput::Constant { name, value } => println!("{name} = {value}"),
+                    ParseOutput::Functions(funcs) => {
+                        for func in funcs {
+                            println!("{func}");
+                        }
+                    }
+                }
+            }
+        }
+        "stats" => print_stats(env),
+        "show" => {
+            for func in env.functions() {
+                show_function(func);
+            }
+        }
+        command if command.starts_with("show ") => {
+            let name = command["show ".len()..].trim();
+            match env.functions().into_iter().find(|x| x.name == name) {
+                Some(func) => show_function(func),
+                None => eprintln!("No function named '{name}' is defined"),
+            }
+        }
+        "reset" => {
+            env.reset();
+            println!("Environment reset");
+        }
+        _ => eprintln!("Unknown command ':{}'", command.trim()),
+    }
+}
+
+fn show_function(func: &Function) {
+    let plural = if func.args.len() == 1 { "" } else { "s" };
+    println!("{func}  ({} parameter{plural})", func.args.len());
+}
+
+fn print_stats<T: Eval>(env: &T) {
+    let mut table = Table::new();
+    table.set_header(vec!["Statistic", "Value"]);
+    for (label, value) in env.stats() {
+        table.add_row(vec![label.to_string(), value]);
+    }
+    println!("{table}");
+}
+
+// Each line must define constants and nothing else, so that a misplaced expression isn't silently evaluated
+fn load_constants<T: Eval>(env: &mut T, path: &str) -> anyhow::Result<()> {
+    let source =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("failed to read '{path}': {e}"))?;
+    for (line_number, line) in MathToken::source_lines(&source) {
+        let in_line = || format!("on line {line_number} of {path}");
+        let ops = parser::Parser::new(&line)
+            .and_then(|mut x| x.parse())
+            .map_err(|e| e.context(in_line()))?;
+        for op in ops {
+            if !matches!(op, ParseOutput::Constant { .. }) {
+                return Err(anyhow!("expected a constant such as 'g = 9.81'").context(in_line()));
+            }
+            env.eval(op).map_err(|e| e.context(in_line()))?;
+        }
+    }
+    Ok(())
+}
+
+// Returns whether every line was parsed and evaluated without an error
+fn run_file<T: Eval>(env: &mut T, path: &str, args: &Args, output: &mut dyn Write) -> bool {
+    let source = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to read '{path}': {e}");
+            return false;
+        }
+    };
+
+    let mut full_timings = Timings::start();
+    let mut ops = vec![];
+    let mut failed = false;
+    for (line_number, line) in MathToken::source_lines(&source) {
+        // A chained expression which doesn't parse is reported and left out, the rest of the line still runs
+        for segment in MathToken::chain_segments(&line) {
+            let segment = segment.trim();
+            let Some((line_ops, timings)) =
+                into_ops(segment, args.verbose, json_output(args, output))
+            else {
+                failed = true;
+                continue;
+            };
+            print_lint_notes(segment, args);
+            full_timings.append(timings, "Init");
+            // Results are shown with the expression as written, before it's folded
+            let sources = line_ops.iter().map(source_of).collect::<Vec<_>>();
+            let line_ops = fold_constants(line_ops, args);
+            warn_unused_args(&line_ops, args);
+            for (op, source) in line_ops.into_iter().zip(sources) {
+                ops.push((line_number, source, op));
+            }
+        }
+    }
+
+    if let Some(path) = &args.emit_dot {
+        emit_dot(ops.iter().map(|(_, _, op)| op), path);
+    }
+
+    // Consecutive definitions are evaluated together, as `mathjit::batch_definitions` does, and reported
+    // against the line of the first
+    let mut batched: Vec<(usize, Option<String>, ParseOutput)> = vec![];
+    for (line_number, source, op) in ops {
+        if let ParseOutput::Functions(funcs) = &op {
+            if let Some((_, _, ParseOutput::Functions(batch))) = batched.last_mut() {
+                batch.extend(funcs.iter().cloned());
+                continue;
+            }
+        }
+        batched.push((line_number, source, op));
+    }
+
+    // An expression which fails is reported and the rest of the file still runs
+    for (line_number, source, op) in batched {
+        let (value, timings) = match env.eval(op) {
+            Ok(x) => x,
+            Err(e) => {
+                let e = e.context(format!("on line {line_number}"));
+                report_error("Evaluation", &e, json_output(args, output));
+                failed = true;
+                continue;
+            }
+        };
+        full_timings.append(timings, "Eval");
+        match value {
+            eval::Response::Value(value) => {
+                let produced_by = env.produced_by();
+                print_value(output, value, source.as_deref(), &produced_by, args);
+            }
+            // Definitions get a record too, as they do outside of file mode
+            eval::Response::Ok { .. } if args.json_result => write_output(output, JSON_NO_VALUE),
+            eval::Response::Ok { .. } => {}
+        }
+    }
+
+    if args.timings {
+        println!("{}", full_timings.report(args.time_unit));
+    }
+    !failed
+}
+
+fn fold_constants(ops: Vec<ParseOutput>, args: &Args) -> Vec<ParseOutput> {
+    if !args.fold {
+        return ops;
+    }
+    let ops = ops
+        .into_iter()
+        .map(|op| match op {
+            ParseOutput::Body(body) => ParseOutput::Body(body.fold_constants()),
+            ParseOutput::Constant { name, value } => ParseOutput::Constant {
+                name,
+                value: value.fold_constants(),
+            },
+            ParseOutput::Functions(funcs) => ParseOutput::Functions(
+                funcs
+                    .into_iter()
+                    .map(|func| Function {
+                        body: func.body.fold_constants(),
+                        ..func
+                    })
+                    .collect(),
+            ),
+        })
+        .collect::<Vec<_>>();
+    if args.verbose {
+        println!("--- Folded AST --");
+        println!("{ops:?}");
+    }
+    ops
+}
+
+// Each function definition gets a node for its signature, pointing at its body
+fn emit_dot<'a>(ops: impl IntoIterator<Item = &'a ParseOutput>, path: &str) {
+    let mut dot = "digraph ast {\n".to_string();
+    let mut next_id = 0;
+    for op in ops {
+        match op {
+            ParseOutput::Body(body) => {
+                body.write_dot(&mut dot, &mut next_id);
+            }
+            ParseOutput::Constant { name, value } => {
+                let id = next_id;
+                next_id += 1;
+                dot.push_str(&format!("    n{id} [label=\"{name} =\"];\n"));
+                let value = value.write_dot(&mut dot, &mut next_id);
+                dot.push_str(&format!("    n{id} -> n{value};\n"));
+            }
+            ParseOutput::Functions(funcs) => {
+                for func in funcs {
+                    let id = next_id;
+                    next_id += 1;
+                    dot.push_str(&format!("    n{id} [label=\"{} =\"];\n", func.signature()));
+                    let body = func.body.write_dot(&mut dot, &mut next_id);
+                    dot.push_str(&format!("    n{id} -> n{body};\n"));
+                }
+            }
+        }
+    }
+    dot.push_str("}\n");
+
+    if let Err(e) = std::fs::write(path, dot) {
+        eprintln!("Failed to write '{path}': {e}");
+    }
+}
+
+fn print_lint_notes(math_expr: &str, args: &Args) {
+    if !args.lint {
+        return;
+    }
+    for note in mathjit::lint::lint(math_expr) {
+        eprintln!("Note: {note}");
+    }
+}
+
+fn warn_unused_args(ops: &[ParseOutput], args: &Args) {
+    if args.allow_unused {
+        return;
+    }
+    for op in ops {
+        let ParseOutput::Functions(funcs) = op else {
+            continue;
+        };
+        for func in funcs {
+            for arg in func.unused_args() {
+                eprintln!("Warning: parameter '{arg}' of {} is never used", func.name);
+            }
+        }
+    }
+}
+
+// `stage` names where the error came from, such as "Parser". Given `json_output`, the error is written there
+// as a record alongside the results, rather than to stderr
+fn report_error(stage: &str, e: &anyhow::Error, json_output: Option<&mut dyn Write>) {
+    if let Some(output) = json_output {
+        let record = format!(
+            "{{\"ok\": false, \"error\": {}}}",
+            json_string(&format!("{e:#}"))
+        );
+        write_output(output, &record);
+        return;
+    }
+    eprintln!("{stage} error:");
+    for cause in e.chain() {
+        eprintln!("{cause}");
+    }
+}
+
+// The record for a definition, which has no result
+const JSON_NO_VALUE: &str = "{\"ok\": true, \"value\": null}";
+
+// Where errors go as JSON records, with --json-result
+fn json_output<'a>(args: &Args, output: &'a mut dyn Write) -> Option<&'a mut dyn Write> {
+    args.json_result.then_some(output)
+}
+
+// JSON has no infinity or NaN, so those become null
+fn json_value(value: &Value) -> String {
+    let number = |x: &f64| {
+        if x.is_finite() {
+            format!("{x:?}")
+        } else {
+            "null".to_string()
+        }
+    };
+    match value {
+        Value::Scalar(x) => number(x),
+        Value::List(xs) | Value::Tuple(xs) => {
+            format!("[{}]", xs.iter().map(number).collect::<Vec<_>>().join(", "))
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// `source` is shown before the result with --annotate, or in verbose mode
+// `produced_by` labels the value in verbose mode, see `Eval::produced_by`
+fn print_value(
+    output: &mut dyn Write,
+    value: Value,
+    source: Option<&str>,
+    produced_by: &[(&str, Option<Value>)],
+    args: &Args,
+) {
+    // Compiled code can't stop at the offending sqrt, so the JIT can only point out the result
+    if args.strict
+        && matches!(args.mode, Mode::Jit)
+        && matches!(value, Value::Scalar(x) if x.is_nan())
+    {
+        eprintln!(
+            "Warning: result is NaN, which may come from the square root of a negative number"
+        );
+    }
+    let value = rounded(value, args);
+    let shown = show_value(&value, args);
+    let shown = match source {
+        Some(source) if args.annotate || args.verbose => format!("{source} = {shown}"),
+        _ => shown,
+    };
+    let line = if args.json_result {
+        format!("{{\"ok\": true, \"value\": {}}}", json_value(&value))
+    } else if args.verbose {
+        // Back-ends which agree share the label, one which doesn't is shown with its own result
+        let agreed = produced_by
+            .iter()
+            .filter(|(_, differing)| differing.is_none())
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        let differing = produced_by
+            .iter()
+            .filter_map(|(name, differing)| {
+                let value = rounded(differing.clone()?, args);
+                Some(format!(", [{name}] {}", show_value(&value, args)))
+            })
+            .collect::<String>();
+        format!("[{}] {shown}{differing}", agreed.join(", "))
+    } else {
+        shown
+    };
+    write_output(output, &line);
+}
+
+// Results go to the --output file, or stdout without one
+fn write_output(output: &mut dyn Write, line: &str) {
+    if let Err(e) = writeln!(output, "{line}") {
+        eprintln!("Failed to write result: {e}");
+    }
+}
+
+// Rounding happens only when printing, so results kept for later use are unaffected
+fn rounded(value: Value, args: &Args) -> Value {
+    match args.round_display {
+        Some(digits) => value.map(|x| {
+            format!("{x:.*e}", usize::from(digits - 1))
+                .parse()
+                .unwrap_or(x)
+        }),
+        None => value,
+    }
+}
+
+fn show_value(value: &Value, args: &Args) -> String {
+    match (args.base, value) {
+        (Some(base), Value::Scalar(x)) => match in_base(*x, base) {
+            Some(x) => x,
+            None => {
+                eprintln!("Warning: {x} isn't a whole number, so it is shown in decimal");
+                value.to_string()
+            }
+        },
+        (Some(base), _) => {
+            eprintln!("Warning: only a single number can be shown in base {base}");
+            value.to_string()
+        }
+        (None, _) if args.rational => value.display_with(|x| {
+            as_fraction(x, args.epsilon).map_or_else(|| x.to_string(), |(p, q)| format!("{p}/{q}"))
+        }),
+        (None, _) => value.to_string(),
+    }
+}
+
+// Bases 2, 8 and 16 get the prefix their literals are written with, other bases are written without one
+fn in_base(x: f64, base: u32) -> Option<String> {
+    if x.fract() != 0.0 || !x.is_finite() || x.abs() >= 2f64.powi(127) {
+        return None;
+    }
+    let prefix = match base {
+        2 => "0b",
+        8 => "0o",
+        16 => "0x",
+        _ => "",
+    };
+    let mut magnitude = x.abs() as u128;
+    let mut digits = vec![];
+    loop {
+        let digit = u32::try_from(magnitude % u128::from(base)).unwrap();
+        digits.push(char::from_digit(digit, base).unwrap().to_ascii_uppercase());
+        magnitude /= u128::from(base);
+        if magnitude == 0 {
+            break;
+        }
+    }
+    let sign = if x < 0.0 { "-" } else { "" };
+    Some(format!(
+        "{sign}{prefix}{}",
+        digits.iter().rev().collect::<String>()
+    ))
+}
+
+// Largest denominator --rational will show, so that results like pi aren't written as 103993/33102
+const MAX_RATIONAL_DENOMINATOR: f64 = 10_000.0;
+
+// The first continued fraction convergent of `x` within `epsilon` (relatively), if its denominator is small
+// enough. Whole numbers are left alone, so they aren't written as `n/1`
+fn as_fraction(x: f64, epsilon: f64) -> Option<(f64, f64)> {
+    if !x.is_finite() || x.fract() == 0.0 {
+        return None;
+    }
+    let target = x.abs();
+    let (mut numerator, mut prev_numerator) = (1.0, 0.0);
+    let (mut denominator, mut prev_denominator) = (0.0, 1.0);
+    let mut remainder = target;
+    loop {
+        let term = remainder.floor();
+        (numerator, prev_numerator) = (term * numerator + prev_numerator, numerator);
+        (denominator, prev_denominator) = (term * denominator + prev_denominator, denominator);
+        // Past 2^53 the convergents can't be represented exactly
+        if denominator > MAX_RATIONAL_DENOMINATOR || numerator > 2f64.powi(53) {
+            return None;
+        }
+        if (target - numerator / denominator).abs() <= epsilon * target {
+            return Some((numerator.copysign(x), denominator));
+        }
+        let fract = remainder - term;
+        if fract == 0.0 {
+            return None;
+        }
+        remainder = 1.0 / fract;
+    }
+}
+
+// Gives the last result along with the expression it came from, as `source_of` shows it. The parsed
+// expression is added to `parsed` when it is wanted for `--emit-dot`
 fn run_repl_expr<T: Eval>(
     env: &mut T,
     math_expr: &str,
-    do_timings: bool,
-    verbose: bool,
-) -> Option<f64> {
+    args: &Args,
+    output: &mut dyn Write,
+    parsed: &mut Vec<ParseOutput>,
+) -> Option<(Value, Option<String>)> {
     let mut full_timings = Timings::start();
-    let (ops, timings) = into_ops(math_expr, verbose)?;
+    let (ops, timings) = into_ops(math_expr, args.verbose, json_output(args, output))?;
+    print_lint_notes(math_expr, args);
+    let mut sources = ops
+        .iter()
+        .filter_map(source_of)
+        .collect::<Vec<_>>()
+        .into_iter();
+    let ops = fold_constants(ops, args);
+    warn_unused_args(&ops, args);
+    if args.emit_dot.is_some() {
+        parsed.extend(ops.iter().cloned());
+    }
     let mut last_response = None;
     full_timings.append(timings, "Init");
-    for op in ops {
-        let (value, timings) = env.eval(op).unwrap();
+    for result in mathjit::evaluate_chain(env, ops) {
+        let (value, timings) = match result {
+            Ok(x) => x,
+            Err(e) => {
+                report_error("Evaluation", &e, json_output(args, output));
+                return None;
+            }
+        };
         full_timings.append(timings, "Eval");
-        if do_timings {
-            println!("{}", full_timings.report());
+        if args.timings {
+            println!("{}", full_timings.report(args.time_unit));
         }
         last_response = match value {
-            eval::Response::Ok => {
-                println!("Ok");
+            eval::Response::Ok { .. } if args.json_result => {
+                write_output(output, JSON_NO_VALUE);
+                None
+            }
+            eval::Response::Ok { redefined } => {
+                if !args.quiet {
+                    for signature in redefined {
+                        write_output(output, &format!("redefined {signature}"));
+                    }
+                }
+                write_output(output, "Ok");
                 None
             }
-            eval::Response::Value(value) => Some(value),
+            eval::Response::Value(value) => Some((value, sources.next())),
         }
     }
     last_response
 }
+
+// The expression a result came from, taken before constant folding, so `2+2` is shown as `2 + 2` rather
+// than `4`. The outermost brackets of an operation are left off. Definitions have no result, and a
+// constant's result is its value, so it is shown by name
+fn source_of(op: &ParseOutput) -> Option<String> {
+    match op {
+        ParseOutput::Body(body) => {
+            let shown = body.to_string();
+            Some(match body {
+                MathOp::Add { .. }
+                | MathOp::Sub { .. }
+                | MathOp::Mul { .. }
+                | MathOp::Div { .. }
+                | MathOp::Exp { .. }
+                | MathOp::Cmp { .. } => shown[1..shown.len() - 1].to_string(),
+                _ => shown,
+            })
+        }
+        ParseOutput::Constant { name, .. } => Some(name.to_string()),
+        ParseOutput::Functions(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // rustyline doesn't show the prompt when stdin isn't a terminal, so it is checked here rather than through
+    // the REPL
+    #[test]
+    fn custom_prompts() {
+        let prompt = |args: &[&str]| repl_prompt(&Args::parse_from([&["mathjit"], args].concat()));
+        assert_eq!(prompt(&[]), "> ");
+        assert_eq!(prompt(&["--prompt", "$ "]), "$ ");
+        assert_eq!(prompt(&["--prompt", "{mode}> ", "-m", "jit"]), "JIT> ");
+    }
+
+    #[test]
+    fn open_brackets_and_comments_continue_onto_the_next_line() {
+        assert!(is_incomplete("(1 +"));
+        assert!(is_incomplete("f(x) = { x if x > 0,"));
+        assert!(is_incomplete("1 + /* a"));
+        assert!(!is_incomplete("(1 + 2)"));
+        assert!(!is_incomplete("1 /* a */ + 2"));
+    }
+
+    #[test]
+    fn only_the_interactive_repl_skips_passes_by_default() {
+        let skips = |args: &[&str], repl_mode| {
+            skip_repl_passes(&Args::parse_from([&["mathjit"], args].concat()), &repl_mode)
+        };
+        assert!(skips(&[], ReplMode::Loop));
+        assert!(!skips(&[], ReplMode::Single("1 + 1".to_string())));
+        assert!(!skips(&[], ReplMode::File("input.txt".to_string())));
+        assert!(!skips(&["--repl-passes"], ReplMode::Loop));
+        assert!(!skips(&["--passes", "gvn"], ReplMode::Loop));
+        assert!(!skips(&["--check-opt"], ReplMode::Loop));
+    }
+
+    // Only this test sets the variable, so it can't affect any other
+    #[test]
+    fn expression_from_the_environment() {
+        let set = |value: &str| Some(value.to_string());
+        assert_eq!(env_expr(true, set("2 + 2")).as_deref(), Some("2 + 2"));
+        // Piped input is read instead
+        assert_eq!(env_expr(false, set("2 + 2")), None);
+        assert_eq!(env_expr(true, set("  ")), None);
+        assert_eq!(env_expr(true, None), None);
+    }
+}
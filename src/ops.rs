@@ -1,12 +1,569 @@
+use std::{collections::BTreeSet, fmt::Display, sync::Arc};
+
+use crate::util;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CmpOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl CmpOp {
+    pub fn apply(self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Le => lhs <= rhs,
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Ge => lhs >= rhs,
+            CmpOp::Eq => lhs == rhs,
+            CmpOp::Ne => lhs != rhs,
+        }
+    }
+}
+
+impl Display for CmpOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                CmpOp::Lt => "<",
+                CmpOp::Le => "<=",
+                CmpOp::Gt => ">",
+                CmpOp::Ge => ">=",
+                CmpOp::Eq => "==",
+                CmpOp::Ne => "!=",
+            }
+        )
+    }
+}
+
+// The region of the source text an operation was parsed from, kept so evaluation errors can point at it
+#[derive(Clone)]
+pub struct Span {
+    pub source: Arc<str>,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn error_message(&self) -> String {
+        util::error_message(&self.source, self.start, self.end)
+    }
+}
+
+impl std::fmt::Debug for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}..={}", self.start, self.end)
+    }
+}
+
+// `MathOp` without spans, and with numbers compared by their bits, so expressions can be ordered and
+// compared structurally. Binary operations are told apart by their operator
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum OpKey {
+    Binary(char, Box<OpKey>, Box<OpKey>),
+    Cmp(CmpOp, Box<OpKey>, Box<OpKey>),
+    Piecewise(Vec<(Option<OpKey>, OpKey)>),
+    Call(String, Vec<OpKey>),
+    List(Vec<OpKey>),
+    Tuple(Vec<OpKey>),
+    Lambda(char, Box<OpKey>),
+    Neg(Box<OpKey>),
+    Arg(char),
+    Num(u64),
+}
+
 #[derive(Debug, Clone)]
 pub enum MathOp {
-    Add { lhs: Box<MathOp>, rhs: Box<MathOp> },
-    Sub { lhs: Box<MathOp>, rhs: Box<MathOp> },
-    Mul { lhs: Box<MathOp>, rhs: Box<MathOp> },
-    Div { lhs: Box<MathOp>, rhs: Box<MathOp> },
-    Exp { lhs: Box<MathOp>, rhs: Box<MathOp> },
-    Call { name: String, args: Vec<MathOp> },
+    Add {
+        lhs: Box<MathOp>,
+        rhs: Box<MathOp>,
+    },
+    Sub {
+        lhs: Box<MathOp>,
+        rhs: Box<MathOp>,
+    },
+    Mul {
+        lhs: Box<MathOp>,
+        rhs: Box<MathOp>,
+    },
+    Div {
+        lhs: Box<MathOp>,
+        rhs: Box<MathOp>,
+    },
+    Exp {
+        lhs: Box<MathOp>,
+        rhs: Box<MathOp>,
+    },
+    Cmp {
+        op: CmpOp,
+        lhs: Box<MathOp>,
+        rhs: Box<MathOp>,
+    },
+    // Cases are tried in order, a case without a condition is the `otherwise` fallthrough
+    Piecewise(Vec<(Option<MathOp>, MathOp)>),
+    Call {
+        name: String,
+        args: Vec<MathOp>,
+        span: Span,
+    },
+    List(Vec<MathOp>),
+    // Several values returned together, such as both roots of a quadratic
+    Tuple(Vec<MathOp>),
+    // An inline function of one argument, `x -> x^2`, only seen as the first argument of an intrinsic
+    Lambda {
+        arg: char,
+        body: Box<MathOp>,
+    },
     Neg(Box<MathOp>),
-    Arg(char),
+    Arg(char, Span),
     Num(f64),
 }
+
+impl MathOp {
+    pub fn children(&self) -> Vec<&MathOp> {
+        match self {
+            MathOp::Add { lhs, rhs }
+            | MathOp::Sub { lhs, rhs }
+            | MathOp::Mul { lhs, rhs }
+            | MathOp::Div { lhs, rhs }
+            | MathOp::Exp { lhs, rhs }
+            | MathOp::Cmp { lhs, rhs, .. } => vec![lhs, rhs],
+            MathOp::Piecewise(cases) => cases
+                .iter()
+                .flat_map(|(cond, value)| cond.iter().chain([value]))
+                .collect(),
+            MathOp::Call { args, .. } | MathOp::List(args) | MathOp::Tuple(args) => {
+                args.iter().collect()
+            }
+            MathOp::Neg(x) | MathOp::Lambda { body: x, .. } => vec![x],
+            MathOp::Arg(..) | MathOp::Num(_) => vec![],
+        }
+    }
+
+    pub fn contains_list(&self) -> bool {
+        matches!(self, MathOp::List(_)) || self.children().into_iter().any(MathOp::contains_list)
+    }
+
+    pub fn contains_tuple(&self) -> bool {
+        matches!(self, MathOp::Tuple(_)) || self.children().into_iter().any(MathOp::contains_tuple)
+    }
+
+    // Measured with an explicit stack, as this is used to reject trees too deep to recurse over safely
+    pub fn depth(&self) -> usize {
+        let mut deepest = 0;
+        let mut pending = vec![(self, 1)];
+        while let Some((op, depth)) = pending.pop() {
+            deepest = deepest.max(depth);
+            pending.extend(op.children().into_iter().map(|x| (x, depth + 1)));
+        }
+        deepest
+    }
+
+    // The first variable which isn't one of `params`, as it can't be given a value when evaluated
+    pub fn unbound_variable(&self, params: &[char]) -> Option<(char, &Span)> {
+        match self {
+            MathOp::Arg(name, span) if !params.contains(name) => Some((*name, span)),
+            // Lambdas don't capture, their body can only use their own argument
+            MathOp::Lambda { arg, body } => body.unbound_variable(&[*arg]),
+            _ => self
+                .children()
+                .into_iter()
+                .find_map(|x| x.unbound_variable(params)),
+        }
+    }
+
+    // Rebuilds this operation with `op` applied to each of its direct children
+    fn map_children(self, op: &impl Fn(MathOp) -> MathOp) -> MathOp {
+        let binary = |lhs: Box<MathOp>, rhs: Box<MathOp>| (Box::new(op(*lhs)), Box::new(op(*rhs)));
+        let each = |ops: Vec<MathOp>| ops.into_iter().map(op).collect();
+        match self {
+            MathOp::Add { lhs, rhs } => {
+                let (lhs, rhs) = binary(lhs, rhs);
+                MathOp::Add { lhs, rhs }
+            }
+            MathOp::Sub { lhs, rhs } => {
+                let (lhs, rhs) = binary(lhs, rhs);
+                MathOp::Sub { lhs, rhs }
+            }
+            MathOp::Mul { lhs, rhs } => {
+                let (lhs, rhs) = binary(lhs, rhs);
+                MathOp::Mul { lhs, rhs }
+            }
+            MathOp::Div { lhs, rhs } => {
+                let (lhs, rhs) = binary(lhs, rhs);
+                MathOp::Div { lhs, rhs }
+            }
+            MathOp::Exp { lhs, rhs } => {
+                let (lhs, rhs) = binary(lhs, rhs);
+                MathOp::Exp { lhs, rhs }
+            }
+            MathOp::Cmp { op: cmp, lhs, rhs } => {
+                let (lhs, rhs) = binary(lhs, rhs);
+                MathOp::Cmp { op: cmp, lhs, rhs }
+            }
+            MathOp::Piecewise(cases) => MathOp::Piecewise(
+                cases
+                    .into_iter()
+                    .map(|(cond, value)| (cond.map(op), op(value)))
+                    .collect(),
+            ),
+            MathOp::Call { name, args, span } => MathOp::Call {
+                name,
+                args: each(args),
+                span,
+            },
+            MathOp::List(items) => MathOp::List(each(items)),
+            MathOp::Tuple(items) => MathOp::Tuple(each(items)),
+            MathOp::Lambda { arg, body } => MathOp::Lambda {
+                arg,
+                body: Box::new(op(*body)),
+            },
+            MathOp::Neg(x) => MathOp::Neg(Box::new(op(*x))),
+            MathOp::Arg(..) | MathOp::Num(_) => self,
+        }
+    }
+
+    /// Rewrites the expression so that equivalent forms compare equal, by sorting the operands of
+    /// `+` and `*` into a fixed order, so `a+b` and `b+a` canonicalize identically. Only the two
+    /// operands of a single operation are swapped, nothing is reassociated, so results are unchanged.
+    pub fn canonicalize(self) -> MathOp {
+        self.canonical().0
+    }
+
+    /// Whether the two expressions are the same once canonicalized, such as `a+b` and `b+a`. Where
+    /// they appear in the input is ignored.
+    pub fn equivalent(&self, other: &MathOp) -> bool {
+        self.clone().canonical().1 == other.clone().canonical().1
+    }
+
+    // The canonical form along with its key, which is built on the way up so operands can be ordered without
+    // walking them again
+    fn canonical(self) -> (MathOp, OpKey) {
+        let pair = |lhs: Box<MathOp>, rhs: Box<MathOp>| {
+            let (lhs, lhs_key) = lhs.canonical();
+            let (rhs, rhs_key) = rhs.canonical();
+            (
+                (Box::new(lhs), Box::new(lhs_key)),
+                (Box::new(rhs), Box::new(rhs_key)),
+            )
+        };
+        let sorted = |lhs, rhs| match pair(lhs, rhs) {
+            (lhs, rhs) if lhs.1 <= rhs.1 => (lhs, rhs),
+            (lhs, rhs) => (rhs, lhs),
+        };
+        let each = |ops: Vec<MathOp>| -> (Vec<MathOp>, Vec<OpKey>) {
+            ops.into_iter().map(MathOp::canonical).unzip()
+        };
+        match self {
+            MathOp::Add { lhs, rhs } => {
+                let ((lhs, lhs_key), (rhs, rhs_key)) = sorted(lhs, rhs);
+                (
+                    MathOp::Add { lhs, rhs },
+                    OpKey::Binary('+', lhs_key, rhs_key),
+                )
+            }
+            MathOp::Mul { lhs, rhs } => {
+                let ((lhs, lhs_key), (rhs, rhs_key)) = sorted(lhs, rhs);
+                (
+                    MathOp::Mul { lhs, rhs },
+                    OpKey::Binary('*', lhs_key, rhs_key),
+                )
+            }
+            MathOp::Sub { lhs, rhs } => {
+                let ((lhs, lhs_key), (rhs, rhs_key)) = pair(lhs, rhs);
+                (
+                    MathOp::Sub { lhs, rhs },
+                    OpKey::Binary('-', lhs_key, rhs_key),
+                )
+            }
+            MathOp::Div { lhs, rhs } => {
+                let ((lhs, lhs_key), (rhs, rhs_key)) = pair(lhs, rhs);
+                (
+                    MathOp::Div { lhs, rhs },
+                    OpKey::Binary('/', lhs_key, rhs_key),
+                )
+            }
+            MathOp::Exp { lhs, rhs } => {
+                let ((lhs, lhs_key), (rhs, rhs_key)) = pair(lhs, rhs);
+                (
+                    MathOp::Exp { lhs, rhs },
+                    OpKey::Binary('^', lhs_key, rhs_key),
+                )
+            }
+            MathOp::Cmp { op, lhs, rhs } => {
+                let ((lhs, lhs_key), (rhs, rhs_key)) = pair(lhs, rhs);
+                (
+                    MathOp::Cmp { op, lhs, rhs },
+                    OpKey::Cmp(op, lhs_key, rhs_key),
+                )
+            }
+            MathOp::Piecewise(cases) => {
+                let (cases, keys) = cases
+                    .into_iter()
+                    .map(|(cond, value)| {
+                        let (cond, cond_key) = cond.map(MathOp::canonical).unzip();
+                        let (value, value_key) = value.canonical();
+                        ((cond, value), (cond_key, value_key))
+                    })
+                    .unzip();
+                (MathOp::Piecewise(cases), OpKey::Piecewise(keys))
+            }
+            MathOp::Call { name, args, span } => {
+                let (args, keys) = each(args);
+                let key = OpKey::Call(name.clone(), keys);
+                (MathOp::Call { name, args, span }, key)
+            }
+            MathOp::List(items) => {
+                let (items, keys) = each(items);
+                (MathOp::List(items), OpKey::List(keys))
+            }
+            MathOp::Tuple(items) => {
+                let (items, keys) = each(items);
+                (MathOp::Tuple(items), OpKey::Tuple(keys))
+            }
+            MathOp::Lambda { arg, body } => {
+                let (body, key) = body.canonical();
+                let body = Box::new(body);
+                (
+                    MathOp::Lambda { arg, body },
+                    OpKey::Lambda(arg, Box::new(key)),
+                )
+            }
+            MathOp::Neg(x) => {
+                let (x, key) = x.canonical();
+                (MathOp::Neg(Box::new(x)), OpKey::Neg(Box::new(key)))
+            }
+            MathOp::Arg(name, span) => (MathOp::Arg(name, span), OpKey::Arg(name)),
+            MathOp::Num(x) => (MathOp::Num(x), OpKey::Num(x.to_bits())),
+        }
+    }
+
+    /// Evaluates operations whose operands are all number literals, so `x * (2 + 3)` becomes `x * 5`.
+    /// The same floating point operations are performed, so results are unchanged.
+    pub fn fold_constants(self) -> MathOp {
+        let folded = self.map_children(&MathOp::fold_constants);
+        let num = |op: &MathOp| match op {
+            MathOp::Num(x) => Some(*x),
+            _ => None,
+        };
+        let binary = |lhs: &MathOp, rhs: &MathOp| num(lhs).zip(num(rhs));
+        let value = match &folded {
+            MathOp::Add { lhs, rhs } => binary(lhs, rhs).map(|(x, y)| x + y),
+            MathOp::Sub { lhs, rhs } => binary(lhs, rhs).map(|(x, y)| x - y),
+            MathOp::Mul { lhs, rhs } => binary(lhs, rhs).map(|(x, y)| x * y),
+            MathOp::Div { lhs, rhs } => binary(lhs, rhs).map(|(x, y)| x / y),
+            MathOp::Exp { lhs, rhs } => binary(lhs, rhs).map(|(x, y)| x.powf(y)),
+            MathOp::Cmp { op, lhs, rhs } => {
+                binary(lhs, rhs).map(|(x, y)| if op.apply(x, y) { 1.0 } else { 0.0 })
+            }
+            MathOp::Neg(x) => num(x).map(|x| -x),
+            _ => None,
+        };
+        value.map_or(folded, MathOp::Num)
+    }
+
+    /// Replaces every use of the variable `name` with `value`. Lambdas are left alone, as their body
+    /// can't see variables from outside it.
+    pub fn substitute(self, name: char, value: &MathOp) -> MathOp {
+        match self {
+            MathOp::Arg(arg, _) if arg == name => value.clone(),
+            MathOp::Lambda { .. } => self,
+            _ => self.map_children(&|x| x.substitute(name, value)),
+        }
+    }
+
+    /// Appends this expression to `out` as Graphviz DOT node and edge statements, numbering nodes
+    /// from `next_id`. Returns the id of the node for this operation.
+    pub fn write_dot(&self, out: &mut String, next_id: &mut usize) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+        let label = match self {
+            MathOp::Add { .. } => "+".to_string(),
+            MathOp::Sub { .. } => "-".to_string(),
+            MathOp::Mul { .. } => "*".to_string(),
+            MathOp::Div { .. } => "/".to_string(),
+            MathOp::Exp { .. } => "^".to_string(),
+            MathOp::Cmp { op, .. } => op.to_string(),
+            MathOp::Piecewise(_) => "cases".to_string(),
+            MathOp::Call { name, .. } => format!("{name}()"),
+            MathOp::List(_) => "list".to_string(),
+            MathOp::Tuple(_) => "tuple".to_string(),
+            MathOp::Lambda { arg, .. } => format!("{arg} ->"),
+            MathOp::Neg(_) => "neg".to_string(),
+            MathOp::Arg(name, _) => name.to_string(),
+            MathOp::Num(x) => x.to_string(),
+        };
+        out.push_str(&format!("    n{id} [label=\"{label}\"];\n"));
+        for child in self.children() {
+            let child_id = child.write_dot(out, next_id);
+            out.push_str(&format!("    n{id} -> n{child_id};\n"));
+        }
+        id
+    }
+
+    /// Every variable used in the expression which isn't bound by a lambda inside it.
+    pub fn free_vars(&self) -> BTreeSet<char> {
+        match self {
+            MathOp::Arg(name, _) => BTreeSet::from([*name]),
+            MathOp::Lambda { arg, body } => {
+                let mut vars = body.free_vars();
+                vars.remove(arg);
+                vars
+            }
+            _ => self
+                .children()
+                .into_iter()
+                .flat_map(MathOp::free_vars)
+                .collect(),
+        }
+    }
+
+    // Every function called anywhere in this expression, including intrinsics
+    pub fn called_functions(&self) -> Vec<(&str, &Span)> {
+        let mut names = match self {
+            MathOp::Call { name, span, .. } => vec![(&name[..], span)],
+            _ => vec![],
+        };
+        for child in self.children() {
+            names.extend(child.called_functions());
+        }
+        names
+    }
+}
+
+// Every operation is fully parenthesized, so the grouping chosen by the parser is explicit
+impl Display for MathOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MathOp::Add { lhs, rhs } => write!(f, "({lhs} + {rhs})"),
+            MathOp::Sub { lhs, rhs } => write!(f, "({lhs} - {rhs})"),
+            MathOp::Mul { lhs, rhs } => write!(f, "({lhs} * {rhs})"),
+            MathOp::Div { lhs, rhs } => write!(f, "({lhs} / {rhs})"),
+            MathOp::Exp { lhs, rhs } => write!(f, "({lhs} ^ {rhs})"),
+            MathOp::Cmp { op, lhs, rhs } => write!(f, "({lhs} {op} {rhs})"),
+            MathOp::Piecewise(cases) => {
+                let cases = cases
+                    .iter()
+                    .map(|(cond, value)| match cond {
+                        Some(cond) => format!("{value} if {cond}"),
+                        None => format!("{value} otherwise"),
+                    })
+                    .collect::<Vec<_>>();
+                write!(f, "{{ {} }}", cases.join(", "))
+            }
+            MathOp::Call { name, args, .. } => write!(
+                f,
+                "{name}({})",
+                args.iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            MathOp::List(items) => write!(
+                f,
+                "[{}]",
+                items
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            MathOp::Tuple(items) if items.len() == 1 => write!(f, "({},)", items[0]),
+            MathOp::Tuple(items) => write!(
+                f,
+                "({})",
+                items
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            MathOp::Lambda { arg, body } => write!(f, "({arg} -> {body})"),
+            MathOp::Neg(x) => write!(f, "(-{x})"),
+            MathOp::Arg(x, _) => write!(f, "{x}"),
+            MathOp::Num(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{ParseOutput, Parser};
+
+    use super::MathOp;
+
+    fn parse(input: &str) -> MathOp {
+        match Parser::new(input).unwrap().parse().unwrap().remove(0) {
+            ParseOutput::Body(body) => body,
+            other => panic!("expected an expression, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn commutative_operands_canonicalize_identically() {
+        assert!(parse("a+b").equivalent(&parse("b+a")));
+        assert!(parse("a*b").equivalent(&parse("b*a")));
+        assert!(parse("(c*b)+a").equivalent(&parse("a+b*c")));
+        assert_eq!(
+            parse("b+a").canonicalize().to_string(),
+            parse("a+b").canonicalize().to_string()
+        );
+    }
+
+    #[test]
+    fn non_commutative_operands_keep_their_order() {
+        assert!(!parse("a-b").equivalent(&parse("b-a")));
+        assert!(!parse("a/b").equivalent(&parse("b/a")));
+        assert!(!parse("a^b").equivalent(&parse("b^a")));
+        assert!(!parse("a+b").equivalent(&parse("a*b")));
+    }
+
+    #[test]
+    fn dot_output_has_a_node_per_operation() {
+        let mut dot = String::new();
+        let mut next_id = 0;
+        let root = parse("2 + 3 * sqrt(x)").write_dot(&mut dot, &mut next_id);
+        assert_eq!(root, 0);
+        assert_eq!(next_id, 6);
+        assert_eq!(dot.matches("[label=").count(), 6);
+        assert_eq!(dot.matches(" -> ").count(), 5);
+        assert!(dot.contains("n0 [label=\"+\"];"));
+        assert!(dot.contains("[label=\"sqrt()\"];"));
+    }
+
+    #[test]
+    fn constant_arithmetic_is_folded() {
+        assert_eq!(parse("x * 2^3").fold_constants().to_string(), "(x * 8)");
+        assert_eq!(parse("x * (2 + 3)").fold_constants().to_string(), "(x * 5)");
+        assert_eq!(
+            parse("-(1 + 1) * x").fold_constants().to_string(),
+            "(-2 * x)"
+        );
+        // Nothing depending on a variable or a call is folded
+        assert_eq!(
+            parse("(x + 2) + 3").fold_constants().to_string(),
+            "((x + 2) + 3)"
+        );
+        assert_eq!(
+            parse("sqrt(4) + 1").fold_constants().to_string(),
+            "(sqrt(4) + 1)"
+        );
+    }
+
+    #[test]
+    fn free_variables() {
+        let free = |input| parse(input).free_vars().into_iter().collect::<String>();
+        assert_eq!(free("2 + 3"), "");
+        assert_eq!(free("x^2 + y*x"), "xy");
+        assert_eq!(free("f(g(a, b), sqrt(c)) + a"), "abc");
+        // A lambda's own argument isn't free, but other variables in its body are
+        assert_eq!(free("sum(x -> x + y, 1, z)"), "yz");
+        assert_eq!(free("{ a if b > 0, c otherwise }"), "abc");
+    }
+}
@@ -1,17 +1,24 @@
 use std::fmt::Display;
+use std::sync::Arc;
 
+use crate::error::MathJitError;
 use crate::eval::intrinsic;
 use crate::ops;
 use crate::tokenizer;
-use crate::util;
-use anyhow::Context;
 use anyhow::{anyhow, Result};
 
+// Input nested deeper than this is rejected, rather than overflowing the stack in the recursive descent below
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
 #[derive(Debug)]
 pub struct Parser {
     tokens: Vec<tokenizer::MathToken>,
     original_tokens: Vec<tokenizer::MathToken>,
-    original_string: String,
+    original_string: Arc<str>,
+    // Parameters of the function whose body is being parsed, these shadow bare constants
+    bound_args: Vec<char>,
+    depth: usize,
+    max_depth: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -21,10 +28,46 @@ pub struct Function {
     pub body: ops::MathOp,
 }
 
-#[derive(Debug)]
+impl Function {
+    /// The name and parameters, such as `f(x, y)`.
+    pub fn signature(&self) -> String {
+        let args = self.args.iter().map(char::to_string).collect::<Vec<_>>();
+        format!("{}({})", self.name, args.join(", "))
+    }
+
+    /// Parameters which are never referenced by the body, in declaration order.
+    pub fn unused_args(&self) -> Vec<char> {
+        let used = self.body.free_vars();
+        self.args
+            .iter()
+            .copied()
+            .filter(|x| !used.contains(x))
+            .collect()
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} = {}", self.signature(), self.body)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum ParseOutput {
     Body(ops::MathOp),
     Functions(Vec<Function>),
+    // `c = 2 + 3`, where the value is evaluated once when defined rather than every time `c` is used
+    Constant { name: char, value: ops::MathOp },
+}
+
+/// A whole chain of input sorted by kind, for tools which want every definition up front. The order within
+/// each list is kept, but not the order between them, so `f(x)=x & f(1) & f(x)=2x` can't be told apart from
+/// the same definitions followed by `f(1)`.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub definitions: Vec<Function>,
+    pub constants: Vec<(char, ops::MathOp)>,
+    pub queries: Vec<ops::MathOp>,
 }
 
 impl Parser {
@@ -33,19 +76,77 @@ impl Parser {
         Ok(Self {
             tokens: tokens.clone(),
             original_tokens: tokens,
-            original_string: input.to_string(),
+            original_string: input.into(),
+            bound_args: vec![],
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
         })
     }
 
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
     pub fn original_tokens(&self) -> &[tokenizer::MathToken] {
         &self.original_tokens
     }
 
-    fn from_tokens(input: &str, tokens: Vec<tokenizer::MathToken>) -> Self {
+    // Bracketed sub-expressions get their own parser, which carries on counting from the parent's depth
+    fn sub_parser(&self, tokens: Vec<tokenizer::MathToken>) -> Self {
         Self {
             tokens: tokens.clone(),
             original_tokens: tokens,
-            original_string: input.to_string(),
+            original_string: Arc::clone(&self.original_string),
+            bound_args: self.bound_args.clone(),
+            depth: self.depth,
+            max_depth: self.max_depth,
+        }
+    }
+
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<ops::MathOp>,
+    ) -> Result<ops::MathOp> {
+        if self.depth >= self.max_depth {
+            let pos = self.peek().map_or(
+                self.original_string.len() - 1,
+                tokenizer::MathToken::position,
+            );
+            return Err(MathJitError::TooDeeplyNested {
+                span: Some(self.span(pos, pos)),
+            }
+            .into());
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn error(&self, message: impl Into<String>, start: usize, end: usize) -> anyhow::Error {
+        MathJitError::Parse {
+            message: message.into(),
+            span: Some(self.span(start, end)),
+        }
+        .into()
+    }
+
+    // `end` is the last byte of the span, so when it's given as the start of a multi-byte character, such as a
+    // token's position for `⌋`, it's moved to the last byte of that character. `start` is moved back to the
+    // start of its character, for errors at the last byte of the input
+    fn span(&self, start: usize, end: usize) -> ops::Span {
+        let mut start = start;
+        while !self.original_string.is_char_boundary(start) {
+            start -= 1;
+        }
+        let end = match self.original_string.get(end..) {
+            Some(rest) => end + rest.chars().next().map_or(1, char::len_utf8) - 1,
+            None => end,
+        };
+        ops::Span {
+            source: Arc::clone(&self.original_string),
+            start,
+            end,
         }
     }
 
@@ -63,6 +164,7 @@ impl Parser {
     fn parse_primary_func_call(&mut self) -> Result<Option<ops::MathOp>> {
         let mut name_buf = String::new();
         let mut args = vec![];
+        let name_start = self.peek().map_or(0, tokenizer::MathToken::position);
         while let Some(tokenizer::MathToken::Id(_, chr)) = self.peek() {
             name_buf.push(*chr);
             self.pop();
@@ -82,10 +184,31 @@ impl Parser {
                     break;
                 }
                 _ => {
-                    let arg = self.parse_expr()?;
+                    let arg = match self.parse_lambda(args.is_empty(), &name_buf)? {
+                        Some(lambda) => lambda,
+                        None => match self.parse_function_reference(args.is_empty(), &name_buf)? {
+                            Some(lambda) => lambda,
+                            None => self.parse_expr()?,
+                        },
+                    };
                     args.push(arg);
-                    if let Some(tokenizer::MathToken::Delim(_)) = self.peek() {
-                        self.pop();
+                    match self.peek() {
+                        // A trailing comma before the `)` is allowed
+                        Some(tokenizer::MathToken::Delim(_)) => {
+                            self.pop();
+                        }
+                        Some(tokenizer::MathToken::Close(_)) => {}
+                        Some(tok) => {
+                            let pos = tok.position();
+                            return Err(self.error(
+                                "expected ',' or ')' in function call",
+                                pos,
+                                pos,
+                            ));
+                        }
+                        None => {
+                            return Err(self.error("function call is never closed", start, start));
+                        }
                     }
                 }
             }
@@ -96,27 +219,182 @@ impl Parser {
 
         let standard_intrinsics = intrinsic::standard_intrinsics();
         if let Some(intrin) = standard_intrinsics.get(&name_buf[..]) {
-            if intrin.proto().arg_count as usize != args.len() {
-                let error = util::error_message(&self.original_string, start, end);
-                return Err(anyhow!(
-                    "incorrect argument count for '{name_buf}' call, {} provided, {} expected {error}",
-                    args.len(),
-                    intrin.proto().arg_count
-                ));
+            // A lambda is passed in addition to the intrinsic's usual arguments, the last of which becomes optional
+            let has_lambda = matches!(args.first(), Some(ops::MathOp::Lambda { .. }));
+            let arg_count = args.len() - usize::from(has_lambda);
+            let proto = intrin.proto();
+            let expected = proto.arg_count();
+            if arg_count != expected && !(has_lambda && arg_count + 1 == expected) {
+                return Err(MathJitError::Arity {
+                    message: format!(
+                        "incorrect argument count for '{name_buf}' call, {arg_count} provided, {name_buf} expects {}",
+                        proto.parameters()
+                    ),
+                    name: name_buf,
+                    provided: arg_count,
+                    span: self.span(start, end),
+                }
+                .into());
             }
         }
 
         Ok(Some(ops::MathOp::Call {
             name: name_buf,
             args,
+            span: self.span(name_start, end),
         }))
     }
 
-    fn parse_primary(&mut self) -> Result<ops::MathOp> {
-        if let Some(tokenizer::MathToken::Sub(_)) = self.peek() {
+    // An inline function such as `x -> x^2`, which may only be the first argument of an intrinsic that accepts one
+    fn parse_lambda(&mut self, first_arg: bool, callee: &str) -> Result<Option<ops::MathOp>> {
+        let (Some(tokenizer::MathToken::Id(pos, arg)), Some(tokenizer::MathToken::Arrow(_))) =
+            (self.tokens.first(), self.tokens.get(1))
+        else {
+            return Ok(None);
+        };
+        let (pos, arg) = (*pos, *arg);
+
+        let accepted = intrinsic::standard_intrinsics()
+            .get(callee)
+            .is_some_and(|x| x.accepts_lambda());
+        if !first_arg || !accepted {
+            return Err(self.error(
+                "a lambda can only be the first argument of a function such as 'sum'",
+                pos,
+                pos,
+            ));
+        }
+
+        self.tokens.drain(..2);
+        let outer_args = std::mem::replace(&mut self.bound_args, vec![arg]);
+        let body = self.parse_comparison();
+        self.bound_args = outer_args;
+        Ok(Some(ops::MathOp::Lambda {
+            arg,
+            body: Box::new(body?),
+        }))
+    }
+
+    // A function passed by name where a lambda is accepted, such as `sum(sin, 0, 1)`, becomes the lambda
+    // `x -> sin(x)`. Both user functions and intrinsics can be passed, as the call is resolved like any other
+    fn parse_function_reference(
+        &mut self,
+        first_arg: bool,
+        callee: &str,
+    ) -> Result<Option<ops::MathOp>> {
+        let intrinsics = intrinsic::standard_intrinsics();
+        if !first_arg || !intrinsics.get(callee).is_some_and(|x| x.accepts_lambda()) {
+            return Ok(None);
+        }
+        let name = self
+            .tokens
+            .iter()
+            .map_while(|x| match x {
+                tokenizer::MathToken::Id(_, chr) => Some(*chr),
+                _ => None,
+            })
+            .collect::<String>();
+        let len = name.chars().count();
+        if len == 0
+            || !matches!(self.tokens.get(len), Some(tokenizer::MathToken::Delim(_)))
+            || (len == 1 && self.bound_args.contains(&name.chars().next().unwrap()))
+        {
+            return Ok(None);
+        }
+
+        let (start, end) = (self.tokens[0].position(), self.tokens[len - 1].position());
+        match intrinsics.get(&name[..]).map(|x| x.proto().arg_count()) {
+            // Constants such as `pi` are values, not functions
+            Some(0) => return Ok(None),
+            Some(1) | None => {}
+            Some(_) => {
+                return Err(self.error(format!("'{name}' can't be passed to '{callee}', as it doesn't take a single argument"), start, end));
+            }
+        }
+
+        self.tokens.drain(..len);
+        let span = self.span(start, end);
+        Ok(Some(ops::MathOp::Lambda {
+            arg: 'x',
+            body: Box::new(ops::MathOp::Call {
+                name,
+                args: vec![ops::MathOp::Arg('x', span.clone())],
+                span,
+            }),
+        }))
+    }
+
+    // Zero-argument intrinsics such as `pi` and `e` can be used without call parentheses
+    fn parse_constant(&mut self) -> Option<ops::MathOp> {
+        let name = self
+            .tokens
+            .iter()
+            .map_while(|x| match x {
+                tokenizer::MathToken::Id(_, chr) => Some(*chr),
+                _ => None,
+            })
+            .collect::<String>();
+        let len = name.chars().count();
+        if len == 1 && self.bound_args.contains(&name.chars().next().unwrap()) {
+            return None;
+        }
+
+        let standard_intrinsics = intrinsic::standard_intrinsics();
+        if standard_intrinsics.get(&name[..])?.proto().arg_count() != 0 {
+            return None;
+        }
+        let span = self.span(self.tokens[0].position(), self.tokens[len - 1].position());
+        self.tokens.drain(..len);
+        Some(ops::MathOp::Call {
+            name,
+            args: vec![],
+            span,
+        })
+    }
+
+    // A single argument intrinsic may be applied to the primary after it without brackets, `sin 0.5` is `sin(0.5)`.
+    // Only the primary is taken, so `sin 2^2` is `sin(2)^2`. The name must be written without spaces, which is
+    // how `sin x` is told apart from the variables `s`, `i`, `n` and `x`
+    fn parse_paren_free_call(&mut self) -> Result<Option<ops::MathOp>> {
+        let Some(tokenizer::MathToken::Id(name_start, _)) = self.peek() else {
+            return Ok(None);
+        };
+        let name_start = *name_start;
+        let mut name = String::new();
+        let mut name_end = name_start;
+        while let Some(tokenizer::MathToken::Id(pos, chr)) = self.peek() {
+            if *pos != name_start + name.len() {
+                break;
+            }
+            name_end = *pos;
+            name.push(*chr);
             self.pop();
-            return Ok(ops::MathOp::Neg(Box::new(self.parse_inner_func()?)));
         }
+        let takes_one = intrinsic::standard_intrinsics()
+            .get(&name[..])
+            .is_some_and(|x| x.proto().arg_count() == 1);
+        let operand_follows = matches!(
+            self.peek(),
+            Some(
+                tokenizer::MathToken::Num(..)
+                    | tokenizer::MathToken::Id(..)
+                    | tokenizer::MathToken::Open(_)
+                    | tokenizer::MathToken::FloorOpen(_)
+                    | tokenizer::MathToken::CeilOpen(_)
+            )
+        );
+        if !takes_one || !operand_follows {
+            return Ok(None);
+        }
+        let arg = self.parse_primary()?;
+        Ok(Some(ops::MathOp::Call {
+            name,
+            args: vec![arg],
+            span: self.span(name_start, name_end),
+        }))
+    }
+
+    fn parse_primary(&mut self) -> Result<ops::MathOp> {
         if let Some(tokenizer::MathToken::Open(start)) = self.peek() {
             let start = *start;
             let mut end = 0;
@@ -129,7 +407,11 @@ impl Parser {
                     depth -= 1;
                     if depth == 0 {
                         if let Some(tokenizer::MathToken::Close(_)) = self.peek() {
-                            return Err(anyhow!("brackets not balanced"));
+                            return Err(MathJitError::Parse {
+                                message: "brackets not balanced".to_string(),
+                                span: None,
+                            }
+                            .into());
                         }
                         break;
                     }
@@ -139,44 +421,184 @@ impl Parser {
                 tok_list.push(tok);
             }
             if depth != 0 {
-                let error = util::error_message(&self.original_string, start, start);
-                return Err(anyhow!("brackets not balanced{error}"));
+                return Err(self.error("brackets not balanced", start, start));
             }
-            let mut parser = Self::from_tokens(&self.original_string, tok_list);
-            return parser.parse_inner_func().with_context(|| {
-                let error = util::error_message(&self.original_string, start, end);
-                anyhow!("while evaluating brackets{error}")
-            });
+            let mut parser = self.sub_parser(tok_list);
+            return parser
+                .parse_bracketed()
+                .and_then(|x| parser.expect_end().map(|()| x))
+                .map_err(|e| {
+                    if let Some(MathJitError::TooDeeplyNested { .. }) = e.downcast_ref() {
+                        return e;
+                    }
+                    let error = self.span(start, end).error_message();
+                    e.context(anyhow!("while evaluating brackets{error}"))
+                });
+        } else if let Some(tokenizer::MathToken::FloorOpen(start)) = self.peek() {
+            let start = *start;
+            self.pop();
+            return self.parse_rounding_brackets("floor", start);
+        } else if let Some(tokenizer::MathToken::CeilOpen(start)) = self.peek() {
+            let start = *start;
+            self.pop();
+            return self.parse_rounding_brackets("ceil", start);
+        } else if let Some(tokenizer::MathToken::CasesOpen(start)) = self.peek() {
+            let start = *start;
+            self.pop();
+            return self.parse_piecewise(start);
+        } else if let Some(tokenizer::MathToken::ListOpen(start)) = self.peek() {
+            let start = *start;
+            self.pop();
+            let (items, _) = self.parse_list_items(start)?;
+            return Ok(ops::MathOp::List(items));
         } else if let Some(tokenizer::MathToken::Num(_, _)) = self.peek() {
             let bb = self.pop();
             if let Some(tokenizer::MathToken::Num(_, x)) = bb {
-                if let Some(tokenizer::MathToken::Open(_)) = self.peek() {
-                    let expr = self.parse_primary()?;
-                    return Ok(ops::MathOp::Mul {
-                        lhs: Box::new(ops::MathOp::Num(x)),
-                        rhs: Box::new(expr),
-                    });
-                }
+                // A postfix percent only applies to the number literal directly before it
+                let x = if let Some(tokenizer::MathToken::Percent(_)) = self.peek() {
+                    self.pop();
+                    x / 100.0
+                } else {
+                    x
+                };
                 return Ok(ops::MathOp::Num(x));
             }
             panic!("Should never happen {bb:?}");
-        } else if let Some(tokenizer::MathToken::Id(_, name)) = self.peek() {
-            let name = *name;
+        } else if let Some(tokenizer::MathToken::Id(pos, name)) = self.peek() {
+            let (pos, name) = (*pos, *name);
             let before = self.tokens.clone();
 
             if let Some(call) = self.parse_primary_func_call()? {
                 return Ok(call);
             }
+            self.tokens = before.clone();
+            if let Some(points) = self.parse_point_evaluation()? {
+                return Ok(points);
+            }
+            self.tokens = before.clone();
+            if let Some(call) = self.parse_paren_free_call()? {
+                return Ok(call);
+            }
             self.tokens = before;
+            if let Some(constant) = self.parse_constant() {
+                return Ok(constant);
+            }
             self.pop();
-            return Ok(ops::MathOp::Arg(name));
+            return Ok(ops::MathOp::Arg(name, self.span(pos, pos)));
         }
         let pos = self.peek().map_or(
             self.original_string.len() - 1,
             tokenizer::MathToken::position,
         );
-        let error = util::error_message(&self.original_string, pos, pos);
-        Err(anyhow!("expected number or open bracket{error}"))
+        Err(self.error("expected number or open bracket", pos, pos))
+    }
+
+    // The items of a list after its `[`, returning them with the position of the closing `]`
+    fn parse_list_items(&mut self, start: usize) -> Result<(Vec<ops::MathOp>, usize)> {
+        let mut items = vec![];
+        loop {
+            match self.peek() {
+                Some(tokenizer::MathToken::ListClose(end)) => {
+                    let end = *end;
+                    self.pop();
+                    return Ok((items, end));
+                }
+                None => {
+                    return Err(self.error("list is never closed", start, start));
+                }
+                _ => {
+                    items.push(self.parse_expr()?);
+                    match self.peek() {
+                        Some(tokenizer::MathToken::Delim(_)) => {
+                            self.pop();
+                        }
+                        Some(tokenizer::MathToken::ListClose(_)) | None => {}
+                        Some(tok) => {
+                            let pos = tok.position();
+                            return Err(self.error("expected ',' or ']' in list", pos, pos));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // `f[1, 2, 3]` evaluates a single argument function at each point, and is the list `[f(1), f(2), f(3)]`
+    fn parse_point_evaluation(&mut self) -> Result<Option<ops::MathOp>> {
+        let name_start = self.peek().map_or(0, tokenizer::MathToken::position);
+        let mut name = String::new();
+        while let Some(tokenizer::MathToken::Id(_, chr)) = self.peek() {
+            name.push(*chr);
+            self.pop();
+        }
+        let Some(tokenizer::MathToken::ListOpen(start)) = self.peek() else {
+            return Ok(None);
+        };
+        let start = *start;
+        if name.chars().count() == 1 && self.bound_args.contains(&name.chars().next().unwrap()) {
+            return Ok(None);
+        }
+        self.pop();
+        let (points, end) = self.parse_list_items(start)?;
+
+        let standard_intrinsics = intrinsic::standard_intrinsics();
+        if let Some(intrin) = standard_intrinsics.get(&name[..]) {
+            if intrin.proto().arg_count() != 1 {
+                return Err(self.error(format!("'{name}' can't be evaluated at a list of points, as it doesn't take a single argument"), name_start, end));
+            }
+        }
+
+        let span = self.span(name_start, end);
+        Ok(Some(ops::MathOp::List(
+            points
+                .into_iter()
+                .map(|point| ops::MathOp::Call {
+                    name: name.clone(),
+                    args: vec![point],
+                    span: span.clone(),
+                })
+                .collect(),
+        )))
+    }
+
+    // `⌊x⌋` is `floor(x)` and `⌈x⌉` is `ceil(x)`. The brackets are three bytes long, which spans have to cover
+    fn parse_rounding_brackets(&mut self, name: &str, start: usize) -> Result<ops::MathOp> {
+        let (open, close) = if name == "floor" {
+            ('⌊', '⌋')
+        } else {
+            ('⌈', '⌉')
+        };
+        let value = self.parse_comparison()?;
+        match (name, self.pop()) {
+            ("floor", Some(tokenizer::MathToken::FloorClose(end)))
+            | ("ceil", Some(tokenizer::MathToken::CeilClose(end))) => Ok(ops::MathOp::Call {
+                name: name.to_string(),
+                args: vec![value],
+                span: self.span(start, end + close.len_utf8() - 1),
+            }),
+            _ => Err(self.error(
+                format!("'{open}' is never closed with '{close}'"),
+                start,
+                start + open.len_utf8() - 1,
+            )),
+        }
+    }
+
+    // Exponents may carry their own sign (`2^-3`, `2^+2`), which only applies to the exponent operand
+    fn parse_exp_operand(&mut self) -> Result<ops::MathOp> {
+        match self.peek() {
+            Some(tokenizer::MathToken::Sub(_)) => {
+                self.pop();
+                Ok(ops::MathOp::Neg(Box::new(
+                    self.nested(Self::parse_exp_operand)?,
+                )))
+            }
+            Some(tokenizer::MathToken::Add(_)) => {
+                self.pop();
+                self.nested(Self::parse_exp_operand)
+            }
+            _ => self.parse_primary(),
+        }
     }
 
     fn parse_exp(&mut self) -> Result<ops::MathOp> {
@@ -185,7 +607,7 @@ impl Parser {
             match self.peek() {
                 Some(tokenizer::MathToken::Exp(_)) => {
                     let _ = self.pop();
-                    let rhs = self.parse_primary()?;
+                    let rhs = self.parse_exp_operand()?;
                     lhs = ops::MathOp::Exp {
                         lhs: Box::new(lhs),
                         rhs: Box::new(rhs),
@@ -198,17 +620,23 @@ impl Parser {
         }
     }
 
-    fn parse_term(&mut self) -> Result<ops::MathOp> {
+    // Negation is the only unary operator, binding looser than `^` and tighter than everything else, so
+    // `-2^2` is `-(2^2)` and `2*-3+1` is `(2*(-3))+1`
+    fn parse_unary(&mut self) -> Result<ops::MathOp> {
         if let Some(tokenizer::MathToken::Sub(_)) = self.peek() {
             self.pop();
-            return Ok(ops::MathOp::Neg(Box::new(self.parse_term()?)));
+            return Ok(ops::MathOp::Neg(Box::new(self.nested(Self::parse_unary)?)));
         }
-        let mut lhs = self.parse_exp()?;
+        self.parse_exp()
+    }
+
+    fn parse_term(&mut self) -> Result<ops::MathOp> {
+        let mut lhs = self.parse_unary()?;
         loop {
             match self.peek() {
                 Some(tokenizer::MathToken::Mul(_)) => {
                     let _ = self.pop();
-                    let rhs = self.parse_exp()?;
+                    let rhs = self.parse_unary()?;
                     lhs = ops::MathOp::Mul {
                         lhs: Box::new(lhs),
                         rhs: Box::new(rhs),
@@ -216,7 +644,7 @@ impl Parser {
                 }
                 Some(tokenizer::MathToken::Div(_)) => {
                     let _ = self.pop();
-                    let rhs = self.parse_exp()?;
+                    let rhs = self.parse_unary()?;
                     lhs = ops::MathOp::Div {
                         lhs: Box::new(lhs),
                         rhs: Box::new(rhs),
@@ -230,11 +658,10 @@ impl Parser {
     }
 
     fn parse_expr(&mut self) -> Result<ops::MathOp> {
-        if let Some(tokenizer::MathToken::Sub(_)) = self.peek() {
-            self.pop();
-            return Ok(ops::MathOp::Neg(Box::new(self.parse_expr()?)));
-        }
+        self.nested(Self::parse_expr_unnested)
+    }
 
+    fn parse_expr_unnested(&mut self) -> Result<ops::MathOp> {
         let mut lhs = self.parse_term()?;
         loop {
             match self.peek() {
@@ -263,41 +690,256 @@ impl Parser {
 
     fn parse_inner_func(&mut self) -> Result<ops::MathOp> {
         if self.tokens.is_empty() {
-            return Err(anyhow!("no input provided"));
+            return Err(MathJitError::Parse {
+                message: "no input provided".to_string(),
+                span: None,
+            }
+            .into());
+        }
+
+        self.parse_comparison()
+    }
+
+    // Commas directly inside brackets separate the values of a tuple, such as `(1, 2)`. As with calls, lists and
+    // definitions, a trailing comma is allowed, so `(1,)` is a tuple holding one value
+    fn parse_bracketed(&mut self) -> Result<ops::MathOp> {
+        let first = self.parse_inner_func()?;
+        if !matches!(self.peek(), Some(tokenizer::MathToken::Delim(_))) {
+            return Ok(first);
         }
 
-        let out = self.parse_expr();
-        out
+        let mut items = vec![first];
+        while let Some(tokenizer::MathToken::Delim(_)) = self.peek() {
+            self.pop();
+            if self.peek().is_none() {
+                break;
+            }
+            items.push(self.parse_inner_func()?);
+        }
+        Ok(ops::MathOp::Tuple(items))
     }
 
-    fn parse_expression_chain_single(&mut self) -> Result<ParseOutput> {
+    fn parse_comparison(&mut self) -> Result<ops::MathOp> {
+        let lhs = self.parse_expr()?;
+        if let Some(tokenizer::MathToken::Cmp(_, op)) = self.peek() {
+            let op = *op;
+            self.pop();
+            let rhs = self.parse_expr()?;
+            return Ok(ops::MathOp::Cmp {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            });
+        }
+        Ok(lhs)
+    }
+
+    // Keywords are spelt out by consecutive identifier tokens, such as `if` or `otherwise`. Whitespace is not
+    // tokenized, so a keyword may run straight into the identifier after it (`if x` arrives as `ifx`)
+    fn parse_keyword(&mut self, keyword: &str) -> bool {
+        let len = keyword.chars().count();
+        let spelt = self
+            .tokens
+            .iter()
+            .take(len)
+            .map(|x| match x {
+                tokenizer::MathToken::Id(_, chr) => Some(*chr),
+                _ => None,
+            })
+            .eq(keyword.chars().map(Some));
+        if !spelt {
+            return false;
+        }
+        self.tokens.drain(..len);
+        true
+    }
+
+    fn parse_piecewise(&mut self, start: usize) -> Result<ops::MathOp> {
+        let mut cases = vec![];
+        loop {
+            let value = self.parse_comparison()?;
+            if self.parse_keyword("otherwise") {
+                cases.push((None, value));
+                let Some(tokenizer::MathToken::CasesClose(_)) = self.pop() else {
+                    return Err(self.error("'otherwise' must be the last case", start, start));
+                };
+                break;
+            }
+            if !self.parse_keyword("if") {
+                let pos = self.peek().map_or(
+                    self.original_string.len() - 1,
+                    tokenizer::MathToken::position,
+                );
+                return Err(self.error("expected 'if' or 'otherwise' after case value", pos, pos));
+            }
+            cases.push((Some(self.parse_comparison()?), value));
+            match self.pop() {
+                Some(tokenizer::MathToken::Delim(_)) => {}
+                Some(tokenizer::MathToken::CasesClose(_)) => break,
+                Some(tok) => {
+                    let pos = tok.position();
+                    return Err(self.error("expected ',' or '}' after case condition", pos, pos));
+                }
+                None => {
+                    return Err(self.error("cases are never closed", start, start));
+                }
+            }
+        }
+        Ok(ops::MathOp::Piecewise(cases))
+    }
+
+    fn parse_expression_chain_single(&mut self) -> Result<Vec<ParseOutput>> {
         let save = self.tokens.clone();
         if let Some(func) = self.parse_full_func()? {
-            return Ok(func);
+            let mut outputs = vec![func];
+            if let Some(ParseOutput::Functions(funcs)) = outputs.first() {
+                if let Some(sample) = self.parse_sample_call(&funcs[0])? {
+                    outputs.push(sample);
+                }
+            }
+            return Ok(outputs);
         }
         self.tokens = save;
+        if let Some(constant) = self.parse_constant_definition()? {
+            return Ok(vec![constant]);
+        }
 
-        Ok(ParseOutput::Body(self.parse_inner_func()?))
+        let mut body = self.parse_inner_func()?;
+        if self.parse_keyword("where") {
+            for (name, value) in self.parse_where_bindings()? {
+                body = body.substitute(name, &value);
+            }
+        }
+        self.check_depth(&body)?;
+        Ok(vec![ParseOutput::Body(body)])
     }
 
-    pub fn parse(&mut self) -> Result<Vec<ParseOutput>> {
-        let first = self.parse_expression_chain_single()?;
+    // A definition may be followed by values to try it with, `f(x) = x^2 @ 3` defines `f` and then
+    // evaluates `f(3)`. Functions with several parameters take a value for each, `g(x, y) = x*y @ 2, 3`
+    fn parse_sample_call(&mut self, func: &Function) -> Result<Option<ParseOutput>> {
+        let Some(tokenizer::MathToken::At(pos)) = self.peek() else {
+            return Ok(None);
+        };
+        let pos = *pos;
+        self.pop();
+        let mut args = vec![self.parse_expr()?];
+        while let Some(tokenizer::MathToken::Delim(_)) = self.peek() {
+            self.pop();
+            args.push(self.parse_expr()?);
+        }
+        if args.len() != func.args.len() {
+            return Err(MathJitError::Arity {
+                name: func.name.clone(),
+                provided: args.len(),
+                message: format!(
+                    "{} takes {} argument(s), but {} sample value(s) were given",
+                    func.signature(),
+                    func.args.len(),
+                    args.len()
+                ),
+                span: self.span(pos, pos),
+            }
+            .into());
+        }
+        Ok(Some(ParseOutput::Body(ops::MathOp::Call {
+            name: func.name.clone(),
+            args,
+            span: self.span(pos, pos),
+        })))
+    }
 
-        let mut exprs = vec![first];
-        while matches!(self.peek(), Some(tokenizer::MathToken::Chain(_))) {
+    fn parse_constant_definition(&mut self) -> Result<Option<ParseOutput>> {
+        let (Some(tokenizer::MathToken::Id(pos, name)), Some(tokenizer::MathToken::Eq(_))) =
+            (self.tokens.first(), self.tokens.get(1))
+        else {
+            return Ok(None);
+        };
+        let (pos, name) = (*pos, *name);
+        if intrinsic::standard_intrinsics().contains_key(&name.to_string()[..]) {
+            return Err(self.error(
+                format!("'{name}' is a built-in function, and can't be defined as a constant"),
+                pos,
+                pos,
+            ));
+        }
+
+        self.tokens.drain(..2);
+        let value = self.parse_inner_func()?;
+        self.check_depth(&value)?;
+        Ok(Some(ParseOutput::Constant { name, value }))
+    }
+
+    // Values for the free variables of an expression, `x^2 + 1 where x = 3, y = 4`. Each binding is
+    // substituted in turn, so a value may refer to variables bound after it
+    fn parse_where_bindings(&mut self) -> Result<Vec<(char, ops::MathOp)>> {
+        let mut bindings = vec![];
+        loop {
+            let Some(tokenizer::MathToken::Id(_, name)) = self.pop() else {
+                let pos = self.peek().map_or(
+                    self.original_string.len() - 1,
+                    tokenizer::MathToken::position,
+                );
+                return Err(self.error("expected a variable name after 'where'", pos, pos));
+            };
+            let Some(tokenizer::MathToken::Eq(_)) = self.pop() else {
+                let pos = self.peek().map_or(
+                    self.original_string.len() - 1,
+                    tokenizer::MathToken::position,
+                );
+                return Err(self.error(
+                    format!("expected '=' after '{name}' in where clause"),
+                    pos,
+                    pos,
+                ));
+            };
+            bindings.push((name, self.parse_inner_func()?));
+            if !matches!(self.peek(), Some(tokenizer::MathToken::Delim(_))) {
+                return Ok(bindings);
+            }
             self.pop();
-            exprs.push(self.parse_expression_chain_single()?);
         }
+    }
 
-        if !self.tokens.is_empty() {
-            let idx = self.tokens.remove(0).position();
-            let msg = util::error_message(&self.original_string, idx, idx);
-            return Err(anyhow!("unexpected sequence{msg}"));
+    // Long operator chains such as `1+1+...+1` are parsed iteratively, but still produce a deep tree
+    fn check_depth(&self, ops: &ops::MathOp) -> Result<()> {
+        if ops.depth() > self.max_depth {
+            return Err(MathJitError::TooDeeplyNested { span: None }.into());
         }
+        Ok(())
+    }
 
+    pub fn parse(&mut self) -> Result<Vec<ParseOutput>> {
+        let mut exprs = self.parse_expression_chain_single()?;
+        while matches!(self.peek(), Some(tokenizer::MathToken::Chain(_))) {
+            self.pop();
+            exprs.extend(self.parse_expression_chain_single()?);
+        }
+
+        self.expect_end()?;
         Ok(exprs)
     }
 
+    /// Parses the input like `parse`, then sorts it into definitions, constants and expressions to evaluate.
+    pub fn parse_program(&mut self) -> Result<Program> {
+        let mut program = Program::default();
+        for output in self.parse()? {
+            match output {
+                ParseOutput::Body(body) => program.queries.push(body),
+                ParseOutput::Functions(funcs) => program.definitions.extend(funcs),
+                ParseOutput::Constant { name, value } => program.constants.push((name, value)),
+            }
+        }
+        Ok(program)
+    }
+
+    fn expect_end(&self) -> Result<()> {
+        if let Some(tok) = self.peek() {
+            let idx = tok.position();
+            return Err(self.error("unexpected trailing tokens", idx, idx));
+        }
+        Ok(())
+    }
+
     fn parse_full_func(&mut self) -> Result<Option<ParseOutput>> {
         if let Some(tokenizer::MathToken::Id(_, name)) = self.peek() {
             let name = name.to_string();
@@ -305,7 +947,13 @@ impl Parser {
             if let Some(tokenizer::MathToken::Open(_)) = self.peek() {
                 let mut args = vec![];
                 self.pop();
-                while let Some(tokenizer::MathToken::Id(_, arg_name)) = self.peek() {
+                // A trailing comma is allowed, `f(x,) = x` is the same as `f(x) = x`. Duplicates are only reported
+                // once the `=` shows this is a definition, as `f(e, e)` is a valid call
+                let mut duplicate = None;
+                while let Some(tokenizer::MathToken::Id(pos, arg_name)) = self.peek() {
+                    if duplicate.is_none() && args.contains(arg_name) {
+                        duplicate = Some((*pos, *arg_name));
+                    }
                     args.push(*arg_name);
                     self.pop();
                     match self.peek() {
@@ -325,7 +973,18 @@ impl Parser {
                     self.pop();
                     if let Some(tokenizer::MathToken::Eq(_)) = self.peek() {
                         self.pop();
-                        let inner_func = self.parse_inner_func()?;
+                        if let Some((pos, arg_name)) = duplicate {
+                            return Err(self.error(
+                                format!("duplicate parameter '{arg_name}' in function {name}"),
+                                pos,
+                                pos,
+                            ));
+                        }
+                        self.bound_args.clone_from(&args);
+                        let inner_func = self.parse_inner_func();
+                        self.bound_args.clear();
+                        let inner_func = inner_func?;
+                        self.check_depth(&inner_func)?;
                         let func = Function {
                             name,
                             args,
@@ -352,14 +1011,309 @@ impl Display for Parser {
                 tokenizer::MathToken::Exp(_) => " ^ ".to_string(),
                 tokenizer::MathToken::Open(_) => "(".to_string(),
                 tokenizer::MathToken::Close(_) => ")".to_string(),
+                tokenizer::MathToken::ListOpen(_) => "[".to_string(),
+                tokenizer::MathToken::ListClose(_) => "]".to_string(),
                 tokenizer::MathToken::Id(_, x) => x.to_string(),
                 tokenizer::MathToken::Delim(_) => ", ".to_string(),
                 tokenizer::MathToken::Eq(_) => " = ".to_string(),
                 tokenizer::MathToken::Num(_, x) => format!("{x}"),
                 tokenizer::MathToken::Chain(_) => " & ".to_string(),
+                tokenizer::MathToken::Percent(_) => "%".to_string(),
+                tokenizer::MathToken::Cmp(_, op) => format!(" {op} "),
+                tokenizer::MathToken::Arrow(_) => " -> ".to_string(),
+                tokenizer::MathToken::At(_) => " @ ".to_string(),
+                tokenizer::MathToken::CasesOpen(_) => "{ ".to_string(),
+                tokenizer::MathToken::CasesClose(_) => " }".to_string(),
+                tokenizer::MathToken::FloorOpen(_) => "⌊".to_string(),
+                tokenizer::MathToken::FloorClose(_) => "⌋".to_string(),
+                tokenizer::MathToken::CeilOpen(_) => "⌈".to_string(),
+                tokenizer::MathToken::CeilClose(_) => "⌉".to_string(),
             });
         }
 
         write!(f, "{}", out_buf.trim())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::with_main_stack;
+
+    // The expression as parsed, with every operation in brackets
+    fn shape(input: &str) -> String {
+        match Parser::new(input).unwrap().parse().unwrap().remove(0) {
+            ParseOutput::Body(body) => body.to_string(),
+            other => panic!("expected an expression, got {other:?}"),
+        }
+    }
+
+    fn definition(input: &str) -> String {
+        match Parser::new(input).unwrap().parse().unwrap().remove(0) {
+            ParseOutput::Functions(funcs) => funcs[0].to_string(),
+            other => panic!("expected a definition, got {other:?}"),
+        }
+    }
+
+    // The error and its causes, one per line
+    fn error(input: &str) -> String {
+        let err = Parser::new(input).unwrap().parse().unwrap_err();
+        err.chain()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn arity_errors_name_the_expected_arguments() {
+        let err = Parser::new("1 + sum(1, 2)").unwrap().parse().unwrap_err();
+        assert!(err.to_string().starts_with(
+            "incorrect argument count for 'sum' call, 2 provided, sum expects (start, stop, step)"
+        ));
+        // The arguments are underlined, as they are what's wrong
+        assert_eq!(error_span("1 + sum(1, 2)"), "(1, 2)");
+    }
+
+    #[test]
+    fn points_in_square_brackets_are_a_list_of_calls() {
+        assert_eq!(shape("f[1, 2 + 1]"), "[f(1), f((2 + 1))]");
+        assert_eq!(shape("sin[0, pi()] * 2"), "([sin(0), sin(pi())] * 2)");
+        let err = Parser::new("hypot[1, 2]").unwrap().parse().unwrap_err();
+        assert!(err.to_string().starts_with(
+            "'hypot' can't be evaluated at a list of points, as it doesn't take a single argument"
+        ));
+        assert_eq!(error_span("hypot[1, 2]"), "hypot[1, 2]");
+    }
+
+    #[test]
+    fn programs_sort_a_chain_into_buckets() {
+        let program = Parser::new("f(x) = x^2 & c = 3 & f(c) & g(x, y) = x * y & g(1, 2) + 1")
+            .unwrap()
+            .parse_program()
+            .unwrap();
+        let definitions: Vec<_> = program
+            .definitions
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(definitions, ["f(x) = (x ^ 2)", "g(x, y) = (x * y)"]);
+        let constants: Vec<_> = program
+            .constants
+            .iter()
+            .map(|(name, value)| format!("{name} = {value}"))
+            .collect();
+        assert_eq!(constants, ["c = 3"]);
+        let queries: Vec<_> = program.queries.iter().map(ToString::to_string).collect();
+        assert_eq!(queries, ["f(c)", "(g(1, 2) + 1)"]);
+        assert!(Parser::new("2 +").unwrap().parse_program().is_err());
+    }
+
+    #[test]
+    fn unary_intrinsics_take_the_next_primary() {
+        assert_eq!(shape("sin 0"), "sin(0)");
+        assert_eq!(shape("sqrt 16 + 1"), "(sqrt(16) + 1)");
+        assert_eq!(shape("sqrt 16^2"), "(sqrt(16) ^ 2)");
+        assert_eq!(shape("cos (x + 1)"), "cos((x + 1))");
+        assert!(Parser::new("sqrt -4").unwrap().parse().is_err());
+        assert!(Parser::new("hypot 1").unwrap().parse().is_err());
+    }
+
+    #[test]
+    fn rounding_brackets_are_calls() {
+        assert_eq!(shape("⌊2.9⌋"), "floor(2.9)");
+        assert_eq!(shape("⌈x + 1⌉ * 2"), "(ceil((x + 1)) * 2)");
+        assert!(Parser::new("⌊2.9⌉").unwrap().parse().is_err());
+    }
+
+    #[test]
+    fn signed_exponents_bind_to_the_exponent() {
+        assert_eq!(shape("2^-3"), "(2 ^ (-3))");
+        assert_eq!(shape("2^+2"), "(2 ^ 2)");
+        assert_eq!(shape("2^(1/2)"), "(2 ^ (1 / 2))");
+        assert_eq!(shape("-2^2"), "(-(2 ^ 2))");
+    }
+
+    #[test]
+    fn percent_only_scales_the_literal_it_follows() {
+        assert_eq!(shape("50%"), "0.5");
+        assert_eq!(shape("200 + 10%"), "(200 + 0.1)");
+        assert_eq!(shape("200 * 10%"), "(200 * 0.1)");
+        assert!(Parser::new("(50)%").unwrap().parse().is_err());
+    }
+
+    #[test]
+    fn piecewise_cases_keep_their_order() {
+        assert_eq!(
+            definition("f(x) = { x^2 if x >= 0, -x otherwise }"),
+            "f(x) = { (x ^ 2) if (x >= 0), (-x) otherwise }"
+        );
+        assert!(Parser::new("{ 1 otherwise, 2 if 1 > 0 }")
+            .unwrap()
+            .parse()
+            .is_err());
+    }
+
+    #[test]
+    fn trailing_tokens_are_reported() {
+        assert_eq!(error_span("2 + 3 4"), "4");
+        assert_eq!(error_span("5 )"), ")");
+    }
+
+    #[test]
+    fn bare_constants_are_calls() {
+        assert_eq!(shape("2*pi"), "(2 * pi())");
+        assert_eq!(shape("e^2"), "(e() ^ 2)");
+        // A parameter of the same name shadows the constant
+        assert_eq!(definition("f(e) = e + 1"), "f(e) = (e + 1)");
+    }
+
+    #[test]
+    fn deep_nesting_is_a_clean_error() {
+        with_main_stack(deep_nesting);
+    }
+
+    fn deep_nesting() {
+        let too_deep = |input: &str| {
+            let err = Parser::new(input).unwrap().parse().unwrap_err();
+            matches!(
+                err.downcast_ref(),
+                Some(MathJitError::TooDeeplyNested { .. })
+            )
+        };
+        let brackets = |n| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        assert!(too_deep(&brackets(1_000)));
+        assert!(too_deep(&format!("{}1", "-".repeat(1_000))));
+        assert!(Parser::new(&brackets(100)).unwrap().parse().is_ok());
+        let mut parser = Parser::new(&brackets(10)).unwrap();
+        parser.set_max_depth(5);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn duplicate_parameters_are_rejected() {
+        let err = error("f(x, x) = x");
+        assert!(err.starts_with("duplicate parameter 'x' in function f"));
+        // The second `x` is highlighted
+        assert!(err.contains("f(x, \x1b[31mx\x1b[0m) = x"));
+        assert_eq!(definition("f(x, y) = x"), "f(x, y) = x");
+    }
+
+    #[test]
+    fn trailing_commas_are_allowed() {
+        assert_eq!(definition("f(x, y,) = x + y"), "f(x, y) = (x + y)");
+        assert_eq!(shape("max(1, 2,)"), shape("max(1, 2)"));
+        assert_eq!(shape("(1, 2,)"), shape("(1, 2)"));
+        // Only one, and not in place of an argument
+        assert!(Parser::new("max(1, 2,,)").unwrap().parse().is_err());
+        assert!(Parser::new("max(,)").unwrap().parse().is_err());
+        assert!(Parser::new("f(,) = 1").unwrap().parse().is_err());
+    }
+
+    #[test]
+    fn lambdas_are_the_first_argument() {
+        assert_eq!(shape("sum(x -> x^2, 1, 3)"), "sum((x -> (x ^ 2)), 1, 3)");
+        assert!(Parser::new("sum(1, x -> x, 3)").unwrap().parse().is_err());
+    }
+
+    #[test]
+    fn double_star_is_an_exponent() {
+        assert_eq!(shape("2**10"), shape("2^10"));
+        assert_eq!(shape("2**-1"), shape("2^-1"));
+        // Only when the stars are next to each other
+        assert!(Parser::new("2 * *3").unwrap().parse().is_err());
+    }
+
+    #[test]
+    fn unused_parameters_are_found() {
+        let unused = |input| match Parser::new(input).unwrap().parse().unwrap().remove(0) {
+            ParseOutput::Functions(funcs) => funcs[0].unused_args(),
+            other => panic!("expected a definition, got {other:?}"),
+        };
+        assert_eq!(unused("f(x, y) = x"), ['y']);
+        assert_eq!(unused("f(x, y, z) = y"), ['x', 'z']);
+        assert!(unused("f(x, y) = x * sum(y -> y, 1, 2) + y").is_empty());
+    }
+
+    #[test]
+    fn where_substitutes_each_binding() {
+        assert_eq!(shape("x^2 + 1 where x = 3"), "((3 ^ 2) + 1)");
+        assert_eq!(shape("x * y where x = y + 1, y = 2"), "((2 + 1) * 2)");
+        assert!(Parser::new("x + 1 where 3").unwrap().parse().is_err());
+        assert!(Parser::new("x + 1 where x").unwrap().parse().is_err());
+    }
+
+    #[test]
+    fn negation_binds_more_loosely_than_exponents_only() {
+        assert_eq!(shape("-2^2"), "(-(2 ^ 2))");
+        assert_eq!(shape("-(2^2)"), "(-(2 ^ 2))");
+        assert_eq!(shape("(-2)^2"), "((-2) ^ 2)");
+        assert_eq!(shape("2^-2"), "(2 ^ (-2))");
+        assert_eq!(shape("2*-3+1"), "((2 * (-3)) + 1)");
+        assert_eq!(shape("--2"), "(-(-2))");
+    }
+
+    #[test]
+    fn adjacent_operands_are_multiplied() {
+        assert_eq!(shape("(1+2)3"), "((1 + 2) * 3)");
+        assert_eq!(shape("(1)(2)"), "(1 * 2)");
+        assert_eq!(definition("f(x) = 2x"), "f(x) = (2 * x)");
+        assert_eq!(shape("2sqrt(4)"), "(2 * sqrt(4))");
+        assert_eq!(shape("sqrt(4)(2)"), "(sqrt(4) * 2)");
+        // A call's own brackets aren't multiplied by its name
+        assert_eq!(shape("max(1, 2)"), "max(1, 2)");
+    }
+
+    #[test]
+    fn implied_multiplication_associates_like_explicit() {
+        for (implied, explicit) in [
+            ("2(3)^2", "2*3^2"),
+            ("8/2(2)", "8/2*2"),
+            ("2(3)(4)", "2*3*4"),
+            ("-2(3)", "-2*3"),
+            ("1+2(3)", "1+2*3"),
+        ] {
+            assert_eq!(shape(implied), shape(explicit), "{implied}");
+        }
+    }
+
+    #[test]
+    fn zero_before_x_isnt_a_hex_prefix() {
+        assert_eq!(definition("f(x)=0x+1"), definition("f(x)=0*x+1"));
+    }
+
+    #[test]
+    fn sample_points_call_the_definition() {
+        let outputs = Parser::new("g(x, y) = x*y @ 2, 3")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let [ParseOutput::Functions(funcs), ParseOutput::Body(call)] = &outputs[..] else {
+            panic!("expected a definition and a call, got {outputs:?}");
+        };
+        assert_eq!(funcs[0].to_string(), "g(x, y) = (x * y)");
+        assert_eq!(call.to_string(), "g(2, 3)");
+        assert_eq!(error_span("f(x, y) = x @ 1"), "@");
+    }
+
+    #[test]
+    fn display_brackets_every_operation() {
+        assert_eq!(shape("2+3*4"), "(2 + (3 * 4))");
+        assert_eq!(shape("2^3^2"), "((2 ^ 3) ^ 2)");
+    }
+
+    // The part of the input a parse error is reported against, which is highlighted in red
+    fn error_span(input: &str) -> String {
+        let err = error(input);
+        let start = err.find("\x1b[31m").unwrap() + "\x1b[31m".len();
+        let end = start + err[start..].find("\x1b[0m").unwrap();
+        err[start..end].to_string()
+    }
+
+    #[test]
+    fn error_spans_cover_rounding_brackets() {
+        assert_eq!(error_span("2 ⌊"), "⌊");
+        assert_eq!(error_span("2 ⌋"), "⌋");
+        assert_eq!(error_span("2 ⌈"), "⌈");
+        assert_eq!(error_span("1 + ⌉"), "⌉");
+    }
+}
@@ -0,0 +1,12 @@
+// Helpers for tests, shared by the unit tests and, through tests/common, the integration tests
+
+// Test threads get a 2 MiB stack, which unoptimised builds overrun before reaching the depth limits, so tests
+// of those limits get the stack the CLI's main thread would have
+pub fn with_main_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    std::thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(f)
+        .unwrap()
+        .join()
+        .unwrap()
+}
@@ -1,7 +1,75 @@
-use std::time::Instant;
+use std::{fmt::Display, str::FromStr, time::Instant};
 
+use anyhow::anyhow;
 use comfy_table::Table;
 
+#[derive(Debug, Clone, Copy)]
+pub enum TimeUnit {
+    Nanos,
+    Micros,
+    Millis,
+    Secs,
+    // Picks whichever of the other units suits each row
+    Auto,
+}
+
+impl TimeUnit {
+    fn label(self) -> &'static str {
+        match self {
+            TimeUnit::Nanos => "ns",
+            TimeUnit::Micros => "us",
+            TimeUnit::Millis => "ms",
+            TimeUnit::Secs => "s",
+            TimeUnit::Auto => "auto",
+        }
+    }
+
+    // Converts a time in milliseconds to this unit, returning the unit actually used
+    fn scale(self, ms: f64) -> (f64, TimeUnit) {
+        match self {
+            TimeUnit::Nanos => (ms * 1_000_000.0, self),
+            TimeUnit::Micros => (ms * 1_000.0, self),
+            TimeUnit::Millis => (ms, self),
+            TimeUnit::Secs => (ms / 1_000.0, self),
+            TimeUnit::Auto if ms < 0.001 => TimeUnit::Nanos.scale(ms),
+            TimeUnit::Auto if ms < 1.0 => TimeUnit::Micros.scale(ms),
+            TimeUnit::Auto if ms < 1_000.0 => TimeUnit::Millis.scale(ms),
+            TimeUnit::Auto => TimeUnit::Secs.scale(ms),
+        }
+    }
+
+    fn format(self, ms: f64) -> String {
+        let (time, unit) = self.scale(ms);
+        match self {
+            TimeUnit::Auto => format!("{time:.4} {}", unit.label()),
+            _ => format!("{time:.4}"),
+        }
+    }
+}
+
+impl Display for TimeUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.label())
+    }
+}
+
+impl FromStr for TimeUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ns" => Ok(TimeUnit::Nanos),
+            "us" => Ok(TimeUnit::Micros),
+            "ms" => Ok(TimeUnit::Millis),
+            "s" => Ok(TimeUnit::Secs),
+            "auto" => Ok(TimeUnit::Auto),
+            _ => Err(anyhow!(
+                "invalid time unit, wanted 'ns', 'us', 'ms', 's' or 'auto'"
+            )),
+        }
+    }
+}
+
 pub struct Timings {
     points: Vec<(String, f64)>,
     last: Instant,
@@ -22,34 +90,175 @@ impl Timings {
         self.points.push((label.to_string(), taken));
     }
 
+    // Restarts the current lap without recording the time since the last one
+    pub fn skip(&mut self) {
+        self.last = Instant::now();
+    }
+
+    // Adds the laps of `other`, which ran within this lap, as `{prefix}/{label}`. When `other` recorded nothing,
+    // the time since this lap began is recorded as `prefix` instead. Either way the current lap then starts
+    // after `other` finished, so no time is counted twice
     pub fn append(&mut self, other: Self, prefix: &str) {
         if other.points.is_empty() {
             self.lap(prefix);
+            return;
         }
 
         for (label, time) in other.points {
             self.points.push((format!("{prefix}/{label}"), time));
         }
+        self.last = self.last.max(other.last);
     }
 
-    pub fn report(&self) -> String {
-        let total = self.points.iter().map(|x| x.1).sum::<f64>();
+    pub fn total(&self) -> f64 {
+        self.points.iter().map(|x| x.1).sum::<f64>()
+    }
+
+    pub fn report(&self, unit: TimeUnit) -> String {
+        let total = self.total();
         let mut table = Table::new();
-        table.set_header(vec!["Category", "Time (MS)", "%"]);
+        let time_header = match unit {
+            TimeUnit::Auto => "Time".to_string(),
+            _ => format!("Time ({})", unit.label().to_uppercase()),
+        };
+        table.set_header(vec!["Category", &time_header, "%"]);
         for (label, time) in &self.points {
             table.add_row(vec![
                 label.to_string(),
-                format!("{time:.4}"),
+                unit.format(*time),
                 format!("{:.4}", time * 100.0 / total),
             ]);
         }
 
         table.add_row(vec![
             "Total".to_string(),
-            format!("{total:.4}"),
+            unit.format(total),
             "100%".to_string(),
         ]);
 
         table.to_string()
     }
 }
+
+/// Lays out the laps of several runs side by side, one column per run, so back-ends can be compared on the
+/// same expression. Laps with the same label are added together, and a lap missing from a run is shown as `-`.
+pub fn side_by_side(runs: &[(&str, &Timings)], unit: TimeUnit) -> String {
+    let mut labels: Vec<&str> = vec![];
+    for (_, timings) in runs {
+        for (label, _) in &timings.points {
+            if !labels.contains(&label.as_str()) {
+                labels.push(label);
+            }
+        }
+    }
+
+    let mut table = Table::new();
+    let mut header = vec!["Category".to_string()];
+    header.extend(runs.iter().map(|(name, _)| match unit {
+        TimeUnit::Auto => name.to_string(),
+        _ => format!("{name} ({})", unit.label().to_uppercase()),
+    }));
+    table.set_header(header);
+    for label in labels {
+        let mut row = vec![label.to_string()];
+        row.extend(runs.iter().map(|(_, timings)| {
+            let laps = timings.points.iter().filter(|x| x.0 == label);
+            match laps.map(|x| x.1).reduce(|a, b| a + b) {
+                Some(time) => unit.format(time),
+                None => "-".to_string(),
+            }
+        }));
+        table.add_row(row);
+    }
+    let mut total = vec!["Total".to_string()];
+    total.extend(runs.iter().map(|(_, timings)| unit.format(timings.total())));
+    table.add_row(total);
+
+    table.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn units_scale_from_milliseconds() {
+        assert_eq!(TimeUnit::Nanos.format(1.5), "1500000.0000");
+        assert_eq!(TimeUnit::Micros.format(1.5), "1500.0000");
+        assert_eq!(TimeUnit::Millis.format(1.5), "1.5000");
+        assert_eq!(TimeUnit::Secs.format(1.5), "0.0015");
+        // Auto picks a unit per value, so names it
+        assert_eq!(TimeUnit::Auto.format(0.0005), "500.0000 ns");
+        assert_eq!(TimeUnit::Auto.format(0.5), "500.0000 us");
+        assert_eq!(TimeUnit::Auto.format(2_500.0), "2.5000 s");
+    }
+
+    #[test]
+    fn report_header_names_the_unit() {
+        let timings = Timings {
+            points: vec![("Parse".to_string(), 0.25)],
+            last: Instant::now(),
+        };
+        let report = timings.report(TimeUnit::Nanos);
+        assert!(report.contains("Time (NS)"));
+        assert!(report.contains("250000.0000"));
+        assert!(!timings.report(TimeUnit::Auto).contains("Time ("));
+    }
+
+    #[test]
+    fn appended_time_is_counted_once() {
+        let start = Instant::now();
+        let mut outer = Timings {
+            points: vec![("Parse".to_string(), 1.0)],
+            last: start,
+        };
+        let inner = Timings {
+            points: vec![("Codegen".to_string(), 2.0), ("Exec".to_string(), 3.0)],
+            last: start + Duration::from_millis(5),
+        };
+        outer.append(inner, "JIT");
+        let labels: Vec<_> = outer.points.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(labels, ["Parse", "JIT/Codegen", "JIT/Exec"]);
+        assert_eq!(outer.total(), 6.0);
+        // The next lap starts where the appended timings left off
+        assert_eq!(outer.last, start + Duration::from_millis(5));
+
+        // Empty timings record the time since then under the prefix, and nothing else
+        std::thread::sleep(Duration::from_millis(10));
+        outer.append(Timings::start(), "Interpreter");
+        assert_eq!(outer.points.len(), 4);
+        assert_eq!(outer.points[3].0, "Interpreter");
+        let since_appended = start.elapsed().as_secs_f64() * 1000.0 - 5.0;
+        assert!(outer.points[3].1 > 0.0 && outer.points[3].1 <= since_appended + 1e-9);
+    }
+
+    #[test]
+    fn runs_side_by_side() {
+        let timings = |points: &[(&str, f64)]| Timings {
+            points: points.iter().map(|(x, t)| (x.to_string(), *t)).collect(),
+            last: Instant::now(),
+        };
+        let interpreter = timings(&[("Parse", 1.0), ("Exec", 2.0), ("Exec", 3.0)]);
+        let jit = timings(&[("Parse", 1.0), ("Codegen", 4.0), ("Exec", 0.5)]);
+        let table = side_by_side(
+            &[("Interpreter", &interpreter), ("JIT", &jit)],
+            TimeUnit::Millis,
+        );
+        let row = |label: &str| {
+            let line = table
+                .lines()
+                .find(|x| x.starts_with(&format!("| {label} ")));
+            line.unwrap()
+                .split('|')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<_>>()
+        };
+        assert!(table.contains("Interpreter (MS)") && table.contains("JIT (MS)"));
+        assert_eq!(row("Exec"), ["Exec", "5.0000", "0.5000"]);
+        assert_eq!(row("Codegen"), ["Codegen", "-", "4.0000"]);
+        assert_eq!(row("Total"), ["Total", "6.0000", "5.5000"]);
+    }
+}
@@ -1,6 +1,11 @@
+use std::sync::Arc;
+
 use anyhow::{anyhow, Result};
 
-use crate::util;
+use crate::{
+    error::MathJitError,
+    ops::{CmpOp, Span},
+};
 
 #[derive(Debug, Clone)]
 pub enum MathToken {
@@ -10,31 +15,216 @@ pub enum MathToken {
     Mul(usize),
     Open(usize),
     Close(usize),
+    ListOpen(usize),
+    ListClose(usize),
     Exp(usize),
     Num(usize, f64),
     Id(usize, char),
     Delim(usize),
     Eq(usize),
     Chain(usize),
+    Percent(usize),
+    Cmp(usize, CmpOp),
+    Arrow(usize),
+    At(usize),
+    CasesOpen(usize),
+    CasesClose(usize),
+    // `⌊x⌋` and `⌈x⌉`, shorthand for `floor(x)` and `ceil(x)`
+    FloorOpen(usize),
+    FloorClose(usize),
+    CeilOpen(usize),
+    CeilClose(usize),
+}
+
+// `0x` only starts a hex literal when a digit follows, perhaps after a '.', so that `0x` in `f(x)=0x+1` is
+// zero times `x`
+fn starts_hex_literal(input: &str) -> bool {
+    let Some(digits) = input
+        .strip_prefix("0x")
+        .or_else(|| input.strip_prefix("0X"))
+    else {
+        return false;
+    };
+    let digits = digits.strip_prefix('.').unwrap_or(digits);
+    digits.starts_with(|x: char| x.is_ascii_hexdigit())
+}
+
+// Length of the hex literal at the start of `input`, such as `0x1.8p3`, including anything which looks like
+// part of it so that a malformed literal is reported as a whole
+fn hex_literal_len(input: &str) -> usize {
+    let bytes = input.as_bytes();
+    let mut len = 2;
+    while len < bytes.len() && (bytes[len].is_ascii_hexdigit() || bytes[len] == b'.') {
+        len += 1;
+    }
+    if len < bytes.len() && (bytes[len] == b'p' || bytes[len] == b'P') {
+        len += 1;
+        if len < bytes.len() && (bytes[len] == b'+' || bytes[len] == b'-') {
+            len += 1;
+        }
+        while len < bytes.len() && bytes[len].is_ascii_digit() {
+            len += 1;
+        }
+    }
+    len
+}
+
+// Length of the decimal literal at the start of `input`. Only ASCII digits are taken, as other numeric
+// characters such as '²' can't be parsed and are better reported on their own. Stray '.'s are included so
+// that `1..2` is reported as a whole
+fn decimal_literal_len(input: &str) -> usize {
+    input
+        .bytes()
+        .take_while(|x| x.is_ascii_digit() || *x == b'.')
+        .count()
+}
+
+// C-style hex literals, `0x1.8p3` is 1.5 * 2^3. `f64::from_str` doesn't accept these. A fraction needs a
+// binary exponent, as in C, while a whole number such as `0xff` may leave it out
+fn parse_hex_float(literal: &str) -> Result<f64> {
+    let digits = &literal[2..];
+    let (mantissa, exponent) = match digits.find(['p', 'P']) {
+        Some(index) => (&digits[..index], Some(&digits[index + 1..])),
+        None => (digits, None),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return Err(anyhow!("hex literal '{literal}' has no digits"));
+    }
+    if fraction.contains('.') {
+        return Err(anyhow!("hex literal '{literal}' has more than one '.'"));
+    }
+    if mantissa.contains('.') && exponent.is_none() {
+        return Err(anyhow!(
+            "hex float literal '{literal}' needs a 'p' exponent, such as 0x1.8p3"
+        ));
+    }
+    let exponent = match exponent {
+        Some(exponent) => exponent.parse::<i32>().map_err(|_| {
+            anyhow!("hex float literal '{literal}' needs a decimal power of two after 'p'")
+        })?,
+        None => 0,
+    };
+
+    let mantissa = whole
+        .chars()
+        .chain(fraction.chars())
+        .fold(0.0, |value, digit| {
+            value * 16.0 + f64::from(digit.to_digit(16).unwrap())
+        });
+    let fraction_bits = i32::try_from(fraction.len() * 4).unwrap_or(i32::MAX);
+    Ok(mantissa * 2f64.powi(exponent.saturating_sub(fraction_bits)))
+}
+
+// Characters which are commonly typed (or pasted) in place of one MathJIT understands
+fn suggestion(chr: char) -> Option<&'static str> {
+    Some(match chr {
+        '×' | '·' | '∙' => "did you mean '*'?",
+        '÷' => "did you mean '/'?",
+        '−' | '–' | '—' => "did you mean '-'?",
+        ':' => "did you mean '=' to define a function?",
+        ';' => "did you mean '&' to chain expressions?",
+        '“' | '”' | '‘' | '’' | '"' | '\'' => {
+            "quotes are not needed, did you mean '(' or ')'?"
+        }
+        '≤' => "did you mean '<='?",
+        '≥' => "did you mean '>='?",
+        '≠' => "did you mean '!='?",
+        '√' => "did you mean 'sqrt(...)'?",
+        'π' => "did you mean 'pi'?",
+        '²' => "did you mean '^2'?",
+        '³' => "did you mean '^3'?",
+        _ => return None,
+    })
+}
+
+fn tokenize_error(input: &str, message: impl ToString, start: usize, end: usize) -> anyhow::Error {
+    MathJitError::Tokenize {
+        message: message.to_string(),
+        span: Span {
+            source: Arc::from(input),
+            start,
+            end,
+        },
+    }
+    .into()
 }
 
+// Words the parser treats as keywords, a number may be followed by one without implying multiplication
+const KEYWORDS: [&str; 3] = ["if", "otherwise", "where"];
+
 impl MathToken {
     pub fn try_new(mut input: String) -> Result<Vec<MathToken>> {
         let mut tokens = vec![];
         let original_size = input.len();
         let original_input = input.clone();
         while !input.is_empty() {
-            let mut current = input.chars().next().unwrap();
+            let current = input.chars().next().unwrap();
             let current_idx = original_size - input.len();
             if current == ' ' {
                 input.remove(0);
                 continue;
             }
 
-            if current == '(' && matches!(tokens.last(), Some(MathToken::Num(_, _))) {
+            if input.starts_with("/*") {
+                let Some(end) = input[2..].find("*/") else {
+                    return Err(tokenize_error(
+                        &original_input,
+                        "block comment is never closed",
+                        current_idx,
+                        current_idx + 1,
+                    ));
+                };
+                input.drain(..end + 4);
+                continue;
+            }
+
+            // Multiplication is implied between adjacent operands, `2(3)`, `(1+2)3`, `(1)(2)`, `2x` and `50%(4)`.
+            // The parser sees an ordinary `*`, so `2(3)^2` is `2*(3^2)`
+            let opens = matches!(current, '(' | '⌊' | '⌈');
+            let implied_mul = match tokens.last() {
+                Some(MathToken::Num(_, _) | MathToken::Percent(_)) => {
+                    opens
+                        || (current.is_ascii_alphabetic()
+                            && !KEYWORDS.iter().any(|x| input.starts_with(x)))
+                }
+                Some(MathToken::Close(_) | MathToken::FloorClose(_) | MathToken::CeilClose(_)) => {
+                    opens || current.is_ascii_digit() || current == '.'
+                }
+                _ => false,
+            };
+            if implied_mul {
                 tokens.push(MathToken::Mul(current_idx));
             }
 
+            if input.starts_with("->") {
+                input.drain(..2);
+                tokens.push(MathToken::Arrow(current_idx));
+                continue;
+            }
+
+            // Python-style exponent, only when the two stars are adjacent
+            if input.starts_with("**") {
+                input.drain(..2);
+                tokens.push(MathToken::Exp(current_idx));
+                continue;
+            }
+
+            let comparison = match (current, input.chars().nth(1)) {
+                ('<', Some('=')) => Some((CmpOp::Le, 2)),
+                ('>', Some('=')) => Some((CmpOp::Ge, 2)),
+                ('=', Some('=')) => Some((CmpOp::Eq, 2)),
+                ('!', Some('=')) => Some((CmpOp::Ne, 2)),
+                ('<', _) => Some((CmpOp::Lt, 1)),
+                ('>', _) => Some((CmpOp::Gt, 1)),
+                _ => None,
+            };
+            if let Some((op, len)) = comparison {
+                input.drain(..len);
+                tokens.push(MathToken::Cmp(current_idx, op));
+                continue;
+            }
+
             if let Some(trivial) = match current {
                 '+' => Some(MathToken::Add(current_idx)),
                 '-' => Some(MathToken::Sub(current_idx)),
@@ -43,9 +233,19 @@ impl MathToken {
                 '^' => Some(MathToken::Exp(current_idx)),
                 '(' => Some(MathToken::Open(current_idx)),
                 ')' => Some(MathToken::Close(current_idx)),
+                '[' => Some(MathToken::ListOpen(current_idx)),
+                ']' => Some(MathToken::ListClose(current_idx)),
+                '{' => Some(MathToken::CasesOpen(current_idx)),
+                '}' => Some(MathToken::CasesClose(current_idx)),
+                '⌊' => Some(MathToken::FloorOpen(current_idx)),
+                '⌋' => Some(MathToken::FloorClose(current_idx)),
+                '⌈' => Some(MathToken::CeilOpen(current_idx)),
+                '⌉' => Some(MathToken::CeilClose(current_idx)),
                 ',' => Some(MathToken::Delim(current_idx)),
                 '=' => Some(MathToken::Eq(current_idx)),
                 '&' => Some(MathToken::Chain(current_idx)),
+                '%' => Some(MathToken::Percent(current_idx)),
+                '@' => Some(MathToken::At(current_idx)),
                 'A'..='Z' | 'a'..='z' => Some(MathToken::Id(current_idx, current)),
                 _ => None,
             } {
@@ -54,22 +254,132 @@ impl MathToken {
                 continue;
             }
 
-            let mut num_buf = String::new();
-            while !input.is_empty() && (current.is_numeric() || current == '.') {
-                num_buf.push(input.remove(0));
-                if !input.is_empty() {
-                    current = input.chars().next().unwrap();
+            if starts_hex_literal(&input) {
+                let literal = input.drain(..hex_literal_len(&input)).collect::<String>();
+                let end_idx = current_idx + literal.len() - 1;
+                match parse_hex_float(&literal) {
+                    Ok(num) => tokens.push(MathToken::Num(current_idx, num)),
+                    Err(e) => {
+                        return Err(tokenize_error(&original_input, e, current_idx, end_idx));
+                    }
                 }
-            }
-            if let Ok(num) = num_buf.parse() {
-                tokens.push(MathToken::Num(current_idx, num));
                 continue;
             }
-            let error = util::error_message(&original_input, current_idx, current_idx);
-            return Err(anyhow!("unexpected token: '{}'", current).context(error));
+
+            // Every branch consumes at least one character or returns, so this always terminates
+            let len = decimal_literal_len(&input);
+            if len > 0 {
+                let literal = input.drain(..len).collect::<String>();
+                if let Ok(num) = literal.parse() {
+                    tokens.push(MathToken::Num(current_idx, num));
+                    continue;
+                }
+                let message = if literal.bytes().any(|x| x.is_ascii_digit()) {
+                    format!("number '{literal}' has more than one '.'")
+                } else {
+                    format!("'{literal}' is not a number, it has no digits")
+                };
+                return Err(tokenize_error(
+                    &original_input,
+                    message,
+                    current_idx,
+                    current_idx + len - 1,
+                ));
+            }
+            let end_idx = current_idx + current.len_utf8() - 1;
+            let message = match suggestion(current) {
+                Some(hint) => format!("unexpected token: '{current}', {hint}"),
+                None => format!("unexpected token: '{current}'"),
+            };
+            return Err(tokenize_error(
+                &original_input,
+                message,
+                current_idx,
+                end_idx,
+            ));
         }
         Ok(tokens)
     }
+
+    /// Splits file input into the lines to evaluate. A block comment which spans several lines is
+    /// kept with the line it starts on, and lines holding nothing but comments are dropped.
+    pub fn source_lines(source: &str) -> Vec<(usize, String)> {
+        let mut lines = vec![];
+        let mut current: Option<(usize, String)> = None;
+        let mut in_comment = false;
+        let mut has_content = false;
+        for (index, line) in source.lines().enumerate() {
+            let (_, buf) = current.get_or_insert_with(|| (index + 1, String::new()));
+            if !buf.is_empty() {
+                buf.push('\n');
+            }
+            buf.push_str(line);
+
+            let mut rest = line;
+            loop {
+                if in_comment {
+                    let Some(end) = rest.find("*/") else {
+                        break;
+                    };
+                    in_comment = false;
+                    rest = &rest[end + 2..];
+                } else if let Some(start) = rest.find("/*") {
+                    has_content |= !rest[..start].trim().is_empty();
+                    in_comment = true;
+                    rest = &rest[start + 2..];
+                } else {
+                    has_content |= !rest.trim().is_empty();
+                    break;
+                }
+            }
+
+            if !in_comment {
+                let (line_number, buf) = current.take().unwrap();
+                if has_content {
+                    lines.push((line_number, buf.trim().to_string()));
+                }
+                has_content = false;
+            }
+        }
+
+        // An unterminated comment is kept, so that tokenizing it reports the error
+        if let Some((line_number, buf)) = current {
+            lines.push((line_number, buf.trim().to_string()));
+        }
+        lines
+    }
+
+    /// Splits input on the `&` between chained expressions, so that each can be parsed on its own and an error
+    /// in one doesn't stop the others. An `&` in brackets or a block comment doesn't split the input.
+    pub fn chain_segments(source: &str) -> Vec<&str> {
+        let mut segments = vec![];
+        let mut start = 0;
+        let mut depth = 0i32;
+        let mut chars = source.char_indices().peekable();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '/' if chars.peek().is_some_and(|x| x.1 == '*') => {
+                    // An unterminated comment runs to the end, and is reported when it is tokenized
+                    let Some(end) = source[index + 2..].find("*/") else {
+                        break;
+                    };
+                    while chars.peek().is_some_and(|x| x.0 < index + 2 + end + 2) {
+                        chars.next();
+                    }
+                }
+                '(' | '[' | '{' | '⌊' | '⌈' => depth += 1,
+                ')' | ']' | '}' | '⌋' | '⌉' => depth -= 1,
+                '&' if depth <= 0 => {
+                    segments.push(&source[start..index]);
+                    start = index + c.len_utf8();
+                }
+                _ => {}
+            }
+        }
+        segments.push(&source[start..]);
+        segments
+    }
+
     pub fn position(&self) -> usize {
         *match self {
             MathToken::Add(x)
@@ -79,11 +389,143 @@ impl MathToken {
             | MathToken::Exp(x)
             | MathToken::Open(x)
             | MathToken::Close(x)
+            | MathToken::ListOpen(x)
+            | MathToken::ListClose(x)
+            | MathToken::CasesOpen(x)
+            | MathToken::CasesClose(x)
+            | MathToken::FloorOpen(x)
+            | MathToken::FloorClose(x)
+            | MathToken::CeilOpen(x)
+            | MathToken::CeilClose(x)
+            | MathToken::Cmp(x, _)
+            | MathToken::Arrow(x)
+            | MathToken::At(x)
             | MathToken::Id(x, _)
             | MathToken::Delim(x)
             | MathToken::Eq(x)
             | MathToken::Num(x, _)
-            | MathToken::Chain(x) => x,
+            | MathToken::Chain(x)
+            | MathToken::Percent(x) => x,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_count(input: &str) -> usize {
+        MathToken::try_new(input.to_string()).unwrap().len()
+    }
+
+    fn error(input: &str) -> String {
+        MathToken::try_new(input.to_string())
+            .unwrap_err()
+            .root_cause()
+            .to_string()
+    }
+
+    #[test]
+    fn confusable_characters_get_a_suggestion() {
+        assert!(error("2 × 3").starts_with("unexpected token: '×', did you mean '*'?"));
+        assert!(error("f(x): x")
+            .starts_with("unexpected token: ':', did you mean '=' to define a function?"));
+        assert!(error("√2").starts_with("unexpected token: '√', did you mean 'sqrt(...)'?"));
+        assert!(error("2 $ 3").starts_with("unexpected token: '$'\n"));
+    }
+
+    #[test]
+    fn block_comments_are_skipped() {
+        assert_eq!(token_count("2 /* twice */ * 3"), 3);
+        // Neither the `*` nor the `/` either side of a comment are part of it
+        assert_eq!(token_count("2*/*c*/3"), 3);
+        assert_eq!(token_count("6/*c*//2"), 3);
+        assert_eq!(token_count("1 /* a\nb */ + 2"), 3);
+    }
+
+    #[test]
+    fn block_comments_span_lines() {
+        let lines = MathToken::source_lines("1 + /* a\nb */ 2\n/* only\na comment */\n3");
+        let lines: Vec<_> = lines.iter().map(|(n, x)| (*n, x.as_str())).collect();
+        assert_eq!(lines, [(1, "1 + /* a\nb */ 2"), (5, "3")]);
+    }
+
+    #[test]
+    fn unterminated_block_comments_are_reported() {
+        let err = MathToken::try_new("1 + /* 2".to_string()).unwrap_err();
+        // The opening `/*` is highlighted
+        assert_eq!(
+            err.to_string(),
+            "block comment is never closed\n1 + \x1b[31m/*\x1b[0m 2\n    ^^"
+        );
+    }
+
+    #[test]
+    fn hex_float_literals() {
+        assert_eq!(parse_hex_float("0x1.8p3").unwrap(), 12.0);
+        assert_eq!(parse_hex_float("0xff").unwrap(), 255.0);
+        assert_eq!(parse_hex_float("0X.8P1").unwrap(), 1.0);
+        assert_eq!(parse_hex_float("0x1p-2").unwrap(), 0.25);
+        let tokens = MathToken::try_new("0x1.8p3+1".to_string()).unwrap();
+        assert!(matches!(tokens[0], MathToken::Num(0, x) if x == 12.0));
+        assert_eq!(tokens.len(), 3);
+    }
+
+    #[test]
+    fn malformed_hex_floats_are_reported_whole() {
+        assert!(error("0x1.8").starts_with("hex float literal '0x1.8' needs a 'p' exponent"));
+        assert!(error("0x1.8p").starts_with("hex float literal '0x1.8p' needs a decimal power"));
+        assert!(error("0x1.8.1p2").starts_with("hex literal '0x1.8.1p2' has more than one '.'"));
+        assert!(parse_hex_float("0x")
+            .unwrap_err()
+            .to_string()
+            .starts_with("hex literal '0x' has no digits"));
+    }
+
+    #[test]
+    fn hex_prefix_needs_a_digit() {
+        // Without one, `0x` is zero followed by the variable `x`
+        let tokens = MathToken::try_new("0x+1".to_string()).unwrap();
+        assert!(matches!(tokens[0], MathToken::Num(0, x) if x == 0.0));
+        // With the multiplication implied between them
+        assert!(matches!(tokens[1], MathToken::Mul(_)));
+        assert!(matches!(tokens[2], MathToken::Id(1, 'x')));
+        assert!(matches!(
+            MathToken::try_new("0x.8p1".to_string()).unwrap()[0],
+            MathToken::Num(0, x) if x == 1.0
+        ));
+    }
+
+    #[test]
+    fn carets_line_up_under_the_bad_character() {
+        let carets = |input| error(input).lines().last().unwrap().to_string();
+        assert_eq!(carets("2 $ 3"), "  ^");
+        // Characters before it which take several bytes still take one column, and tabs are kept
+        assert_eq!(carets("/* π */ 1 $"), "          ^");
+        assert_eq!(carets("/*\t*/ $"), "  \t   ^");
+        assert_eq!(carets("1..2"), "^^^^");
+    }
+
+    #[test]
+    fn malformed_decimals_are_reported_whole() {
+        for (input, message, literal) in [
+            ("..", "'..' is not a number, it has no digits", ".."),
+            (".", "'.' is not a number, it has no digits", "."),
+            ("2 + .", "'.' is not a number, it has no digits", "."),
+            ("1..2", "number '1..2' has more than one '.'", "1..2"),
+            ("1.2.3 + 1", "number '1.2.3' has more than one '.'", "1.2.3"),
+        ] {
+            let err = error(input);
+            assert!(err.starts_with(message), "{input}: {err}");
+            // The whole literal is highlighted
+            assert!(
+                err.contains(&format!("\x1b[31m{literal}\x1b[0m")),
+                "{input}: {err}"
+            );
         }
+        assert!(matches!(
+            MathToken::try_new(".5 + 1.".to_string()).unwrap()[..],
+            [MathToken::Num(0, a), MathToken::Add(_), MathToken::Num(5, b)] if a == 0.5 && b == 1.0
+        ));
     }
 }
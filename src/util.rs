@@ -1,6 +1,17 @@
 use coloured_strings::colour;
 
+// The input with `start..=end` highlighted, and underlined with carets on the line below so the span can
+// be found without colour
 pub fn error_message(input: &str, start: usize, end: usize) -> String {
+    // Spans are in bytes, so they're widened to whole characters rather than slicing one in half
+    let mut start = start;
+    while !input.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = end.max(start);
+    while end + 1 < input.len() && !input.is_char_boundary(end + 1) {
+        end += 1;
+    }
     let mut indic = String::with_capacity(input.len());
     indic.push_str(&input[..start]);
     let reg = &input[start..=end].to_string();
@@ -8,5 +19,11 @@ pub fn error_message(input: &str, start: usize, end: usize) -> String {
     if input.len() > end {
         indic.push_str(&input[(end + 1)..]);
     }
-    format!("\n{indic}")
+    // Tabs are kept so the carets line up however wide the terminal shows them
+    let padding = input[..start]
+        .chars()
+        .map(|x| if x == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    let carets = "^".repeat(reg.chars().count());
+    format!("\n{indic}\n{padding}{carets}")
 }
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Error;
+
+use crate::{
+    eval::{intrinsic, unbound_variable, undefined_function, wrong_arg_count},
+    ops::MathOp,
+    parser::{self, Function, ParseOutput},
+    tokenizer::MathToken,
+};
+
+/// Parses every line of `source` and checks each call against the intrinsics and the functions
+/// defined anywhere in `source`, without evaluating anything. Every problem found is returned,
+/// rather than stopping at the first.
+pub fn validate(source: &str) -> Vec<Error> {
+    let mut errors = vec![];
+    let mut statements = vec![];
+    for (line_number, line) in MathToken::source_lines(source) {
+        match parser::Parser::new(&line).and_then(|mut x| x.parse()) {
+            Ok(ops) => statements.extend(ops.into_iter().map(|x| (line_number, x))),
+            Err(e) => errors.push((line_number, e)),
+        }
+    }
+
+    // Definitions may come after their first use, so collect them all before checking calls
+    let mut arities = HashMap::new();
+    let mut constants = HashSet::new();
+    for (_, op) in &statements {
+        match op {
+            ParseOutput::Functions(funcs) => {
+                for func in funcs {
+                    arities.insert(func.name.clone(), func.args.len());
+                }
+            }
+            ParseOutput::Constant { name, .. } => {
+                constants.insert(*name);
+            }
+            ParseOutput::Body(_) => {}
+        }
+    }
+
+    for (line, op) in &statements {
+        let funcs = match op {
+            ParseOutput::Body(body) | ParseOutput::Constant { value: body, .. } => vec![Function {
+                name: String::new(),
+                args: vec![],
+                body: body.clone(),
+            }],
+            ParseOutput::Functions(funcs) => funcs.clone(),
+        };
+        for func in &funcs {
+            errors.extend(
+                check_function(func, &arities, &constants)
+                    .into_iter()
+                    .map(|e| (*line, e)),
+            );
+        }
+    }
+
+    errors.sort_by_key(|(line, _)| *line);
+    errors
+        .into_iter()
+        .map(|(line, e)| e.context(format!("on line {line}")))
+        .collect()
+}
+
+fn check_function(
+    func: &Function,
+    arities: &HashMap<String, usize>,
+    constants: &HashSet<char>,
+) -> Vec<Error> {
+    let intrinsics = intrinsic::standard_intrinsics();
+    let mut errors = vec![];
+    let mut pending = vec![&func.body];
+    while let Some(op) = pending.pop() {
+        match op {
+            MathOp::Call { name, args, span } if !intrinsics.contains_key(&name[..]) => {
+                match arities.get(name) {
+                    Some(&expected) if expected != args.len() => {
+                        errors.push(wrong_arg_count(name, "", args.len(), expected, span));
+                    }
+                    Some(_) => {}
+                    None => errors.push(undefined_function(name, &func.name, span)),
+                }
+            }
+            MathOp::Arg(n, span) if !func.args.contains(n) && !constants.contains(n) => {
+                errors.push(unbound_variable(*n, &func.name, span));
+            }
+            // A lambda only sees its own argument, not even constants, so its body is checked separately
+            MathOp::Lambda { arg, body } => {
+                errors.extend(check_function(
+                    &Function {
+                        name: "lambda".to_string(),
+                        args: vec![*arg],
+                        body: *body.clone(),
+                    },
+                    arities,
+                    &HashSet::new(),
+                ));
+                continue;
+            }
+            _ => {}
+        }
+        pending.extend(op.children().into_iter().rev());
+    }
+    errors
+}
@@ -0,0 +1,504 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+fn mathjit(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mathjit"))
+        .args(args)
+        .output()
+        .expect("could not run mathjit")
+}
+
+fn stdout(args: &[&str]) -> String {
+    String::from_utf8(mathjit(args).stdout).unwrap()
+}
+
+// Types `input` into the REPL
+fn repl(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mathjit"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run mathjit");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Tests run in parallel, so each file they use gets its own name
+fn temp_path() -> PathBuf {
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "mathjit-{}-{}.txt",
+        std::process::id(),
+        FILES.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+// Runs mathjit with `flag` pointing at a file holding `contents`
+fn with_file(flag: &str, contents: &str) -> Output {
+    let path = temp_path();
+    std::fs::write(&path, contents).unwrap();
+    let output = mathjit(&[flag, path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn verbose_results_name_their_back_end() {
+    assert_eq!(stdout(&["2+2"]).trim(), "4");
+    // Verbose mode prints the AST and such too, so only the result's own line is looked for
+    let verbose = stdout(&["--verbose", "2+2"]);
+    assert!(verbose.lines().any(|x| x == "[Interpreter] 2 + 2 = 4"));
+    let verbose = stdout(&["--verbose", "-m", "compare", "2+2"]);
+    assert!(verbose.lines().any(|x| x == "[Interpreter, JIT] 2 + 2 = 4"));
+}
+
+#[test]
+fn file_errors_are_reported_and_the_rest_still_runs() {
+    let output = with_file("-f", "1+1\n2 $ 3\nf(1)\n2*3\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2\n6\n");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("on line 3"));
+    assert!(!output.status.success());
+}
+
+#[test]
+fn chained_errors_only_stop_their_own_segment() {
+    let output = mathjit(&["2+2 & 3+3 & @bad"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "4\n6\n");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Parser error"));
+}
+
+#[test]
+fn explain_shows_the_grouping_of_the_last_expression() {
+    let output = String::from_utf8(repl(&[], "2+3*4\n:explain\n").stdout).unwrap();
+    assert!(output.lines().any(|x| x == "(2 + (3 * 4))"));
+}
+
+#[test]
+fn validate_reports_every_bad_line() {
+    let output = with_file(
+        "--validate",
+        "f(x) = g(x) + 1\ng(x) = x * 2\nh(x) = k(x)\nf(1, 2)\n",
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("on line 3"));
+    assert!(stderr.contains("on line 4"));
+    assert!(stderr.contains("2 error(s) found"));
+    assert!(!output.status.success());
+    // Nothing is evaluated, so the verdict is all that is printed
+    let output = with_file("--validate", "f(x) = x\nf(2)\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 1);
+    assert!(stdout.ends_with(" is valid\n"));
+    assert!(output.status.success());
+}
+
+#[test]
+fn round_display_tidies_near_integers() {
+    let rounded = |expr| stdout(&["--round-display", "15", expr]).trim().to_string();
+    assert_eq!(rounded("0.1+0.2"), "0.3");
+    assert_eq!(rounded("sqrt(2)^2"), "2");
+    assert_eq!(rounded("1/3"), "0.333333333333333");
+    assert_eq!(stdout(&["sqrt(2)^2"]).trim(), "2.0000000000000004");
+    assert!(!mathjit(&["--round-display", "0", "1"]).status.success());
+}
+
+#[test]
+fn unbound_variables_in_the_repl_are_an_error() {
+    let output = repl(&[], "x+1\n2+2\n");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("unknown variable 'x'"));
+    // The REPL carries on to the next line
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .any(|x| x == "4"));
+    assert!(output.status.success());
+}
+
+#[test]
+fn verbose_interpreter_traces_calls() {
+    let expr = "f(x) = x + 1 & g(x) = f(x) * 2 & g(3)";
+    let verbose = stdout(&["--verbose", expr]);
+    // Each call is printed once it returns, so the inner call comes first, indented
+    let trace: Vec<_> = verbose
+        .lines()
+        .skip_while(|x| *x != "  f(3) = 4")
+        .take(2)
+        .collect();
+    assert_eq!(trace, ["  f(3) = 4", "g(3) = 8"]);
+    assert!(!stdout(&[expr]).contains("f(3) = 4"));
+}
+
+#[test]
+fn blank_lines_are_skipped() {
+    let output = with_file("-f", "1+1\n\n   \n2*3\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2\n6\n");
+    assert!(output.stderr.is_empty());
+    assert!(output.status.success());
+    let output = repl(&[], "\n  \n2+2\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn unused_parameters_are_warned_about() {
+    let warning = "Warning: parameter 'y' of f is never used";
+    let output = mathjit(&["f(x, y) = x"]);
+    assert!(String::from_utf8(output.stderr).unwrap().contains(warning));
+    assert!(mathjit(&["--allow-unused", "f(x, y) = x"])
+        .stderr
+        .is_empty());
+}
+
+#[test]
+fn list_intrinsics_shows_the_builtins() {
+    let output = mathjit(&["--list-intrinsics"]);
+    let listed = String::from_utf8(output.stdout).unwrap();
+    let names: Vec<_> = listed
+        .lines()
+        .filter_map(|x| x.strip_prefix("| "))
+        .filter_map(|x| x.split_whitespace().next())
+        .collect();
+    for name in ["sqrt", "sin", "cos", "pi", "e", "sum", "hypot"] {
+        assert!(names.contains(&name), "{name} isn't listed");
+    }
+    assert!(listed
+        .lines()
+        .any(|x| x.starts_with("| sum ") && x.contains("| (start, stop, step) |")));
+    assert!(output.status.success());
+}
+
+#[test]
+fn redefinitions_are_noted() {
+    let expr = "f(x) = x & g(x) = x & f(x) = 2*x";
+    assert_eq!(stdout(&[expr]), "Ok\nOk\nredefined f(x)\nOk\n");
+    assert_eq!(stdout(&["--quiet", expr]), "Ok\nOk\nOk\n");
+}
+
+#[test]
+fn emit_dot_writes_a_graph() {
+    let path = temp_path();
+    let output = mathjit(&[
+        "--emit-dot",
+        path.to_str().unwrap(),
+        "f(x) = x + 1 & f(2) * 3",
+    ]);
+    let dot = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    assert!(dot.starts_with("digraph ast {\n"));
+    assert!(dot.trim_end().ends_with('}'));
+    assert!(dot.contains("[label=\"f(x) =\"];"));
+    assert!(dot.contains("[label=\"f()\"];"));
+}
+
+#[test]
+fn output_writes_results_to_a_file() {
+    let (input, results) = (temp_path(), temp_path());
+    std::fs::write(&input, "1+1\n2 $ 3\n3*3\n").unwrap();
+    let output = mathjit(&[
+        "-f",
+        input.to_str().unwrap(),
+        "-o",
+        results.to_str().unwrap(),
+    ]);
+    let written = std::fs::read_to_string(&results).unwrap();
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&results).unwrap();
+    assert_eq!(written, "2\n9\n");
+    // Errors still go to stderr, and nothing else is printed
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("unexpected token: '$'"));
+    // Definitions are acknowledged in the same place as results
+    let output = mathjit(&[
+        "-o",
+        results.to_str().unwrap(),
+        "f(x) = x & f(x) = 2*x & f(2)",
+    ]);
+    let written = std::fs::read_to_string(&results).unwrap();
+    std::fs::remove_file(&results).unwrap();
+    assert_eq!(written, "Ok\nredefined f(x)\nOk\n4\n");
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn reset_command_forgets_definitions() {
+    let output = repl(&[], "f(x) = x\n:reset\nf(1)\n");
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .any(|x| x == "Environment reset"));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("function f is not defined"));
+}
+
+#[test]
+fn strict_jit_warns_about_nan() {
+    let warning = "Warning: result is NaN";
+    let output = mathjit(&["--strict", "-m", "jit", "sqrt(-1)"]);
+    assert!(String::from_utf8(output.stderr).unwrap().contains(warning));
+    let output = mathjit(&["-m", "jit", "sqrt(-1)"]);
+    assert!(!String::from_utf8(output.stderr).unwrap().contains(warning));
+}
+
+#[test]
+fn folded_constants_show_in_the_ir() {
+    let ir = |fold: bool| {
+        let mut args = vec!["-m", "jit", "--verbose", "--no-passes"];
+        if fold {
+            args.push("--fold");
+        }
+        args.push("f(x) = x * 2^3 & f(1)");
+        String::from_utf8(mathjit(&args).stderr).unwrap()
+    };
+    let (unfolded, folded) = (ir(false), ir(true));
+    assert!(unfolded.contains("@llvm.pow.f64"));
+    assert!(!folded.contains("@llvm.pow.f64"));
+    assert!(folded.contains("8.000000e+00"));
+}
+
+#[test]
+fn explained_ir_names_instructions_after_their_source() {
+    let ir = |explain: bool| {
+        let mut args = vec!["-m", "jit", "--verbose", "--no-passes"];
+        if explain {
+            args.push("--explain-ir");
+        }
+        args.push("f(x) = (x + 1) * 2 & f(2)");
+        String::from_utf8(mathjit(&args).stderr).unwrap()
+    };
+    let (plain, explained) = (ir(false), ir(true));
+    assert!(explained.contains("%\"add:(x + 1)\" = fadd"));
+    assert!(explained.contains("%\"mul:((x + 1) * 2)\" = fmul"));
+    assert!(!plain.contains("add:"));
+}
+
+#[test]
+fn assembly_for_another_target() {
+    // Registers only the chosen target has, so its assembly can't be mistaken for the host's
+    let (triple, register) = if cfg!(target_arch = "aarch64") {
+        ("x86_64-unknown-linux-gnu", "%xmm0")
+    } else {
+        ("aarch64-unknown-linux-gnu", "d0, d0")
+    };
+    let output = stdout(&[
+        "-m",
+        "jit",
+        "--verbose",
+        "--target",
+        triple,
+        "f(x) = x * 3 & f(2)",
+    ]);
+    let assembly = output
+        .split("--- Assembly ---")
+        .nth(1)
+        .and_then(|x| x.split("--- Code size ---").next())
+        .unwrap();
+    assert!(assembly.contains(register));
+    // It still runs on the host
+    assert!(output.lines().any(|x| x.ends_with("= 6")));
+    let output = mathjit(&["-m", "jit", "--verbose", "--target", "not-a-target", "1"]);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("unsupported target triple 'not-a-target'"));
+}
+
+#[test]
+fn bigger_functions_have_more_code() {
+    // The size reported for `f` in verbose mode
+    let code_size = |definition: &str| -> usize {
+        let output = stdout(&["-m", "jit", "--verbose", &format!("{definition} & f(1)")]);
+        let sizes = output.split("--- Code size ---").nth(1).unwrap();
+        sizes
+            .lines()
+            .find_map(|x| x.strip_prefix("f: "))
+            .and_then(|x| x.strip_suffix(" bytes"))
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    let small = code_size("f(x) = x");
+    let large = code_size("f(x) = sin(x) * cos(x)^3 + sqrt(x * 7) / (x + 2) - hypot(x, 3)");
+    assert!(large > small, "{large} bytes isn't more than {small}");
+}
+
+#[test]
+fn check_opt_is_quiet_when_optimization_keeps_the_result() {
+    // Reassociating to `(x - x) + 1` would give 1, but without fast-math LLVM mustn't
+    let output = mathjit(&["-m", "jit", "--check-opt", "f(x) = (x + 1) - x & f(10^16)"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Ok\n0\n");
+    assert!(!String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("optimization changed the result"));
+}
+
+#[test]
+fn json_results() {
+    let json = |expr| stdout(&["--json-result", expr]);
+    assert_eq!(json("2+2"), "{\"ok\": true, \"value\": 4.0}\n");
+    assert_eq!(json("f(x) = x"), "{\"ok\": true, \"value\": null}\n");
+    assert_eq!(json("[1, 2]"), "{\"ok\": true, \"value\": [1.0, 2.0]}\n");
+    // JSON has no infinity
+    assert_eq!(json("1/0"), "{\"ok\": true, \"value\": null}\n");
+    // Errors go to stdout too, with newlines escaped so each result is a single line
+    let output = mathjit(&["--json-result", "2 $ 3"]);
+    let error = String::from_utf8(output.stdout).unwrap();
+    assert!(error.starts_with("{\"ok\": false, \"error\": \"unexpected token: '$'\\n"));
+    assert_eq!(error.lines().count(), 1);
+    assert!(output.stderr.is_empty());
+    // Records for errors and definitions follow the results into the --output file
+    let (input, results) = (temp_path(), temp_path());
+    std::fs::write(&input, "f(x) = x + 1\ng(1)\nf(1)\n").unwrap();
+    let output = mathjit(&[
+        "--json-result",
+        "-f",
+        input.to_str().unwrap(),
+        "-o",
+        results.to_str().unwrap(),
+    ]);
+    let written = std::fs::read_to_string(&results).unwrap();
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&results).unwrap();
+    let records = written.lines().collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0], "{\"ok\": true, \"value\": null}");
+    assert!(
+        records[1].starts_with("{\"ok\": false, \"error\": \"on line 2: function g is not defined")
+    );
+    assert_eq!(records[2], "{\"ok\": true, \"value\": 2.0}");
+    assert!(output.stdout.is_empty() && output.stderr.is_empty());
+}
+
+#[test]
+fn sample_points_print_and_keep_the_definition() {
+    assert_eq!(stdout(&["f(x) = x^2 @ 3 & f(4)"]), "Ok\n9\n16\n");
+}
+
+#[test]
+fn whole_numbers_in_another_base() {
+    let in_base = |base, expr| stdout(&["--base", base, expr]);
+    assert_eq!(in_base("16", "255"), "0xFF\n");
+    assert_eq!(in_base("2", "255"), "0b11111111\n");
+    assert_eq!(in_base("16", "0 - 255"), "-0xFF\n");
+    assert_eq!(in_base("3", "9"), "100\n");
+    let output = mathjit(&["--base", "16", "5/2"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2.5\n");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("2.5 isn't a whole number, so it is shown in decimal"));
+}
+
+#[test]
+fn constants_are_loaded_from_a_file() {
+    let with_constants = |contents: &str, expr| {
+        let path = temp_path();
+        std::fs::write(&path, contents).unwrap();
+        let output = mathjit(&["--constants", path.to_str().unwrap(), expr]);
+        std::fs::remove_file(&path).unwrap();
+        output
+    };
+    let output = with_constants(
+        "g = 2.5\n\n/* later ones can use earlier */ c = 3 & h = 2 * c\n",
+        "g * h",
+    );
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "15\n");
+
+    // Anything but a constant is refused, with the line it is on
+    for contents in ["g = 2.5\n1 + 1\n", "g = 2.5\nk = 2 +\n"] {
+        let output = with_constants(contents, "g");
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.starts_with("Constants error:\non line 2 of "),
+            "{stderr}"
+        );
+    }
+}
+
+#[test]
+fn rational_results() {
+    let rational = |expr| stdout(&["--rational", expr]);
+    assert_eq!(rational("1/3"), "1/3\n");
+    assert_eq!(rational("0 - 2/3"), "-2/3\n");
+    assert_eq!(rational("3"), "3\n");
+    assert_eq!(rational("[1/2, 0.25]"), "[1/2, 1/4]\n");
+    // Irrational numbers, and denominators over the bound, are left in decimal
+    assert_eq!(rational("sqrt(2)"), "1.4142135623730951\n");
+    assert_eq!(rational("pi()"), "3.141592653589793\n");
+    assert_eq!(rational("1/10001"), "0.00009999000099990002\n");
+}
+
+#[test]
+fn show_command_prints_definitions() {
+    let output = repl(
+        &[],
+        "f(x, y) = x^2 + y & g(x) = x\n:show f\n:show\n:show h\n",
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let f = "f(x, y) = ((x ^ 2) + y)  (2 parameters)";
+    // Once for `:show f`, and again with `g` for `:show`
+    assert_eq!(stdout.lines().filter(|x| *x == f).count(), 2);
+    assert!(stdout.lines().any(|x| x == "g(x) = x  (1 parameter)"));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("No function named 'h' is defined"));
+}
+
+#[test]
+fn annotated_results_follow_their_expression() {
+    assert_eq!(
+        stdout(&["--annotate", "2+2 & f(x) = x & f(3)*2"]),
+        "2 + 2 = 4\nOk\nf(3) * 2 = 6\n"
+    );
+    assert_eq!(stdout(&["2+2"]), "4\n");
+}
+
+#[test]
+fn benchmark_all_times_every_back_end() {
+    let output = mathjit(&["--benchmark-all", "f(x) = x^2 & f(3) + 1"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Interpreter") && stdout.contains("JIT"));
+    assert!(stdout.lines().any(|x| x.starts_with("| Total ")));
+    assert!(stdout.ends_with("All back-ends gave 10\n"));
+    assert!(output.status.success());
+}
+
+#[test]
+fn expressions_on_the_command_line_or_stdin_beat_the_environment() {
+    let output = Command::new(env!("CARGO_BIN_EXE_mathjit"))
+        .arg("1 + 1")
+        .env("MATHJIT_EXPR", "2 + 2")
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2\n");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mathjit"))
+        .env("MATHJIT_EXPR", "2 + 2")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"3 + 3\n").unwrap();
+    let stdout = String::from_utf8(child.wait_with_output().unwrap().stdout).unwrap();
+    assert!(stdout.lines().any(|x| x == "6"));
+    assert!(!stdout.lines().any(|x| x == "4"));
+}
@@ -0,0 +1,34 @@
+// Helpers shared by the integration tests, not every test file uses all of them
+#![allow(dead_code)]
+
+use mathjit::{
+    error::MathJitError,
+    eval::{Eval, EvalOptions, Response, Value},
+};
+
+// The unit tests' helpers, such as `with_main_stack`
+#[path = "../../src/test_util.rs"]
+mod test_util;
+pub use test_util::with_main_stack;
+
+pub fn options() -> EvalOptions {
+    EvalOptions::default()
+}
+
+// The value of the last expression in `expr` which gives one
+pub fn last_value<T: Eval>(env: &mut T, expr: &str) -> anyhow::Result<Value> {
+    let mut last = None;
+    for response in mathjit::evaluate_all(env, expr)? {
+        if let Response::Value(value) = response? {
+            last = Some(value);
+        }
+    }
+    Ok(last.expect("nothing was evaluated"))
+}
+
+pub fn error<T: Eval>(expr: &str) -> MathJitError {
+    let err = last_value(&mut T::new(options()), expr).unwrap_err();
+    err.downcast_ref::<MathJitError>()
+        .unwrap_or_else(|| panic!("{expr}: {err} isn't a MathJitError"))
+        .clone()
+}
@@ -0,0 +1,779 @@
+mod common;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use common::{error, last_value, options, with_main_stack};
+use inkwell::values::FloatValue;
+use mathjit::{
+    error::MathJitError,
+    eval::{
+        ast_interpret::AstInterpreter,
+        compare::Compare,
+        intrinsic::{BuiltinFunction, BuiltinProto},
+        llvm::{FunctionGen, Jit},
+        Eval, EvalOptions, Value, DEFAULT_EPSILON,
+    },
+    ops::MathOp,
+    parser::Parser,
+};
+
+fn interpret(expr: &str) -> Value {
+    interpret_with(&options(), expr)
+}
+
+fn interpret_with(options: &EvalOptions, expr: &str) -> Value {
+    last_value(&mut AstInterpreter::new(options.clone()), expr).unwrap()
+}
+
+// Evaluates `expr` with both back-ends, which have to agree
+fn eval(expr: &str) -> f64 {
+    eval_with(&options(), expr)
+}
+
+fn eval_with(options: &EvalOptions, expr: &str) -> f64 {
+    let interpreted = last_value(&mut AstInterpreter::new(options.clone()), expr).unwrap();
+    let compiled = last_value(&mut Jit::new(options.clone()), expr).unwrap();
+    assert!(
+        !interpreted.differs(&compiled, DEFAULT_EPSILON),
+        "{expr}: the interpreter gave {interpreted} but the JIT gave {compiled}"
+    );
+    match interpreted {
+        Value::Scalar(x) => x,
+        value => panic!("{expr}: expected a single number, got {value}"),
+    }
+}
+
+#[test]
+fn signed_exponents() {
+    assert_eq!(eval("2^-3"), 0.125);
+    assert_eq!(eval("2^+2"), 4.0);
+    assert_eq!(eval("2^(1/2)"), 2f64.sqrt());
+    assert_eq!(eval("-2^2"), -4.0);
+}
+
+#[test]
+fn percentages() {
+    assert_eq!(eval("50%"), 0.5);
+    assert_eq!(eval("200 + 10%"), 200.1);
+    assert_eq!(eval("200 * 10%"), 20.0);
+}
+
+#[test]
+fn element_wise_list_arithmetic() {
+    assert_eq!(interpret("[1,2,3] * 2"), Value::List(vec![2.0, 4.0, 6.0]));
+    assert_eq!(interpret("[1,2]+[3,4]"), Value::List(vec![4.0, 6.0]));
+    assert_eq!(interpret("2 - [1,2]"), Value::List(vec![1.0, 0.0]));
+    assert!(matches!(
+        error::<AstInterpreter>("[1,2]+[1,2,3]"),
+        MathJitError::Runtime { .. }
+    ));
+    assert!(matches!(
+        error::<Jit>("[1,2] * 2"),
+        MathJitError::Unsupported { .. }
+    ));
+}
+
+#[test]
+fn functions_at_several_points() {
+    assert_eq!(
+        interpret("f(x) = x^2 & f[1, 2, 3]"),
+        Value::List(vec![1.0, 4.0, 9.0])
+    );
+    assert_eq!(interpret("f(x) = x + 1 & f[0] * 2"), Value::List(vec![2.0]));
+    assert!(matches!(
+        error::<AstInterpreter>("f(x, y) = x & f[1, 2]"),
+        MathJitError::Arity { .. }
+    ));
+}
+
+#[test]
+fn piecewise_cases() {
+    let f = "f(x) = { x^2 if x >= 0, -x otherwise }";
+    assert_eq!(eval(&format!("{f} & f(3)")), 9.0);
+    assert_eq!(eval(&format!("{f} & f(-2)")), 2.0);
+    // Without an `otherwise`, a value matching no case is NaN
+    let g = "g(x) = { 1 if x < 0, 2 if x == 0 }";
+    assert_eq!(eval(&format!("{g} & g(0)")), 2.0);
+    assert!(eval(&format!("{g} & g(1)")).is_nan());
+    // The recursive call is only made when its case matches
+    assert_eq!(
+        eval("h(n) = { 1 if n <= 1, n * h(n - 1) otherwise } & h(5)"),
+        120.0
+    );
+}
+
+#[test]
+fn bare_constants() {
+    assert_eq!(eval("2*pi"), std::f64::consts::TAU);
+    assert_eq!(eval("e^2"), std::f64::consts::E.powi(2));
+    assert_eq!(eval("f(e) = e + 1 & f(2)"), 3.0);
+}
+
+#[test]
+fn kahan_summation_recovers_lost_precision() {
+    let expr = "f(x) = 0.1 & sum(1, 100000, 1)";
+    assert_ne!(eval(expr), 10000.0);
+    let kahan = EvalOptions {
+        kahan: true,
+        ..options()
+    };
+    assert_eq!(eval_with(&kahan, expr), 10000.0);
+}
+
+#[test]
+fn sum_runs_the_same_iterations_in_both_back_ends() {
+    // Adding 0.1 ten times doesn't reach 1 exactly, so the count is worked out up front instead
+    assert_eq!(eval("f(x) = 1 & sum(0, 1, 0.1)"), 11.0);
+    assert!((eval("f(x) = x & sum(0, 1, 0.1)") - 5.5).abs() < 1e-12);
+    let exclusive = EvalOptions {
+        sum_exclusive: true,
+        ..options()
+    };
+    assert_eq!(eval_with(&exclusive, "f(x) = 1 & sum(0, 1, 0.1)"), 10.0);
+}
+
+#[test]
+fn forward_references() {
+    assert_eq!(eval("f(x) = g(x) + 1 & g(x) = x * 2 & f(2)"), 5.0);
+    let unsatisfied = "f(x) = g(x) + 1 & f(2)";
+    for err in [
+        error::<AstInterpreter>(unsatisfied),
+        error::<Jit>(unsatisfied),
+    ] {
+        let MathJitError::UnknownFunction { name, message, .. } = err else {
+            panic!("{unsatisfied}: {err}");
+        };
+        assert_eq!(name, "g");
+        assert_eq!(message, "function g referenced by f is not defined");
+    }
+}
+
+#[test]
+fn errors_can_be_told_apart() {
+    // Both back-ends report the same kind of error for the same mistake
+    for err in [error::<AstInterpreter>, error::<Jit>].map(|error| {
+        [
+            error("2 $ 3"),
+            error("2 +"),
+            error("(1 + (2 +))"),
+            error("1 + sqrt(1, 2)"),
+            error("f(x) = x + y & f(1)"),
+            error("1 + g(2)"),
+        ]
+    }) {
+        assert!(matches!(err[0], MathJitError::Tokenize { .. }));
+        assert!(matches!(err[1], MathJitError::Parse { .. }));
+        // Through the context added for the brackets
+        assert!(matches!(err[2], MathJitError::Parse { .. }));
+        assert!(matches!(err[3], MathJitError::Arity { provided: 2, .. }));
+        assert!(matches!(
+            err[4],
+            MathJitError::UnknownVariable { name: 'y', .. }
+        ));
+        assert!(matches!(err[5], MathJitError::UnknownFunction { .. }));
+    }
+    let strict = EvalOptions {
+        strict: true,
+        ..options()
+    };
+    let err = last_value(&mut AstInterpreter::new(strict), "sqrt(-1)").unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(MathJitError::Runtime { .. })
+    ));
+}
+
+#[test]
+fn errors_point_at_the_call_responsible() {
+    let span = |err: &MathJitError| {
+        let span = err.span().unwrap();
+        span.source[span.start..=span.end].to_string()
+    };
+    let undefined = "1 + sqrt(4) * g(2)";
+    assert_eq!(span(&error::<AstInterpreter>(undefined)), "g(2)");
+    assert_eq!(span(&error::<Jit>(undefined)), "g(2)");
+    assert_eq!(
+        span(&error::<AstInterpreter>("f(x) = x & 2 * f(1, 2)")),
+        "f(1, 2)"
+    );
+}
+
+#[test]
+fn wrong_argument_counts() {
+    let expr = "f(x) = x & 2 * f(1, 2)";
+    for err in [
+        error::<AstInterpreter>(expr),
+        error::<Jit>(expr),
+        error::<Compare>(expr),
+    ] {
+        let MathJitError::Arity {
+            name,
+            provided,
+            span,
+            ..
+        } = err
+        else {
+            panic!("{expr}: {err}");
+        };
+        assert_eq!((&name[..], provided), ("f", 2));
+        assert_eq!(&span.source[span.start..=span.end], "f(1, 2)");
+    }
+    // The JIT compiles every case, including one the interpreter never reaches
+    let untaken = "f(x) = x & { 1 if 1 > 0, f(1, 2) otherwise }";
+    assert!(matches!(error::<Jit>(untaken), MathJitError::Arity { .. }));
+    assert_eq!(
+        last_value(&mut Compare::new(options()), untaken).unwrap(),
+        Value::Scalar(1.0)
+    );
+}
+
+#[test]
+fn runaway_recursion_is_a_clean_error() {
+    let err = with_main_stack(|| error::<AstInterpreter>("f(x) = f(x) + 1 & f(1)"));
+    assert!(matches!(err, MathJitError::Runtime { .. }));
+}
+
+#[test]
+fn epsilon_decides_when_results_differ() {
+    // Back-ends which disagree in the twelfth digit, as reordered floating point arithmetic can
+    let interpreted = Value::Scalar(0.1 + 0.2);
+    let compiled = Value::Scalar((0.1 + 0.2) * (1.0 + 1e-12));
+    assert!(!interpreted.differs(&compiled, DEFAULT_EPSILON));
+    assert!(interpreted.differs(&compiled, 1e-14));
+    // The difference is relative, so it means the same for large results
+    let large = Value::Scalar(1e20);
+    assert!(!large.differs(&Value::Scalar(1e20 + 1e8), DEFAULT_EPSILON));
+    assert!(large.differs(&Value::Scalar(1e20 + 1e12), DEFAULT_EPSILON));
+    let list = Value::List(vec![1.0, 2.0]);
+    assert!(list.differs(&Value::List(vec![1.0, 2.0 + 1e-12]), 1e-14));
+    assert!(list.differs(&Value::List(vec![1.0]), DEFAULT_EPSILON));
+}
+
+#[test]
+fn tuples() {
+    let r = "r(a,b,c) = ((sqrt(b^2-4*a*c)-b)/(2*a), (0-b-sqrt(b^2-4*a*c))/(2*a))";
+    let roots = interpret(&format!("{r} & r(1,-1,-6)"));
+    assert_eq!(roots, Value::Tuple(vec![3.0, -2.0]));
+    assert_eq!(roots.to_string(), "(3, -2)");
+    assert!(matches!(
+        error::<AstInterpreter>(&format!("{r} & r(1,-1,-6) + 1")),
+        MathJitError::Runtime { .. }
+    ));
+    assert!(matches!(
+        error::<Jit>("(1, 2)"),
+        MathJitError::Unsupported { .. }
+    ));
+}
+
+#[test]
+fn divmod_floors_the_quotient() {
+    let divmod = |a, b| interpret(&format!("divmod({a}, {b})"));
+    assert_eq!(divmod(7, 3), Value::Tuple(vec![2.0, 1.0]));
+    // The remainder has the sign of the divisor
+    assert_eq!(divmod(-7, 3), Value::Tuple(vec![-3.0, 2.0]));
+    assert_eq!(divmod(7, -3), Value::Tuple(vec![-3.0, -2.0]));
+    assert_eq!(divmod(-6, 3), Value::Tuple(vec![-2.0, 0.0]));
+    assert!(matches!(
+        error::<AstInterpreter>("divmod(7, 3) + 1"),
+        MathJitError::Runtime { .. }
+    ));
+    assert!(matches!(
+        error::<Jit>("divmod(7, 3)"),
+        MathJitError::Unsupported { .. }
+    ));
+}
+
+struct Double;
+impl BuiltinFunction for Double {
+    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> anyhow::Result<f64> {
+        Ok(args[0] * 2.0)
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
+        let value = fg.cg.build_block(&args[0], fg);
+        fg.cg
+            .builder
+            .build_float_add(value, value, "double")
+            .expect("Failed to add")
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["x"],
+            description: "twice x",
+        }
+    }
+}
+
+#[test]
+fn custom_intrinsics() {
+    for name in ["double", "sqrt"] {
+        let expr = format!("f(x) = {name}(x) + 1 & f(3)");
+        let mut interpreter = AstInterpreter::new(options()).with_intrinsic(name, Box::new(Double));
+        let mut jit = Jit::new(options()).with_intrinsic(name, Box::new(Double));
+        assert_eq!(
+            last_value(&mut interpreter, &expr).unwrap(),
+            Value::Scalar(7.0)
+        );
+        assert_eq!(last_value(&mut jit, &expr).unwrap(), Value::Scalar(7.0));
+    }
+}
+
+#[test]
+fn custom_intrinsic_calls_are_checked() {
+    // The parser only checks the standard intrinsics' arguments
+    let mut jit = Jit::new(options()).with_intrinsic("double", Box::new(Double));
+    let err = last_value(&mut jit, "double(1, 2)").unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(MathJitError::Arity { provided: 2, .. })
+    ));
+    // Nor can it tell that `sum` has been replaced by an intrinsic which doesn't take a lambda
+    let expr = "sum(x -> x, 1, 2)";
+    let mut interpreter = AstInterpreter::new(options()).with_intrinsic("sum", Box::new(Double));
+    let mut jit = Jit::new(options()).with_intrinsic("sum", Box::new(Double));
+    for err in [
+        last_value(&mut interpreter, expr).unwrap_err(),
+        last_value(&mut jit, expr).unwrap_err(),
+    ] {
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(MathJitError::Runtime { .. })
+        ));
+    }
+}
+
+#[test]
+fn sum_needs_a_function_to_sum() {
+    for expr in ["sum(0, 10, 1)", "f(x, y) = x & sum(0, 10, 1)"] {
+        for err in [error::<AstInterpreter>(expr), error::<Jit>(expr)] {
+            assert!(matches!(err, MathJitError::Runtime { .. }), "{expr}: {err}");
+        }
+    }
+    let err = error::<Jit>("f(x, y) = x & sum(0, 10, 1)");
+    assert!(err
+        .to_string()
+        .contains("but f(x, y) doesn't take a single argument"));
+}
+
+#[test]
+fn unbound_variables() {
+    for err in [error::<AstInterpreter>("x+1"), error::<Jit>("x+1")] {
+        let MathJitError::UnknownVariable { name, message, .. } = err else {
+            panic!("x+1: {err}");
+        };
+        assert_eq!(name, 'x');
+        assert_eq!(message, "unknown variable 'x'");
+    }
+    assert_eq!(eval("x = 3 & x + 1"), 4.0);
+}
+
+#[test]
+fn lambdas() {
+    assert_eq!(eval("sum(x -> x^2, 1, 3)"), 14.0);
+    assert_eq!(eval("sum(x -> x, 0, 1, 0.5)"), 1.5);
+    assert_eq!(eval("g(x) = x + 1 & sum(x -> g(x), 1, 3)"), 9.0);
+    assert_eq!(eval("f(x) = x^2 & sum(f, 1, 3)"), 14.0);
+    // A lambda only sees its own argument
+    let expr = "f(a) = sum(x -> x^a, 1, 3) & f(2)";
+    assert!(matches!(
+        error::<AstInterpreter>(expr),
+        MathJitError::UnknownVariable { name: 'a', .. }
+    ));
+    assert!(matches!(
+        error::<Jit>(expr),
+        MathJitError::UnknownVariable { name: 'a', .. }
+    ));
+}
+
+#[test]
+fn double_star_exponents() {
+    assert_eq!(eval("2**10"), 1024.0);
+    assert_eq!(eval("2**10"), eval("2^10"));
+}
+
+#[test]
+fn huge_loops_need_an_override() {
+    let expr = "f(x) = x & sum(0, 10^12, 1)";
+    let MathJitError::Runtime { message, .. } = error::<AstInterpreter>(expr) else {
+        panic!("{expr} should have been refused");
+    };
+    assert!(message.ends_with("pass --allow-huge-loops to run it anyway"));
+    // With the override it runs, until the timeout stops it
+    let allowed = EvalOptions {
+        allow_huge_loops: true,
+        timeout: Some(Duration::from_millis(50)),
+        ..options()
+    };
+    let err = last_value(&mut AstInterpreter::new(allowed), expr).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(MathJitError::TimedOut { .. })
+    ));
+}
+
+#[test]
+fn max_iterations_applies_to_both_back_ends() {
+    let limited = EvalOptions {
+        max_iterations: Some(10),
+        ..options()
+    };
+    // The JIT checks a range which depends on a parameter as the compiled code runs
+    for expr in [
+        "f(x) = x & sum(0, 100, 1)",
+        "f(x) = sum(n -> n, 0, x) & f(100)",
+    ] {
+        for err in [
+            last_value(&mut AstInterpreter::new(limited.clone()), expr).unwrap_err(),
+            last_value(&mut Jit::new(limited.clone()), expr).unwrap_err(),
+        ] {
+            let Some(MathJitError::Runtime { message, .. }) = err.downcast_ref() else {
+                panic!("{expr}: {err}");
+            };
+            assert!(message.contains("more than the --max-iterations limit of 10"));
+        }
+    }
+    assert_eq!(eval_with(&limited, "f(x) = x & sum(0, 9, 1)"), 45.0);
+    assert_eq!(eval_with(&limited, "f(x) = sum(n -> n, 0, x) & f(9)"), 45.0);
+    // A sum in a branch which isn't taken doesn't count
+    let untaken = "f(x) = { sum(n -> n, 0, x) if x < 0, 1 otherwise } & f(100)";
+    assert_eq!(eval_with(&limited, untaken), 1.0);
+}
+
+#[test]
+fn copysign_and_hypot() {
+    assert_eq!(eval("copysign(3, -1)"), -3.0);
+    assert_eq!(eval("copysign(-3, 1)"), 3.0);
+    // The sign of zero counts
+    assert_eq!(eval("copysign(3, 0 * -1)"), -3.0);
+    assert_eq!(eval("hypot(3, 4)"), 5.0);
+    assert_eq!(eval("hypot(-3, 4)"), 5.0);
+    // Squaring the arguments would overflow
+    assert!((eval("hypot(3 * 10^200, 4 * 10^200)") / 5e200 - 1.0).abs() < 1e-12);
+    assert!(Parser::new("hypot(3)").unwrap().parse().is_err());
+}
+
+#[test]
+fn degrees_and_radians() {
+    let pi = std::f64::consts::PI;
+    assert!((eval("degrees(pi())") - 180.0).abs() < 1e-12);
+    assert!((eval("radians(180)") - pi).abs() < 1e-12);
+    assert!((eval("radians(degrees(2))") - 2.0).abs() < 1e-12);
+    assert!((eval("sin(radians(90))") - 1.0).abs() < 1e-12);
+}
+
+#[test]
+fn factorials_report_overflow_when_strict() {
+    assert_eq!(eval("fact(5)"), 120.0);
+    assert!(eval("fact(170)").is_finite());
+    assert_eq!(eval("fact(171)"), f64::INFINITY);
+    assert!(eval("fact(2.5)").is_nan());
+    let strict = EvalOptions {
+        strict: true,
+        ..options()
+    };
+    assert_eq!(
+        interpret_with(&strict, "fact(170)"),
+        Value::Scalar(eval("fact(170)"))
+    );
+    let err = last_value(&mut AstInterpreter::new(strict), "fact(171)").unwrap_err();
+    let Some(MathJitError::Runtime { message, .. }) = err.downcast_ref() else {
+        panic!("{err}");
+    };
+    assert!(message.starts_with("factorial of 171 overflows"));
+}
+
+fn check_disabled(mut env: impl Eval) {
+    let err = last_value(&mut env, "1 + sqrt(4)").unwrap_err();
+    let Some(MathJitError::Disabled { name, span }) = err.downcast_ref() else {
+        panic!("{err}");
+    };
+    assert_eq!(name, "sqrt");
+    assert_eq!(&span.source[span.start..=span.end], "sqrt(4)");
+    assert!(err.to_string().starts_with("function 'sqrt' is disabled"));
+    // A definition can't bring it back in through a call either
+    assert!(last_value(&mut env, "f(x) = sqrt(x) & f(4)").is_err());
+}
+
+#[test]
+fn disabled_intrinsics() {
+    let options = EvalOptions {
+        disabled: vec!["sqrt".to_string()],
+        ..options()
+    };
+    check_disabled(AstInterpreter::new(options.clone()));
+    check_disabled(Jit::new(options.clone()));
+    assert_eq!(eval_with(&options, "sin(0) + 1"), 1.0);
+}
+
+#[test]
+fn where_clauses() {
+    assert_eq!(eval("x^2 + 1 where x = 3"), 10.0);
+    assert_eq!(eval("x * y where x = 3, y = 4"), 12.0);
+    assert!(matches!(
+        error::<AstInterpreter>("x * y where x = 3"),
+        MathJitError::UnknownVariable { name: 'y', .. }
+    ));
+}
+
+#[test]
+fn negation_precedence() {
+    assert_eq!(eval("-2^2"), -4.0);
+    assert_eq!(eval("-(2^2)"), -4.0);
+    assert_eq!(eval("(-2)^2"), 4.0);
+    assert_eq!(eval("2^-2"), 0.25);
+    assert_eq!(eval("2*-3+1"), -5.0);
+}
+
+#[test]
+fn infinity_and_nan() {
+    assert_eq!(eval("inf + 1"), f64::INFINITY);
+    assert_eq!(eval("-inf"), f64::NEG_INFINITY);
+    assert!(eval("inf - inf").is_nan());
+    assert!(eval("nan + 1").is_nan());
+    assert_eq!(eval("nan == nan"), 0.0);
+    assert_eq!(eval("nan != nan"), 1.0);
+    assert_eq!(eval("inf > 10^308"), 1.0);
+}
+
+fn check_reset(mut env: impl Eval) {
+    last_value(&mut env, "f(x) = x + 1 & c = 2 & f(c)").unwrap();
+    env.reset();
+    assert!(env.functions().is_empty());
+    assert!(matches!(
+        last_value(&mut env, "f(1)").unwrap_err().downcast_ref(),
+        Some(MathJitError::UnknownFunction { .. })
+    ));
+    assert!(matches!(
+        last_value(&mut env, "c + 1").unwrap_err().downcast_ref(),
+        Some(MathJitError::UnknownVariable { .. })
+    ));
+    // Registered intrinsics are kept
+    assert_eq!(
+        last_value(&mut env, "double(2)").unwrap(),
+        Value::Scalar(4.0)
+    );
+}
+
+#[test]
+fn reset_forgets_definitions() {
+    check_reset(AstInterpreter::new(options()).with_intrinsic("double", Box::new(Double)));
+    check_reset(Jit::new(options()).with_intrinsic("double", Box::new(Double)));
+}
+
+#[test]
+fn strict_square_roots() {
+    assert!(eval("sqrt(-1)").is_nan());
+    let strict = EvalOptions {
+        strict: true,
+        ..options()
+    };
+    let err = last_value(&mut AstInterpreter::new(strict.clone()), "1 + sqrt(-1)").unwrap_err();
+    let Some(MathJitError::Runtime { message, .. }) = err.downcast_ref() else {
+        panic!("{err}");
+    };
+    assert!(message.starts_with("square root of negative number -1"));
+    assert_eq!(eval_with(&strict, "sqrt(4)"), 2.0);
+}
+
+fn check_timeout(mut env: impl Eval) {
+    // Something the optimizer can't turn into a closed form
+    let expr = "f(x) = sin(x) & sum(0, 10^10, 1)";
+    let err = last_value(&mut env, expr).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(MathJitError::TimedOut { .. })
+    ));
+    // The session carries on afterwards
+    assert_eq!(
+        last_value(&mut env, "f(0) + 1").unwrap(),
+        Value::Scalar(1.0)
+    );
+}
+
+#[test]
+fn slow_expressions_time_out() {
+    let timeout = EvalOptions {
+        allow_huge_loops: true,
+        timeout: Some(Duration::from_millis(50)),
+        ..options()
+    };
+    check_timeout(AstInterpreter::new(timeout.clone()));
+    check_timeout(Jit::new(timeout));
+}
+
+fn check_interrupt(mut env: impl Eval, interrupt: &Arc<AtomicBool>) {
+    let flag = interrupt.clone();
+    let interrupter = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        flag.store(true, Ordering::Relaxed);
+    });
+    // Something the optimizer can't turn into a closed form
+    let err = last_value(&mut env, "sum(x -> sin(x), 0, 10^10, 1)").unwrap_err();
+    interrupter.join().unwrap();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(MathJitError::Interrupted)
+    ));
+    // Whoever set the flag clears it, and the session carries on
+    interrupt.store(false, Ordering::Relaxed);
+    assert_eq!(last_value(&mut env, "1 + 1").unwrap(), Value::Scalar(2.0));
+}
+
+#[test]
+fn long_evaluations_can_be_interrupted() {
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let options = EvalOptions {
+        allow_huge_loops: true,
+        interrupt: Some(interrupt.clone()),
+        ..options()
+    };
+    check_interrupt(AstInterpreter::new(options.clone()), &interrupt);
+    check_interrupt(Jit::new(options), &interrupt);
+}
+
+#[test]
+fn functions_passed_by_name() {
+    let reference: f64 = (0..=31).map(|i| (f64::from(i) * 0.1).sin()).sum();
+    let by_name = eval("sum(sin, 0, pi, 0.1)");
+    assert!(
+        (by_name - reference).abs() < 1e-9,
+        "{by_name} != {reference}"
+    );
+    assert_eq!(by_name, eval("sum(x -> sin(x), 0, pi, 0.1)"));
+    // Only functions of a single argument can be summed
+    assert!(Parser::new("sum(hypot, 1, 3)").unwrap().parse().is_err());
+}
+
+#[test]
+fn optimization_doesnt_reassociate() {
+    let check_opt = EvalOptions {
+        check_opt: true,
+        ..options()
+    };
+    assert_eq!(eval_with(&check_opt, "f(x) = (x + 1) - x & f(10^16)"), 0.0);
+    assert_eq!(eval_with(&check_opt, "f(x) = (x + 1) - x & f(1)"), 1.0);
+}
+
+// Counts how often the interpreter calls it
+struct Tick(Arc<AtomicUsize>);
+impl BuiltinFunction for Tick {
+    fn eval_interpreter(&self, _: &AstInterpreter, args: Vec<f64>) -> anyhow::Result<f64> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Ok(args[0])
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, args: &[MathOp]) -> FloatValue<'b> {
+        fg.cg.build_block(&args[0], fg)
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self(Arc::clone(&self.0)))
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &["x"],
+            description: "x, counting the call",
+        }
+    }
+}
+
+#[test]
+fn constants_are_evaluated_once() {
+    assert_eq!(eval("c = 2 + 3 & c * c"), 25.0);
+    // A redefinition sees the old value
+    assert_eq!(eval("c = 1 & c = c + 1 & c = c + 1 & c"), 3.0);
+    assert_eq!(eval("c = 2 & f(x) = x * c & f(4)"), 8.0);
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let mut env =
+        AstInterpreter::new(options()).with_intrinsic("tick", Box::new(Tick(Arc::clone(&ticks))));
+    assert_eq!(
+        last_value(&mut env, "c = tick(5) & c + c + c").unwrap(),
+        Value::Scalar(15.0)
+    );
+    assert_eq!(ticks.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn fast_skips_multiplication_by_zero() {
+    let fast = EvalOptions {
+        fast: true,
+        allow_huge_loops: true,
+        ..options()
+    };
+    let slow = "0 * sum(x -> sin(x), 0, 10^10, 1)";
+    assert_eq!(interpret_with(&fast, slow), Value::Scalar(0.0));
+    assert_eq!(interpret_with(&fast, "(0/0) * 0"), Value::Scalar(0.0));
+    assert!(matches!(interpret("(0/0) * 0"), Value::Scalar(x) if x.is_nan()));
+
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let count = |options: &EvalOptions, expr| {
+        ticks.store(0, Ordering::Relaxed);
+        let mut env = AstInterpreter::new(options.clone())
+            .with_intrinsic("tick", Box::new(Tick(Arc::clone(&ticks))));
+        last_value(&mut env, expr).unwrap();
+        ticks.load(Ordering::Relaxed)
+    };
+    assert_eq!(count(&fast, "tick(0) * tick(2)"), 1);
+    assert_eq!(count(&fast, "tick(1) * tick(2)"), 2);
+    assert_eq!(count(&options(), "tick(0) * tick(2)"), 2);
+}
+
+#[test]
+fn decimals_only_rejects_fractions() {
+    let whole = EvalOptions {
+        decimals_only: true,
+        ..options()
+    };
+    let error = |expr| {
+        let err = last_value(&mut AstInterpreter::new(whole.clone()), expr).unwrap_err();
+        let Some(MathJitError::Runtime { message, .. }) = err.downcast_ref() else {
+            panic!("{err}");
+        };
+        message.clone()
+    };
+    assert_eq!(interpret_with(&whole, "4/2"), Value::Scalar(2.0));
+    assert_eq!(
+        error("1/2"),
+        "(1 / 2) is 0.5, which isn't a whole number (--decimals-only)"
+    );
+    // Intermediate results count too, even when the final one is whole
+    assert!(error("(1/2) * 2").starts_with("(1 / 2) is 0.5"));
+    assert_eq!(
+        error("1.5 * 2"),
+        "1.5 isn't a whole number (--decimals-only)"
+    );
+}
+
+#[test]
+fn implied_multiplication() {
+    assert_eq!(eval("2(3)^2"), 18.0);
+    assert_eq!(eval("8/2(2)"), 8.0);
+}
+
+#[test]
+fn unary_intrinsics_without_brackets() {
+    assert_eq!(eval("sin 0"), 0.0);
+    assert_eq!(eval("sqrt 16 + 1"), 5.0);
+    assert_eq!(eval("2 * sqrt 9^2"), 18.0);
+}
+
+#[test]
+fn rounding_brackets() {
+    assert_eq!(eval("⌊2.9⌋"), 2.0);
+    assert_eq!(eval("⌈2.1⌉"), 3.0);
+    assert_eq!(eval("⌊-2.1⌋"), -3.0);
+    assert_eq!(eval("⌊2.9⌋"), eval("floor(2.9)"));
+    assert_eq!(eval("2⌈0.5⌉ + ⌊⌈1.5⌉ / 4⌋"), 2.0);
+}
@@ -0,0 +1,274 @@
+mod common;
+
+use common::{last_value, options, with_main_stack};
+use inkwell::values::FloatValue;
+use mathjit::{
+    error::MathJitError,
+    eval::{
+        ast_interpret::AstInterpreter,
+        intrinsic::{BuiltinFunction, BuiltinProto},
+        llvm::{FunctionGen, Jit},
+        Eval, EvalOptions, Response, Value, DEFAULT_EPSILON,
+    },
+    ops::{MathOp, Span},
+    parser::{Function, ParseOutput, Parser},
+};
+
+#[test]
+fn session_timings_accumulate() {
+    let mut jit = Jit::new(options());
+    last_value(&mut jit, "f(x) = x^2 & f(2)").unwrap();
+    let (compile_ms, run_ms) = (jit.compile_ms, jit.run_ms);
+    assert!(compile_ms > 0.0 && run_ms > 0.0);
+    last_value(&mut jit, "f(3) + 1").unwrap();
+    assert!(jit.compile_ms > compile_ms);
+    assert!(jit.run_ms > run_ms);
+}
+
+#[test]
+fn warmup_leaves_the_result_unchanged() {
+    let expr = "f(x) = x^2 + 1 & f(3) * 2";
+    let cold = last_value(&mut Jit::new(options()), expr).unwrap();
+    let warm = EvalOptions {
+        warmup: 5,
+        ..options()
+    };
+    assert_eq!(last_value(&mut Jit::new(warm), expr).unwrap(), cold);
+}
+
+#[test]
+fn batched_definitions_compile_once_each() {
+    // Names are single letters, leaving out the constant `e` and the parameter `x`
+    let definitions = "abcdfghijklmnopqrstu"
+        .chars()
+        .map(|name| format!("{name}(x) = x + 1"))
+        .collect::<Vec<_>>()
+        .join(" & ");
+    let mut jit = Jit::new(options());
+    for op in mathjit::batch_definitions(Parser::new(&definitions).unwrap().parse().unwrap()) {
+        jit.eval(op).unwrap();
+    }
+    assert_eq!(jit.functions_compiled, 0);
+    last_value(&mut jit, "u(1)").unwrap();
+    assert_eq!(jit.functions_compiled, 20);
+}
+
+#[test]
+fn custom_pass_lists() {
+    let expr = "f(x) = { 1 if x <= 1, x * f(x - 1) otherwise } & f(5) + sum(1, 4, 1)";
+    let expected = last_value(&mut Jit::new(options()), expr).unwrap();
+    let with_passes = |passes: &[&str]| {
+        let options = EvalOptions {
+            passes: Some(passes.iter().map(ToString::to_string).collect()),
+            ..options()
+        };
+        last_value(&mut Jit::new(options), expr)
+    };
+    assert_eq!(with_passes(&["mem2reg"]).unwrap(), expected);
+    assert_eq!(with_passes(&[]).unwrap(), expected);
+    let err = with_passes(&["not-a-pass"]).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(MathJitError::Backend { .. })
+    ));
+}
+
+#[test]
+fn redefinitions_invalidate_the_cache() {
+    let mut jit = Jit::new(options());
+    last_value(
+        &mut jit,
+        "f(x) = x + 1 & g(x) = f(x) * 2 & h(x) = x & g(1) + h(1)",
+    )
+    .unwrap();
+    let mut redefine = |definition| {
+        for op in Parser::new(definition).unwrap().parse().unwrap() {
+            jit.eval(op).unwrap();
+        }
+        last_value(&mut jit, "g(1) + h(1)").unwrap();
+        jit.cache_invalidations
+    };
+    // Swapping the operands of `+` doesn't change the function
+    assert_eq!(redefine("f(x) = 1 + x"), 0);
+    assert_eq!(redefine("f(x) = x + 2"), 1);
+    assert_eq!(redefine("f(x) = x + 3"), 2);
+    // Changing the parameters means the callers have to be rebuilt too
+    assert_eq!(redefine("f(x, y) = x + y & g(x) = f(x, 1) * 2"), 4);
+}
+
+#[test]
+fn redefining_one_function_leaves_the_others_compiled() {
+    let mut jit = Jit::new(options());
+    let value = last_value(
+        &mut jit,
+        "f(x) = x + 1 & g(x) = x * 2 & h(x) = f(x) + g(x) & h(3)",
+    );
+    assert_eq!(value.unwrap(), Value::Scalar(10.0));
+    assert_eq!(jit.functions_compiled, 3);
+
+    // Only `g` is rebuilt, `f` and `h` are linked in from their cached modules
+    let value = last_value(&mut jit, "g(x) = x * 3 & h(3)");
+    assert_eq!(value.unwrap(), Value::Scalar(13.0));
+    assert_eq!(jit.functions_compiled, 4);
+    assert_eq!(jit.cache_invalidations, 1);
+}
+
+#[test]
+fn seeded_functions_compile_up_front() {
+    // `f(x) = x * x + 1`, built without parsing a definition
+    let x = || {
+        let span = Span {
+            source: "x".into(),
+            start: 0,
+            end: 0,
+        };
+        Box::new(MathOp::Arg('x', span))
+    };
+    let f = Function {
+        name: "f".to_string(),
+        args: vec!['x'],
+        body: MathOp::Add {
+            lhs: Box::new(MathOp::Mul { lhs: x(), rhs: x() }),
+            rhs: Box::new(MathOp::Num(1.0)),
+        },
+    };
+    let mut jit = Jit::new(options()).with_functions(vec![f.clone()]).unwrap();
+    assert_eq!(jit.functions_compiled, 1);
+    let compiled = last_value(&mut jit, "f(3) * 2").unwrap();
+    assert_eq!(compiled, Value::Scalar(20.0));
+    assert_eq!(jit.functions_compiled, 1);
+
+    let mut interpreter = AstInterpreter::new(options()).with_functions(vec![f]);
+    assert_eq!(last_value(&mut interpreter, "f(3) * 2").unwrap(), compiled);
+}
+
+#[test]
+fn skipping_passes_gives_the_same_result() {
+    let body = (1..=200)
+        .map(|i| format!("sin(x * {i}) * {i}"))
+        .collect::<Vec<_>>()
+        .join(" + ");
+    let expr = format!("f(x) = {body} & f(0.5)");
+    // Timings are too noisy to compare, so whether the passes ran is counted instead
+    let compile = |passes: Option<Vec<String>>| {
+        let mut jit = Jit::new(EvalOptions {
+            passes,
+            ..options()
+        });
+        let value = last_value(&mut jit, &expr).unwrap();
+        (jit.optimized, value)
+    };
+    let (optimized_runs, optimized) = compile(None);
+    let (unoptimized_runs, unoptimized) = compile(Some(vec![]));
+    assert!(!optimized.differs(&unoptimized, DEFAULT_EPSILON));
+    assert_eq!((optimized_runs, unoptimized_runs), (1, 0));
+}
+
+#[test]
+fn callers_are_checked_against_a_new_parameter_list() {
+    let mut jit = Jit::new(options());
+    last_value(&mut jit, "f(x) = x & g(x) = f(x) + 1 & g(1)").unwrap();
+    // `g` no longer builds, which mustn't stop anything else from running
+    assert_eq!(
+        last_value(&mut jit, "f(x, y) = x + y & f(1, 2)").unwrap(),
+        Value::Scalar(3.0)
+    );
+    let err = last_value(&mut jit, "g(1)").unwrap_err();
+    let Some(MathJitError::Arity {
+        name,
+        provided,
+        message,
+        ..
+    }) = err.downcast_ref()
+    else {
+        panic!("{err}");
+    };
+    assert_eq!((&name[..], *provided), ("f", 1));
+    assert!(message.starts_with("incorrect argument count for 'f' call in g"));
+}
+
+// Returns from the middle of the expression, leaving the rest of its block after the terminator
+struct EarlyReturn;
+impl BuiltinFunction for EarlyReturn {
+    fn eval_interpreter(&self, _: &AstInterpreter, _: Vec<f64>) -> anyhow::Result<f64> {
+        Ok(0.0)
+    }
+
+    fn gen_jit<'b>(&self, fg: &FunctionGen<'b, '_>, _: &[MathOp]) -> FloatValue<'b> {
+        let zero = fg.cg.context.f64_type().const_float(0.0);
+        fg.cg
+            .builder
+            .build_return(Some(&zero))
+            .expect("Failed to return");
+        zero
+    }
+
+    fn replicate(&self) -> Box<dyn BuiltinFunction> {
+        Box::new(Self)
+    }
+
+    fn proto(&self) -> BuiltinProto {
+        BuiltinProto {
+            args: &[],
+            description: "returns early",
+        }
+    }
+}
+
+#[test]
+fn malformed_code_fails_verification() {
+    let mut jit = Jit::new(options()).with_intrinsic("z", Box::new(EarlyReturn));
+    last_value(&mut jit, "f(x) = x * 2 & f(1)").unwrap();
+    let err = last_value(&mut jit, "g(x) = x + 1 & g(z()) + 1").unwrap_err();
+    let Some(MathJitError::Backend { message }) = err.downcast_ref() else {
+        panic!("{err}");
+    };
+    assert!(message.starts_with("generated code failed LLVM verification"));
+    // The session carries on, keeping the definitions made before and alongside the broken expression
+    assert_eq!(
+        last_value(&mut jit, "f(3) + g(1)").unwrap(),
+        Value::Scalar(8.0)
+    );
+}
+
+#[test]
+fn chained_and_separate_definitions_compile_alike() {
+    // Timings are too noisy to compare, so the work done is counted instead
+    let mut chained = Jit::new(options());
+    last_value(&mut chained, "f(x) = x^2 & g(x) = f(x) + 1 & g(3)").unwrap();
+    let mut separate = Jit::new(options());
+    for line in ["f(x) = x^2", "g(x) = f(x) + 1"] {
+        for op in Parser::new(line).unwrap().parse().unwrap() {
+            separate.eval(op).unwrap();
+        }
+    }
+    assert_eq!(
+        last_value(&mut separate, "g(3)").unwrap(),
+        last_value(&mut chained, "g(3)").unwrap()
+    );
+    for jit in [chained, separate] {
+        assert_eq!(jit.functions_compiled, 2);
+        assert_eq!(jit.cache_invalidations, 0);
+    }
+}
+
+// `-(-(...1))`, nested `depth` levels deep
+fn negated(depth: usize) -> ParseOutput {
+    ParseOutput::Body((0..depth).fold(MathOp::Num(1.0), |x, _| MathOp::Neg(Box::new(x))))
+}
+
+#[test]
+fn deep_expressions_are_refused_before_codegen() {
+    with_main_stack(|| {
+        let mut jit = Jit::new(options());
+        let Err(err) = jit.eval(negated(5000)) else {
+            panic!("an expression 5000 levels deep was compiled");
+        };
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(MathJitError::Unsupported { .. })
+        ));
+        let (response, _) = jit.eval(negated(500)).unwrap();
+        assert!(matches!(response, Response::Value(Value::Scalar(x)) if x == 1.0));
+    });
+}